@@ -0,0 +1,14 @@
+use terminal_input::{
+    poll::poll_input,
+};
+
+static QUIT_SEQUENCE: [u8; 2] = [26, 16];   // Ctrl-Z and Ctrl-P
+fn main() {
+    let stdin = poll_input(&QUIT_SEQUENCE).unwrap();
+
+    println!("Use CTRL-Z then CTRL-P to Exit.");
+
+    for e in stdin {
+        println!("Display = \"{}\"; Debug = {:?}", e, e);
+    }
+}
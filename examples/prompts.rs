@@ -0,0 +1,18 @@
+use std::io;
+use terminal_input::{
+    prompt::{PathCheck, read_date, read_integer, read_path},
+    user::parsed_input,
+};
+
+static QUIT_SEQUENCE: [u8; 2] = [26, 16];   // Ctrl-Z and Ctrl-P
+fn main() {
+    let mut stdin = parsed_input(&QUIT_SEQUENCE);
+    let mut stdout = io::stdout();
+
+    let count = read_integer(&mut stdin, &mut stdout, "How many (1-10)? ", 1..=10);
+    println!("Count = {:?}", count);
+    let date = read_date(&mut stdin, &mut stdout, "Start date? ");
+    println!("Date = {:?}", date);
+    let path = read_path(&mut stdin, &mut stdout, "Config file? ", PathCheck::File);
+    println!("Path = {:?}", path);
+}
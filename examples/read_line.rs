@@ -0,0 +1,22 @@
+use terminal_input::{
+    complete::WordCompleter,
+    line::LineEditor,
+    user::{Protocol, disable_protocol, enable_protocol, parsed_input},
+};
+
+static QUIT_SEQUENCE: [u8; 2] = [26, 16];   // Ctrl-Z and Ctrl-P
+fn main() {
+    let mut stdin = parsed_input(&QUIT_SEQUENCE);
+    let mut editor = LineEditor::new("> ");
+    editor.set_completer(WordCompleter::new(vec!["help", "history", "quit"]));
+    // Lines ending in a backslash carry on onto the next one.
+    editor.set_validator(|text: &str| !text.ends_with('\\'));
+    enable_protocol(Protocol::BracketedPaste).unwrap();
+
+    println!("Enter lines to echo. Use CTRL-D on an empty line to Exit.");
+
+    while let Ok(Some(line)) = editor.read_line(&mut stdin) {
+        println!("Line = {:?}", line);
+    }
+    disable_protocol(Protocol::BracketedPaste).unwrap();
+}
@@ -0,0 +1,9 @@
+use terminal_input::prompt::{MaskStyle, read_password};
+
+fn main() {
+    match read_password("Password: ", MaskStyle::LastCharVisible) {
+        Ok(Some(password)) => { println!("Read {} characters", password.chars().count()); },
+        Ok(None) => { println!("No password"); },
+        Err(e) => { println!("Error: {}", e); },
+    }
+}
@@ -0,0 +1,34 @@
+use std::env;
+use terminal_input::{
+    record::{Pace, Recorder, Recording, recorded_input, replay_input},
+    user::InputOptions,
+};
+
+static QUIT_SEQUENCE: [u8; 2] = [26, 16];   // Ctrl-Z and Ctrl-P
+
+/// Records keys to a file with `record FILE`, and plays them back with `replay FILE [SPEED]`.
+fn main() -> std::io::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let input = match (args.first().map(String::as_str), args.get(1)) {
+        (Some("record"), Some(path)) => {
+            println!("Recording to {}. Use CTRL-Z then CTRL-P to Exit.", path);
+            recorded_input(&QUIT_SEQUENCE, InputOptions::default(), Recorder::create(path)?)?
+        },
+        (Some("replay"), Some(path)) => {
+            let pace = match args.get(2) {
+                Some(speed) if speed == "0" => Pace::Immediate,
+                Some(speed) => Pace::Speed(speed.parse().expect("the speed should be a number")),
+                None => Pace::default(),
+            };
+            replay_input(Recording::load(path)?, &QUIT_SEQUENCE, InputOptions::default(), pace)
+        },
+        _ => {
+            eprintln!("usage: record_input record FILE | replay FILE [SPEED, or 0 for no delays]");
+            std::process::exit(2);
+        },
+    };
+    for e in input {
+        println!("Display = \"{}\"; Debug = {:?}", e, e);
+    }
+    Ok(())
+}
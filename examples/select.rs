@@ -0,0 +1,21 @@
+use std::io;
+use terminal_input::{
+    select::{confirm, fuzzy_select, multi_select, select},
+    user::parsed_input,
+};
+
+static QUIT_SEQUENCE: [u8; 2] = [26, 16];   // Ctrl-Z and Ctrl-P
+fn main() {
+    let mut stdin = parsed_input(&QUIT_SEQUENCE);
+    let mut stdout = io::stdout();
+    let colours = ["red", "orange", "yellow", "green", "blue", "indigo", "violet"];
+
+    let colour = select(&mut stdin, &mut stdout, "Favourite colour?", &colours, 0);
+    println!("Picked {:?}", colour.map(|c| c.map(|i| colours[i])));
+    let chosen = multi_select(&mut stdin, &mut stdout, "Colours to mix?", &colours, &[]);
+    println!("Chose {:?}", chosen);
+    let found = fuzzy_select(&mut stdin, &mut stdout, "Find a colour:", &colours);
+    println!("Found {:?}", found.map(|c| c.map(|i| colours[i])));
+    let again = confirm(&mut stdin, &mut stdout, "Again?", Some(false));
+    println!("Again {:?}", again);
+}
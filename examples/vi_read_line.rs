@@ -0,0 +1,20 @@
+use terminal_input::{
+    user::{EscapeTimeout, InputOptions, parsed_input_with},
+    vi::{self, ViEditor},
+};
+
+static QUIT_SEQUENCE: [u8; 2] = [26, 16];   // Ctrl-Z and Ctrl-P
+fn main() {
+    let options = InputOptions {
+        escape_timeout: EscapeTimeout::Fixed(vi::ESCAPE_TIMEOUT),
+        ..InputOptions::default()
+    };
+    let mut stdin = parsed_input_with(&QUIT_SEQUENCE, options);
+    let mut editor = ViEditor::new("> ");
+
+    println!("Enter lines to echo, with vi keys. Use CTRL-D on an empty line to Exit.");
+
+    while let Ok(Some(line)) = editor.read_line(&mut stdin) {
+        println!("Line = {:?}", line);
+    }
+}
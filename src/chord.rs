@@ -0,0 +1,359 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+use crate::{
+    parse::ExtendedChar,
+    poll::PollInput,
+    user::StdinIterator,
+};
+
+/**
+  Recognizes registered sequences of keys, such as `Ctrl-X Ctrl-S` or `g g`, in a stream of parsed
+  input and replaces them with `Chord(id)`. Keys that aren't part of a registered sequence are
+  passed through unchanged.
+
+  The sequences are kept in a trie. While the keys seen so far could still become a sequence they
+  are held back, and when a key turns out not to continue it, the held keys are resolved in the
+  same way [check_quit_buffer](crate::user::check_quit_buffer) recovers from a partial match: the
+  longest sequence that starts at the first held key is reported, or failing that the first key on
+  its own, and matching starts over with the keys after it. So with `a b c` and `b d` registered,
+  `a b d` comes out as `a` followed by the `b d` chord. When one sequence is a prefix of another,
+  the shorter one is reported once the longer one can no longer match, or once the time allowed
+  between keys has passed.
+  */
+#[derive(Clone, Debug)]
+pub struct ChordMatcher {
+    nodes: Vec<Node>,
+    timeout: Option<Duration>,
+    held: Vec<ExtendedChar>,
+    last_key: Option<Instant>,
+    count: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Node {
+    children: HashMap<ExtendedChar, usize>,
+    chord: Option<usize>,
+}
+
+impl Default for ChordMatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChordMatcher {
+    /// Creates a matcher with no sequences that waits as long as it takes for the next key.
+    pub fn new() -> Self {
+        ChordMatcher {
+            nodes: vec![Node::default()],
+            timeout: None,
+            held: Vec::new(),
+            last_key: None,
+            count: 0,
+        }
+    }
+
+    /**
+      Sets how long to wait between the keys of a sequence before giving up on it. `None` waits
+      until the next key arrives.
+      */
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /**
+      Registers a sequence of keys and returns the id it will be reported with. Registering the
+      same sequence again replaces its id, and an empty sequence is never matched.
+      */
+    pub fn register(&mut self, keys: &[ExtendedChar]) -> usize {
+        let mut node = 0;
+        for key in keys {
+            node = match self.nodes[node].children.get(key) {
+                Some(&child) => child,
+                None => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children.insert(*key, child);
+                    child
+                },
+            };
+        }
+        let id = self.count;
+        self.count += 1;
+        if node != 0 {
+            self.nodes[node].chord = Some(id);
+        }
+        id
+    }
+
+    /**
+      Adds a key that arrived at `now` and returns whatever can be decided. If the keys being held
+      have been waiting longer than the timeout, they are resolved first.
+      */
+    pub fn push(&mut self, key: ExtendedChar, now: Instant) -> Vec<ExtendedChar> {
+        let mut out = Vec::new();
+        if self.deadline().is_some_and(|d| d <= now) {
+            self.resolve(true, &mut out);
+        }
+        self.held.push(key);
+        self.last_key = Some(now);
+        self.resolve(false, &mut out);
+        out
+    }
+
+    /// Stops waiting for the rest of a sequence and returns the held keys, resolved as they are.
+    pub fn flush(&mut self) -> Vec<ExtendedChar> {
+        let mut out = Vec::new();
+        self.resolve(true, &mut out);
+        out
+    }
+
+    /// Returns true if keys are being held waiting for the rest of a sequence.
+    pub fn is_pending(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    /// Returns the time at which the held keys will be given up on, if there is a timeout.
+    pub fn deadline(&self) -> Option<Instant> {
+        match (self.is_pending(), self.last_key, self.timeout) {
+            (true, Some(last), Some(timeout)) => Some(last + timeout),
+            _ => None,
+        }
+    }
+
+    /**
+      Moves everything that has been decided from the held keys to `out`. Unless `finished` is set,
+      keys that could still become a longer sequence are left held.
+      */
+    fn resolve(&mut self, finished: bool, out: &mut Vec<ExtendedChar>) {
+        while !self.held.is_empty() {
+            let mut node = 0;
+            let mut longest = None;
+            let mut complete = true;
+            for (i, key) in self.held.iter().enumerate() {
+                match self.nodes[node].children.get(key) {
+                    Some(&child) => {
+                        node = child;
+                        if let Some(id) = self.nodes[node].chord {
+                            longest = Some((i + 1, id));
+                        }
+                    },
+                    None => {
+                        complete = false;
+                        break;
+                    },
+                }
+            }
+            if complete && !finished && !self.nodes[node].children.is_empty() {
+                break;
+            }
+            match longest {
+                Some((len, id)) => {
+                    self.held.drain(..len);
+                    out.push(ExtendedChar::Chord(id));
+                },
+                None => {
+                    out.push(self.held.remove(0));
+                },
+            }
+        }
+    }
+}
+
+/// A source of parsed input that can wait for input until a deadline.
+pub trait TimedInput: Iterator<Item = ExtendedChar> {
+    /// Waits until `deadline` for the next character.
+    fn next_deadline(&mut self, deadline: Instant) -> Option<ExtendedChar>;
+
+    /// Returns true once the input has finished.
+    fn is_done(&self) -> bool;
+}
+
+impl TimedInput for StdinIterator<ExtendedChar> {
+    fn next_deadline(&mut self, deadline: Instant) -> Option<ExtendedChar> {
+        StdinIterator::next_deadline(self, deadline)
+    }
+
+    fn is_done(&self) -> bool {
+        StdinIterator::is_done(self)
+    }
+}
+
+impl TimedInput for PollInput {
+    fn next_deadline(&mut self, deadline: Instant) -> Option<ExtendedChar> {
+        PollInput::next_deadline(self, deadline)
+    }
+
+    fn is_done(&self) -> bool {
+        PollInput::is_done(self)
+    }
+}
+
+/**
+  An iterator over parsed input with the sequences registered in a [ChordMatcher] replaced by
+  `Chord(id)`. While keys are held it waits for the next one only until the matcher's deadline.
+  */
+pub struct Chords<I> {
+    input: I,
+    matcher: ChordMatcher,
+    out: VecDeque<ExtendedChar>,
+    finished: bool,
+}
+
+impl<I: TimedInput> Chords<I> {
+    pub fn new(input: I, matcher: ChordMatcher) -> Self {
+        Chords {
+            input,
+            matcher,
+            out: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    /// Returns the matcher, so that sequences can be registered as the application goes.
+    pub fn matcher(&mut self) -> &mut ChordMatcher {
+        &mut self.matcher
+    }
+}
+
+impl<I: TimedInput> Iterator for Chords<I> {
+    type Item = ExtendedChar;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(e) = self.out.pop_front() {
+                return Some(e);
+            }
+            if self.finished { return None; }
+            let key = match self.matcher.deadline() {
+                Some(deadline) => self.input.next_deadline(deadline),
+                None => self.input.next(),
+            };
+            match key {
+                Some(key) => { self.out.extend(self.matcher.push(key, Instant::now())); },
+                None => {
+                    // Either the time between keys ran out or the input finished, and either way
+                    // the held keys aren't going to be continued.
+                    self.out.extend(self.matcher.flush());
+                    self.finished = self.input.is_done();
+                },
+            }
+        }
+    }
+}
+
+/**
+  Input for tests that plays back a script of keys, in which `None` stands for a pause longer than
+  any timeout.
+  */
+#[cfg(test)]
+pub(crate) struct ScriptedInput {
+    keys: VecDeque<Option<ExtendedChar>>,
+    done: bool,
+}
+
+#[cfg(test)]
+impl ScriptedInput {
+    pub(crate) fn new(keys: Vec<Option<ExtendedChar>>) -> Self {
+        ScriptedInput { keys: keys.into(), done: false }
+    }
+}
+
+#[cfg(test)]
+impl Iterator for ScriptedInput {
+    type Item = ExtendedChar;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(key) = self.keys.pop_front() {
+            if key.is_some() { return key; }
+        }
+        self.done = true;
+        None
+    }
+}
+
+#[cfg(test)]
+impl TimedInput for ScriptedInput {
+    fn next_deadline(&mut self, _deadline: Instant) -> Option<ExtendedChar> {
+        match self.keys.pop_front() {
+            Some(key) => key,
+            None => {
+                self.done = true;
+                None
+            },
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chord::*;
+    use crate::parse::{ExtendedChar::*, SpecialChar::*};
+
+    #[test]
+    fn test_chord_matcher() {
+        let mut matcher = ChordMatcher::new();
+        let save = matcher.register(&[Byte(24), Byte(19)]);       // Ctrl-X Ctrl-S
+        let top = matcher.register(&[Byte(b'g'), Byte(b'g')]);
+        let abc = matcher.register(&[Byte(b'a'), Byte(b'b'), Byte(b'c')]);
+        let bd = matcher.register(&[Byte(b'b'), Byte(b'd')]);
+        let g = matcher.register(&[Byte(b'g')]);
+        let now = Instant::now();
+        let mut push = |keys: &[ExtendedChar]| {
+            keys.iter().flat_map(|k| matcher.push(*k, now)).collect::<Vec<_>>()
+        };
+        // Unregistered keys pass straight through.
+        assert_eq!(push(&[Byte(b'x'), Normal(UpArrow)]), vec![Byte(b'x'), Normal(UpArrow)]);
+        assert_eq!(push(&[Byte(24), Byte(19)]), vec![Chord(save)]);
+        assert_eq!(push(&[Byte(b'a'), Byte(b'b'), Byte(b'c')]), vec![Chord(abc)]);
+        // A failed match is retried from the next key.
+        assert_eq!(push(&[Byte(b'a'), Byte(b'b'), Byte(b'd')]), vec![Byte(b'a'), Chord(bd)]);
+        assert_eq!(push(&[Byte(24), Byte(24), Byte(19)]), vec![Byte(24), Chord(save)]);
+        // A sequence that is a prefix of another waits to see which it is.
+        assert_eq!(push(&[Byte(b'g')]), vec![]);
+        assert_eq!(push(&[Byte(b'g')]), vec![Chord(top)]);
+        assert_eq!(push(&[Byte(b'g'), Byte(b'x')]), vec![Chord(g), Byte(b'x')]);
+        assert_eq!(push(&[Byte(b'g'), Byte(b'g'), Byte(b'g')]), vec![Chord(top)]);
+        assert!(matcher.is_pending());
+        assert_eq!(matcher.flush(), vec![Chord(g)]);
+        assert!(!matcher.is_pending());
+    }
+
+    #[test]
+    fn test_chord_timeout() {
+        let ms = Duration::from_millis;
+        let mut matcher = ChordMatcher::new();
+        let save = matcher.register(&[Byte(24), Byte(19)]);
+        let start = Instant::now();
+        assert_eq!(matcher.push(Byte(24), start), vec![]);
+        assert_eq!(matcher.deadline(), None);
+        assert_eq!(matcher.push(Byte(19), start + ms(5000)), vec![Chord(save)]);
+        // With a timeout, keys that come too late start over.
+        matcher.set_timeout(Some(ms(500)));
+        assert_eq!(matcher.push(Byte(24), start), vec![]);
+        assert_eq!(matcher.deadline(), Some(start + ms(500)));
+        assert_eq!(matcher.push(Byte(19), start + ms(600)), vec![Byte(24), Byte(19)]);
+    }
+
+    #[test]
+    fn test_chords() {
+        let mut matcher = ChordMatcher::new();
+        matcher.set_timeout(Some(Duration::from_secs(1)));
+        let top = matcher.register(&[Byte(b'g'), Byte(b'g')]);
+        let keys = vec![Some(Byte(b'g')), Some(Byte(b'g')), Some(Byte(b'g')), None, Some(Byte(b'g')), Some(Byte(b'g'))];
+        let input = ScriptedInput::new(keys);
+        let chords: Vec<_> = Chords::new(input, matcher).collect();
+        assert_eq!(chords, vec![Chord(top), Byte(b'g'), Chord(top)]);
+        // Keys still held when the input ends are returned.
+        let input = ScriptedInput::new(vec![Some(Byte(b'g'))]);
+        let mut matcher = ChordMatcher::new();
+        matcher.register(&[Byte(b'g'), Byte(b'g')]);
+        assert_eq!(Chords::new(input, matcher).collect::<Vec<_>>(), vec![Byte(b'g')]);
+    }
+}
@@ -0,0 +1,102 @@
+use unicode_width::UnicodeWidthStr;
+
+/**
+  Supplies the completions offered by a [LineEditor](crate::line::LineEditor) when Tab is pressed.
+
+  Given the line and the position of the cursor in it, counted in characters, a completer returns
+  the position where the text being completed starts and the candidates that could replace the
+  text from there up to the cursor. Closures of the same shape are completers too.
+  */
+pub trait Completer {
+    fn complete(&self, line: &str, cursor: usize) -> (usize, Vec<String>);
+}
+
+impl<F: Fn(&str, usize) -> (usize, Vec<String>)> Completer for F {
+    fn complete(&self, line: &str, cursor: usize) -> (usize, Vec<String>) {
+        self(line, cursor)
+    }
+}
+
+/// Completes the word before the cursor from a fixed list of words, such as a REPL's commands.
+#[derive(Clone, Debug, Default)]
+pub struct WordCompleter {
+    words: Vec<String>,
+}
+
+impl WordCompleter {
+    pub fn new<I: IntoIterator<Item = S>, S: Into<String>>(words: I) -> Self {
+        let mut words: Vec<String> = words.into_iter().map(Into::into).collect();
+        words.sort();
+        words.dedup();
+        WordCompleter { words }
+    }
+}
+
+impl Completer for WordCompleter {
+    fn complete(&self, line: &str, cursor: usize) -> (usize, Vec<String>) {
+        let before: Vec<char> = line.chars().take(cursor).collect();
+        let start = before.iter().rposition(|c| c.is_whitespace()).map_or(0, |i| i + 1);
+        let word: String = before[start..].iter().collect();
+        let candidates = self.words.iter().filter(|w| w.starts_with(&word)).cloned().collect();
+        (start, candidates)
+    }
+}
+
+/// Returns the longest text that every one of `candidates` starts with.
+pub fn common_prefix(candidates: &[String]) -> String {
+    let mut candidates = candidates.iter();
+    let mut prefix: Vec<char> = match candidates.next() {
+        Some(first) => first.chars().collect(),
+        None => return String::new(),
+    };
+    for candidate in candidates {
+        let same = prefix.iter().zip(candidate.chars()).take_while(|(a, b)| **a == *b).count();
+        prefix.truncate(same);
+    }
+    prefix.into_iter().collect()
+}
+
+/**
+  Lays out `items` in as many columns as fit in `width` columns of the terminal, filling each
+  column from top to bottom like `ls`, and returns the rows.
+  */
+pub fn format_columns(items: &[String], width: usize) -> Vec<String> {
+    let longest = items.iter().map(|item| item.width()).max().unwrap_or(0);
+    let column_width = longest + 2;
+    let columns = (width / column_width).clamp(1, items.len().max(1));
+    let rows = items.len().div_ceil(columns);
+    (0..rows).map(|row| {
+        let mut line = String::new();
+        for item in items.iter().skip(row).step_by(rows) {
+            line.push_str(item);
+            line.extend(std::iter::repeat(' ').take(column_width - item.width()));
+        }
+        line.truncate(line.trim_end().len());
+        line
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::complete::*;
+
+    #[test]
+    fn test_word_completer() {
+        let completer = WordCompleter::new(vec!["help", "history", "quit", "help"]);
+        assert_eq!(completer.complete("h", 1), (0, vec!["help".to_string(), "history".to_string()]));
+        assert_eq!(completer.complete("help hi x", 7), (5, vec!["history".to_string()]));
+        assert_eq!(completer.complete("x", 1).1, Vec::<String>::new());
+        assert_eq!(common_prefix(&["history".to_string(), "hist".to_string(), "histogram".to_string()]), "hist");
+        assert_eq!(common_prefix(&[]), "");
+    }
+
+    #[test]
+    fn test_format_columns() {
+        let items: Vec<String> = ["a", "bb", "ccc", "d", "e"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(format_columns(&items, 15), vec!["a    ccc  e", "bb   d"]);
+        assert_eq!(format_columns(&items, 4), vec!["a", "bb", "ccc", "d", "e"]);
+        assert_eq!(format_columns(&items, 80), vec!["a    bb   ccc  d    e"]);
+        let items: Vec<String> = ["\u{4e2d}\u{6587}", "ab", "c"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(format_columns(&items, 80), vec!["\u{4e2d}\u{6587}  ab    c"]);
+    }
+}
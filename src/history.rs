@@ -0,0 +1,339 @@
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+/**
+  The lines entered into a [LineEditor](crate::line::LineEditor), oldest first. Blank lines are left
+  out, and adding a line that is already there moves it to the end rather than keeping both. Once
+  there are more than the maximum number of entries, the oldest ones are dropped.
+
+  A history opened from a file appends each new entry to it straight away, under an exclusive
+  `flock(2)`, so several sessions can share one file without losing each other's entries. The lock
+  is taken on a separate file next to it, named with `.lock` added, which is left in place. The file
+  holds one entry per line, with backslashes and line breaks escaped as `\\`, `\n` and `\r`. Adding
+  an entry only reads what other sessions have appended since, to keep count of the lines.
+
+  Once the file holds half as many lines again as the maximum, it is compacted to only the newest
+  entries under the same lock. The compacted text is written to a temporary file, named with `.tmp`
+  added, which is then renamed over the history file, so a crash or a full disk part way through
+  leaves the old file as it was, with at most a stray temporary file beside it.
+  */
+#[derive(Clone, Debug)]
+pub struct History {
+    entries: Vec<String>,
+    max_len: usize,
+    path: Option<PathBuf>,
+    extent: FileExtent,
+}
+
+/// How far into the history file has been read, and how many lines that was.
+#[derive(Clone, Copy, Debug, Default)]
+struct FileExtent {
+    len: u64,
+    lines: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl History {
+    pub const DEFAULT_MAX_LEN: usize = 1000;
+
+    /// Creates an empty history that is only kept in memory.
+    pub fn new() -> Self {
+        Self::with_max_len(History::DEFAULT_MAX_LEN)
+    }
+
+    pub fn with_max_len(max_len: usize) -> Self {
+        History {
+            entries: Vec::new(),
+            max_len: max_len.max(1),
+            path: None,
+            extent: FileExtent::default(),
+        }
+    }
+
+    /// Loads the history kept in the file at `path`, which is created when the first entry is added.
+    pub fn open<P: AsRef<Path>>(path: P, max_len: usize) -> io::Result<Self> {
+        let mut history = Self::with_max_len(max_len);
+        history.path = Some(path.as_ref().to_path_buf());
+        history.reload()?;
+        Ok(history)
+    }
+
+    /// Reads the history file again, picking up the entries other sessions have added since.
+    pub fn reload(&mut self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if !path.exists() { return Ok(()); }
+        let _lock = FileLock::new(path, libc::LOCK_SH)?;
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut text = Vec::new();
+        file.read_to_end(&mut text)?;
+        self.entries = parse_entries(&String::from_utf8_lossy(&text), self.max_len);
+        self.extent = FileExtent { len: text.len() as u64, lines: count_lines(&text) };
+        Ok(())
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    /**
+      Adds a line as the newest entry, and appends it to the history file if there is one. Returns
+      false if the line was left out because it is blank.
+      */
+    pub fn add(&mut self, line: &str) -> io::Result<bool> {
+        if line.trim().is_empty() { return Ok(false); }
+        self.entries.retain(|entry| entry != line);
+        self.entries.push(line.to_string());
+        if self.entries.len() > self.max_len {
+            self.entries.drain(..self.entries.len() - self.max_len);
+        }
+        if let Some(path) = &self.path {
+            append_entry(path, line, self.max_len, &mut self.extent)?;
+        }
+        Ok(true)
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.entries.get(index).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(String::as_str)
+    }
+
+    /**
+      Finds an entry containing `query`, returning its index and the position in characters of the
+      match. Going back it looks at the entries before `start`, newest first, and going forward at
+      the entries from `start` on.
+      */
+    pub fn search(&self, query: &str, start: usize, backward: bool) -> Option<(usize, usize)> {
+        self.find(start, backward, |entry| entry.find(query).map(|at| entry[..at].chars().count()))
+    }
+
+    /// Finds an entry starting with `prefix`, looking at entries in the same order as [search](History::search).
+    pub fn search_prefix(&self, prefix: &str, start: usize, backward: bool) -> Option<usize> {
+        self.find(start, backward, |entry| if entry.starts_with(prefix) { Some(()) } else { None })
+            .map(|(index, _)| index)
+    }
+
+    fn find<T, F: Fn(&str) -> Option<T>>(&self, start: usize, backward: bool, matches: F) -> Option<(usize, T)> {
+        let start = start.min(self.entries.len());
+        let check = |i: usize| matches(&self.entries[i]).map(|found| (i, found));
+        if backward {
+            (0..start).rev().find_map(check)
+        }
+        else {
+            (start..self.entries.len()).find_map(check)
+        }
+    }
+}
+
+/// Holds a `flock(2)` on the lock file that goes with a history file until it is dropped.
+struct FileLock {
+    file: File,
+}
+
+impl FileLock {
+    fn new(path: &Path, operation: libc::c_int) -> io::Result<Self> {
+        let file = OpenOptions::new().write(true).create(true).truncate(false).open(with_suffix(path, ".lock"))?;
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+                return Ok(FileLock { file });
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN); }
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path);
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/**
+  Appends an entry to the history file, compacting it if it now holds more than half as many lines
+  again as `max_len`. `extent` is what this session has already read of the file, so only what
+  other sessions have appended since has to be read to keep the count.
+  */
+fn append_entry(path: &Path, line: &str, max_len: usize, extent: &mut FileExtent) -> io::Result<()> {
+    let _lock = FileLock::new(path, libc::LOCK_EX)?;
+    let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+    let len = file.metadata()?.len();
+    if len < extent.len {
+        // Another session has compacted the file, so count it all again.
+        *extent = FileExtent::default();
+    }
+    if len > extent.len {
+        let mut text = Vec::new();
+        file.seek(SeekFrom::Start(extent.len))?;
+        file.read_to_end(&mut text)?;
+        extent.lines += count_lines(&text);
+    }
+    let entry = format!("{}\n", escape(line));
+    file.write_all(entry.as_bytes())?;
+    *extent = FileExtent { len: len + entry.len() as u64, lines: extent.lines + 1 };
+    if extent.lines > max_len + max_len / 2 {
+        let mut text = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut text)?;
+        let entries = parse_entries(&String::from_utf8_lossy(&text), max_len);
+        let mut text = String::new();
+        for entry in &entries {
+            text.push_str(&escape(entry));
+            text.push('\n');
+        }
+        compact(path, &text)?;
+        *extent = FileExtent { len: text.len() as u64, lines: entries.len() };
+    }
+    Ok(())
+}
+
+/// Replaces the history file with `text`, leaving it untouched if that fails.
+fn compact(path: &Path, text: &str) -> io::Result<()> {
+    let temporary = with_suffix(path, ".tmp");
+    let result = File::create(&temporary)
+        .and_then(|mut file| {
+            file.write_all(text.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temporary, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+fn count_lines(text: &[u8]) -> usize {
+    text.iter().filter(|&&b| b == b'\n').count()
+}
+
+/// Returns the newest `max_len` distinct entries in the text of a history file, oldest first.
+fn parse_entries(text: &str, max_len: usize) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut entries: Vec<_> = text.lines().rev()
+        .filter(|line| !line.is_empty())
+        .map(unescape)
+        .filter(|entry| seen.insert(entry.clone()))
+        .take(max_len)
+        .collect();
+    entries.reverse();
+    entries
+}
+
+fn escape(entry: &str) -> String {
+    entry.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn unescape(line: &str) -> String {
+    let mut entry = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            entry.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => { entry.push('\n'); },
+            Some('r') => { entry.push('\r'); },
+            Some(c) => { entry.push(c); },
+            None => { entry.push('\\'); },
+        }
+    }
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::history::*;
+
+    #[test]
+    fn test_history() {
+        let mut history = History::with_max_len(3);
+        assert!(!history.add("  ").unwrap());
+        for line in &["ls", "cd src", "ls", "make", "cat a\\b"] {
+            assert!(history.add(line).unwrap());
+        }
+        assert_eq!(history.iter().collect::<Vec<_>>(), vec!["ls", "make", "cat a\\b"]);
+        assert_eq!(history.search("a", 3, true), Some((2, 1)));
+        assert_eq!(history.search("a", 2, true), Some((1, 1)));
+        assert_eq!(history.search("a", 2, false), Some((2, 1)));
+        assert_eq!(history.search("x", 3, true), None);
+        assert_eq!(history.search_prefix("l", 3, true), Some(0));
+        assert_eq!(history.search_prefix("m", 0, false), Some(1));
+        assert_eq!(unescape(&escape("a\\n\r\nb\\")), "a\\n\r\nb\\");
+        assert_eq!(escape("a\\n\nb"), "a\\\\n\\nb");
+    }
+
+    #[test]
+    fn test_history_file() {
+        let path = std::env::temp_dir().join(format!("terminal_input_history_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // Two sessions sharing a file see each other's entries once they reload.
+        let mut first = History::open(&path, 4).unwrap();
+        let mut second = History::open(&path, 4).unwrap();
+        first.add("one").unwrap();
+        second.add("two\nlines").unwrap();
+        first.add("three").unwrap();
+        second.add("one").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\ntwo\\nlines\nthree\none\n");
+        first.reload().unwrap();
+        assert_eq!(first.iter().collect::<Vec<_>>(), vec!["two\nlines", "three", "one"]);
+        // The file is only compacted to the newest entries once it has grown well past the maximum.
+        let inode = |path: &PathBuf| std::os::unix::fs::MetadataExt::ino(&std::fs::metadata(path).unwrap());
+        let appended_to = inode(&path);
+        second.add("four").unwrap();
+        second.add("five").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\ntwo\\nlines\nthree\none\nfour\nfive\n");
+        assert_eq!(inode(&path), appended_to);
+        // A session that hasn't read the new entries counts them before adding its own.
+        first.add("six").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\nfour\nfive\nsix\n");
+        assert_ne!(inode(&path), appended_to);
+        // And one that hasn't seen the file compacted counts it all again.
+        second.add("seven").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\nfour\nfive\nsix\nseven\n");
+        second.reload().unwrap();
+        assert_eq!(second.iter().collect::<Vec<_>>(), vec!["four", "five", "six", "seven"]);
+        let _ = std::fs::remove_file(with_suffix(&path, ".lock"));
+        let _ = std::fs::remove_file(&path);
+    }
+}
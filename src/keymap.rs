@@ -0,0 +1,877 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt::{self, Display},
+    fs,
+    io,
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use crate::{
+    chord::TimedInput,
+    parse::{ExtendedChar, KeyParseError, KeySequence},
+};
+
+/**
+  A set of key bindings, mapping sequences of keys to the names of actions. A keymap can name a
+  parent whose bindings apply wherever its own don't.
+  */
+#[derive(Clone, Debug, Default)]
+pub struct Keymap {
+    parent: Option<String>,
+    bindings: HashMap<Vec<ExtendedChar>, String>,
+    prefixes: HashSet<Vec<ExtendedChar>>,
+}
+
+impl Keymap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the name of the keymap to fall back to.
+    pub fn parent(&self) -> Option<&str> {
+        self.parent.as_deref()
+    }
+
+    pub fn set_parent(&mut self, parent: Option<String>) {
+        self.parent = parent;
+    }
+
+    /// Binds a sequence of keys to an action, replacing any earlier binding for the same keys.
+    pub fn bind(&mut self, keys: &[ExtendedChar], action: impl Into<String>) {
+        for len in 1..keys.len() {
+            self.prefixes.insert(keys[..len].to_vec());
+        }
+        self.bindings.insert(keys.to_vec(), action.into());
+    }
+
+    /// Returns the action bound to exactly these keys.
+    pub fn get(&self, keys: &[ExtendedChar]) -> Option<&str> {
+        self.bindings.get(keys).map(String::as_str)
+    }
+
+    /// Returns true if these keys are the start of a longer binding.
+    pub fn is_prefix(&self, keys: &[ExtendedChar]) -> bool {
+        self.prefixes.contains(keys)
+    }
+
+    pub fn bindings(&self) -> impl Iterator<Item = (&[ExtendedChar], &str)> {
+        self.bindings.iter().map(|(keys, action)| (keys.as_slice(), action.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.bindings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bindings.is_empty()
+    }
+}
+
+/**
+  Named keymaps, one for each mode of an application, such as `normal`, `insert` and `command`.
+
+  They can be loaded from a simple text format, so that users can change bindings without
+  recompiling:
+
+  ```text
+  # Comments start with a #, at the start of a line or after a space.
+  C-q = quit              # bindings before the first section go in the "default" mode
+
+  [normal]
+  C-x C-s = save
+  gg = "goto-top"
+  <C-w>j = window-down
+  "=" = indent            # keys that contain '=' or start with '#' or '[' must be quoted
+
+  [insert: normal]        # a mode whose unbound keys fall through to "normal"
+  C-a = line-start
+  ```
+
+  Keys are written as described for [KeySequence], and actions are any text, optionally quoted.
+  A section can be repeated to add to it. Every parent must be defined somewhere in the file, and
+  parents can't form a loop.
+  */
+#[derive(Clone, Debug, Default)]
+pub struct Keymaps {
+    modes: HashMap<String, Keymap>,
+}
+
+impl Keymaps {
+    /// The mode that bindings before the first section header belong to.
+    pub const DEFAULT_MODE: &'static str = "default";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads keymaps from a file in the format described above.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        fs::read_to_string(path)?.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn get(&self, mode: &str) -> Option<&Keymap> {
+        self.modes.get(mode)
+    }
+
+    /// Returns the keymap for a mode, adding an empty one if there isn't one yet.
+    pub fn mode(&mut self, mode: &str) -> &mut Keymap {
+        self.modes.entry(mode.to_string()).or_default()
+    }
+
+    pub fn insert(&mut self, mode: impl Into<String>, keymap: Keymap) {
+        self.modes.insert(mode.into(), keymap);
+    }
+
+    pub fn modes(&self) -> impl Iterator<Item = (&str, &Keymap)> {
+        self.modes.iter().map(|(name, keymap)| (name.as_str(), keymap))
+    }
+}
+
+impl FromStr for Keymaps {
+    type Err = KeymapError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut keymaps = Keymaps::new();
+        let mut mode = Keymaps::DEFAULT_MODE.to_string();
+        // Where each parent was named, for reporting problems with it.
+        // In file order, so the error reported for a bad parent is always the first one.
+        let mut parent_lines = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let error = |kind| KeymapError { line: i + 1, kind };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[') {
+                let header = strip_comment(header).strip_suffix(']').ok_or_else(|| error(KeymapErrorKind::BadSection))?;
+                let (name, parent) = match header.split_once(':') {
+                    Some((name, parent)) => (name.trim(), Some(parent.trim())),
+                    None => (header.trim(), None),
+                };
+                if name.is_empty() || parent == Some("") {
+                    return Err(error(KeymapErrorKind::BadSection));
+                }
+                mode = name.to_string();
+                let keymap = keymaps.mode(name);
+                if let Some(parent) = parent {
+                    keymap.set_parent(Some(parent.to_string()));
+                    parent_lines.push((mode.clone(), i + 1));
+                }
+                continue;
+            }
+            let (keys, rest) = match read_quoted(line) {
+                Some(quoted) => {
+                    let (keys, rest) = quoted.ok_or_else(|| error(KeymapErrorKind::UnterminatedString))?;
+                    let rest = rest.trim_start().strip_prefix('=').ok_or_else(|| error(KeymapErrorKind::MissingEquals))?;
+                    (keys, rest)
+                },
+                None => {
+                    let (keys, rest) = line.split_once('=').ok_or_else(|| error(KeymapErrorKind::MissingEquals))?;
+                    (keys.trim().to_string(), rest)
+                },
+            };
+            let rest = rest.trim();
+            let action = match read_quoted(rest) {
+                Some(quoted) => {
+                    let (action, rest) = quoted.ok_or_else(|| error(KeymapErrorKind::UnterminatedString))?;
+                    if !strip_comment(rest).is_empty() {
+                        return Err(error(KeymapErrorKind::MissingAction));
+                    }
+                    action
+                },
+                None => strip_comment(rest).to_string(),
+            };
+            if action.is_empty() {
+                return Err(error(KeymapErrorKind::MissingAction));
+            }
+            let keys: KeySequence = keys.parse().map_err(|e| error(KeymapErrorKind::Key(e)))?;
+            keymaps.mode(&mode).bind(&keys.0, action);
+        }
+        for (name, line) in parent_lines {
+            let error = |kind| KeymapError { line, kind };
+            let mut current = name.as_str();
+            for _ in 0..keymaps.modes.len() {
+                match keymaps.modes[current].parent() {
+                    None => break,
+                    Some(parent) if keymaps.modes.contains_key(parent) => { current = parent; },
+                    Some(_) => { return Err(error(KeymapErrorKind::UnknownParent)); },
+                }
+            }
+            if keymaps.modes[current].parent().is_some() {
+                return Err(error(KeymapErrorKind::ParentLoop));
+            }
+        }
+        Ok(keymaps)
+    }
+}
+
+/// What a [Dispatcher] made of the keys it was given.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Resolved {
+    /// The keys are bound to an action.
+    Action {
+        action: String,
+        keys: Vec<ExtendedChar>,
+    },
+    /// A printable character with no binding, which usually means inserting it.
+    Insert(char),
+    /// Keys with no binding that aren't a printable character.
+    Unbound(Vec<ExtendedChar>),
+}
+
+/**
+  Turns parsed input into actions using a set of [Keymaps]. The dispatcher keeps a stack of modes,
+  such as `insert` pushed on top of `normal`, and looks keys up in the mode on top of the stack
+  and then in its parents.
+
+  When the keys typed so far are the start of a longer binding they are held as pending, and the
+  bindings they could still become are available from [pending_bindings] for a which-key style
+  hint. If the next key doesn't continue any binding, the longest bound start of the pending keys
+  is resolved and the rest is looked up again; when no start of them is bound, the prefix that led
+  nowhere is reported as [Unbound](Resolved::Unbound) and the key that ended it is looked up
+  again, so `d i` with only `d w` bound gives `d` and then whatever `i` is bound to. A single
+  printable key that isn't bound comes out as
+  [Insert](Resolved::Insert).
+
+  [pending_bindings]: Dispatcher::pending_bindings
+  */
+#[derive(Clone, Debug)]
+pub struct Dispatcher {
+    keymaps: Keymaps,
+    modes: Vec<String>,
+    pending: Vec<ExtendedChar>,
+    timeout: Option<Duration>,
+    last_key: Option<Instant>,
+}
+
+impl Dispatcher {
+    /// Creates a dispatcher for `keymaps` starting out in `mode`.
+    pub fn new(keymaps: Keymaps, mode: &str) -> Self {
+        Dispatcher {
+            keymaps,
+            modes: vec![mode.to_string()],
+            pending: Vec::new(),
+            timeout: None,
+            last_key: None,
+        }
+    }
+
+    pub fn keymaps(&self) -> &Keymaps {
+        &self.keymaps
+    }
+
+    pub fn keymaps_mut(&mut self) -> &mut Keymaps {
+        &mut self.keymaps
+    }
+
+    /// Returns the current mode, the one on top of the stack.
+    pub fn mode(&self) -> &str {
+        self.modes.last().map(String::as_str).unwrap_or(Keymaps::DEFAULT_MODE)
+    }
+
+    /// Enters `mode`, remembering the current one for [pop_mode](Dispatcher::pop_mode).
+    pub fn push_mode(&mut self, mode: &str) {
+        self.pending.clear();
+        self.modes.push(mode.to_string());
+    }
+
+    /// Goes back to the previous mode, returning the one that was left. The last mode is never popped.
+    pub fn pop_mode(&mut self) -> Option<String> {
+        if self.modes.len() < 2 { return None; }
+        self.pending.clear();
+        self.modes.pop()
+    }
+
+    /// Replaces the current mode with `mode`.
+    pub fn set_mode(&mut self, mode: &str) {
+        self.pending.clear();
+        self.modes.pop();
+        self.modes.push(mode.to_string());
+    }
+
+    /**
+      Sets how long to wait for the rest of a binding before resolving the pending keys as they
+      are. `None` waits until the next key arrives.
+      */
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Returns the keys held as the start of a longer binding.
+    pub fn pending(&self) -> &[ExtendedChar] {
+        &self.pending
+    }
+
+    /**
+      Returns the bindings that the pending keys could still become, as the keys that would finish
+      them and their actions, in the order of the keys' notation.
+      */
+    pub fn pending_bindings(&self) -> Vec<(Vec<ExtendedChar>, &str)> {
+        let mut found: HashMap<&[ExtendedChar], &str> = HashMap::new();
+        for keymap in self.chain() {
+            for (keys, action) in keymap.bindings() {
+                if keys.len() > self.pending.len() && keys.starts_with(&self.pending) {
+                    // Closer modes were visited first and take precedence.
+                    found.entry(&keys[self.pending.len()..]).or_insert(action);
+                }
+            }
+        }
+        let mut found: Vec<_> = found.into_iter().map(|(keys, action)| (keys.to_vec(), action)).collect();
+        found.sort_by_cached_key(|(keys, _)| KeySequence(keys.clone()).to_string());
+        found
+    }
+
+    /// Returns the time at which the pending keys will be resolved, if there is a timeout.
+    pub fn deadline(&self) -> Option<Instant> {
+        match (self.pending.is_empty(), self.last_key, self.timeout) {
+            (false, Some(last), Some(timeout)) => Some(last + timeout),
+            _ => None,
+        }
+    }
+
+    /**
+      Adds a key that arrived at `now` and returns whatever can be resolved. If the pending keys
+      have been waiting longer than the timeout, they are resolved first.
+      */
+    pub fn push(&mut self, key: ExtendedChar, now: Instant) -> Vec<Resolved> {
+        let mut out = Vec::new();
+        if self.deadline().is_some_and(|d| d <= now) {
+            self.resolve(true, &mut out);
+        }
+        self.pending.push(key);
+        self.last_key = Some(now);
+        self.resolve(false, &mut out);
+        out
+    }
+
+    /// Stops waiting for the rest of a binding and resolves the pending keys as they are.
+    pub fn flush(&mut self) -> Vec<Resolved> {
+        let mut out = Vec::new();
+        self.resolve(true, &mut out);
+        out
+    }
+
+    /// Returns the keymaps to look keys up in, closest first.
+    fn chain(&self) -> impl Iterator<Item = &Keymap> {
+        let mut next = self.keymaps.get(self.mode());
+        // A loop of parents can only come from keymaps built by hand, so just stop somewhere.
+        (0..=self.keymaps.modes.len()).map_while(move |_| {
+            let keymap = next?;
+            next = keymap.parent().and_then(|parent| self.keymaps.get(parent));
+            Some(keymap)
+        })
+    }
+
+    fn action(&self, keys: &[ExtendedChar]) -> Option<String> {
+        self.chain().find_map(|keymap| keymap.get(keys)).map(str::to_string)
+    }
+
+    fn resolve(&mut self, finished: bool, out: &mut Vec<Resolved>) {
+        while !self.pending.is_empty() {
+            if !finished && self.chain().any(|keymap| keymap.is_prefix(&self.pending)) {
+                break;
+            }
+            let bound = (1..=self.pending.len()).rev()
+                .find_map(|len| self.action(&self.pending[..len]).map(|action| (len, action)));
+            match bound {
+                Some((len, action)) => {
+                    let keys = self.pending.drain(..len).collect();
+                    out.push(Resolved::Action { action, keys });
+                },
+                None => {
+                    // Only the prefix that led nowhere is dropped; the key after it starts afresh.
+                    let dead = (1..self.pending.len()).rev()
+                        .find(|&len| self.chain().any(|keymap| keymap.is_prefix(&self.pending[..len])));
+                    let keys: Vec<_> = self.pending.drain(..dead.unwrap_or(1)).collect();
+                    out.push(match keys.as_slice() {
+                        _ if dead.is_some() => Resolved::Unbound(keys),
+                        [ExtendedChar::Byte(b)] if printable(*b) => Resolved::Insert(char::from(*b)),
+                        [ExtendedChar::Char(c)] => Resolved::Insert(*c),
+                        _ => Resolved::Unbound(keys),
+                    });
+                },
+            }
+        }
+    }
+}
+
+/// Returns true for the bytes that stand for a character that can be inserted.
+fn printable(b: u8) -> bool {
+    (0x20..0x7f).contains(&b) || b >= 0xa0
+}
+
+/**
+  An iterator over the actions resolved from parsed input by a [Dispatcher]. While keys are
+  pending it waits for the next one only until the dispatcher's deadline.
+  */
+pub struct Actions<I> {
+    input: I,
+    dispatcher: Dispatcher,
+    out: VecDeque<Resolved>,
+    finished: bool,
+}
+
+impl<I: TimedInput> Actions<I> {
+    pub fn new(input: I, dispatcher: Dispatcher) -> Self {
+        Actions {
+            input,
+            dispatcher,
+            out: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    /// Returns the dispatcher, so that the mode can be changed in response to actions.
+    pub fn dispatcher(&mut self) -> &mut Dispatcher {
+        &mut self.dispatcher
+    }
+}
+
+impl<I: TimedInput> Iterator for Actions<I> {
+    type Item = Resolved;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(resolved) = self.out.pop_front() {
+                return Some(resolved);
+            }
+            if self.finished { return None; }
+            let key = match self.dispatcher.deadline() {
+                Some(deadline) => self.input.next_deadline(deadline),
+                None => self.input.next(),
+            };
+            match key {
+                Some(key) => { self.out.extend(self.dispatcher.push(key, Instant::now())); },
+                None => {
+                    self.out.extend(self.dispatcher.flush());
+                    self.finished = self.input.is_done();
+                },
+            }
+        }
+    }
+}
+
+/**
+  A numeric argument typed before a command, in the style of Emacs. Ctrl-U starts one at 4 and
+  multiplies it by 4 each time it is repeated, Alt with a digit starts or continues a number, and
+  Alt-minus makes it negative. Once an argument has been started, plain digits and minus signs
+  carry on typing it. A Ctrl-U after some digits ends the argument, so that a digit can follow it
+  as the command, as in `C-u 5 C-u 1` for five ones.
+
+  Keys that aren't part of the argument are handed back, and the argument is then kept for the
+  command they start until it is [taken](PrefixArgument::take).
+  */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PrefixArgument {
+    active: bool,
+    finished: bool,
+    multiplier: i64,
+    negative: bool,
+    digits: Option<i64>,
+}
+
+impl PrefixArgument {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a key, returning it again if it isn't part of the argument.
+    pub fn push(&mut self, key: ExtendedChar) -> Option<ExtendedChar> {
+        if self.finished { return Some(key); }
+        // Plain digits and minus signs only count once an argument has been started.
+        let (started, b) = match key {
+            ExtendedChar::Byte(21) => {
+                if !self.active {
+                    self.start(4);
+                }
+                else if self.digits.is_some() || self.negative {
+                    self.finished = true;
+                }
+                else {
+                    self.multiplier = self.multiplier.saturating_mul(4);
+                }
+                return None;
+            },
+            ExtendedChar::AltByte(b) => (true, b),
+            ExtendedChar::Byte(b) => (self.active, b),
+            _ => (false, 0),
+        };
+        match b {
+            b'0'..=b'9' if started => {
+                if !self.active {
+                    self.start(1);
+                }
+                let digit = i64::from(b - b'0');
+                self.digits = Some(self.digits.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+                None
+            },
+            b'-' if started && !self.negative && self.digits.is_none() => {
+                if !self.active {
+                    self.start(1);
+                }
+                self.negative = true;
+                None
+            },
+            _ => {
+                self.finished = self.active;
+                Some(key)
+            },
+        }
+    }
+
+    /// Returns true while an argument is being typed or waiting to be taken.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Returns true once the argument has been typed and is waiting to be taken.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /**
+      Returns the value of the argument typed so far, for showing while it is being typed. A
+      minus sign with no digits stands for -1, and Ctrl-U with no digits for a power of 4.
+      */
+    pub fn value(&self) -> Option<i64> {
+        if !self.active { return None; }
+        let value = match self.digits {
+            Some(digits) => digits,
+            None if self.negative => 1,
+            None => self.multiplier,
+        };
+        Some(if self.negative { -value } else { value })
+    }
+
+    /// Returns the value of the argument and starts over.
+    pub fn take(&mut self) -> Option<i64> {
+        let value = self.value();
+        *self = Self::default();
+        value
+    }
+
+    fn start(&mut self, multiplier: i64) {
+        self.active = true;
+        self.multiplier = multiplier;
+    }
+}
+
+/// A key resolved by a [Dispatcher], along with the [PrefixArgument] typed before it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Counted {
+    pub count: Option<i64>,
+    pub resolved: Resolved,
+}
+
+impl Counted {
+    /// Returns the number of times to repeat the command, which is 1 when no argument was given.
+    pub fn repeat(&self) -> i64 {
+        self.count.unwrap_or(1)
+    }
+}
+
+/**
+  An iterator like [Actions] that also reads [PrefixArgument]s, attaching each one to the next
+  key resolved after it. The argument keys themselves never reach the dispatcher, so they can't
+  be bound to anything else while an argument can be started.
+  */
+pub struct CountedActions<I> {
+    input: I,
+    dispatcher: Dispatcher,
+    argument: PrefixArgument,
+    out: VecDeque<Resolved>,
+    finished: bool,
+}
+
+impl<I: TimedInput> CountedActions<I> {
+    pub fn new(input: I, dispatcher: Dispatcher) -> Self {
+        CountedActions {
+            input,
+            dispatcher,
+            argument: PrefixArgument::new(),
+            out: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    /// Returns the dispatcher, so that the mode can be changed in response to actions.
+    pub fn dispatcher(&mut self) -> &mut Dispatcher {
+        &mut self.dispatcher
+    }
+
+    /// Returns the argument being typed, so that it can be shown.
+    pub fn argument(&self) -> &PrefixArgument {
+        &self.argument
+    }
+}
+
+impl<I: TimedInput> Iterator for CountedActions<I> {
+    type Item = Counted;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(resolved) = self.out.pop_front() {
+                let count = if self.argument.is_finished() { self.argument.take() } else { None };
+                return Some(Counted { count, resolved });
+            }
+            if self.finished { return None; }
+            let key = match self.dispatcher.deadline() {
+                Some(deadline) => self.input.next_deadline(deadline),
+                None => self.input.next(),
+            };
+            match key {
+                Some(key) => {
+                    // Keys finishing a binding are never taken as the start of an argument.
+                    let key = if self.dispatcher.pending().is_empty() { self.argument.push(key) } else { Some(key) };
+                    if let Some(key) = key {
+                        self.out.extend(self.dispatcher.push(key, Instant::now()));
+                    }
+                },
+                None => {
+                    self.out.extend(self.dispatcher.flush());
+                    self.finished = self.input.is_done();
+                },
+            }
+        }
+    }
+}
+
+/// Removes a comment that follows something else on a line.
+fn strip_comment(s: &str) -> &str {
+    match s.find(" #").or_else(|| s.find("\t#")) {
+        Some(i) => s[..i].trim(),
+        None => s.trim(),
+    }
+}
+
+/**
+  Reads a double-quoted string from the start of `s`, in which `\"` and `\\` stand for a quote and
+  a backslash. Returns `None` if `s` doesn't start with a quote, and `Some(None)` if the string
+  isn't closed, or otherwise the string and whatever follows it.
+  */
+fn read_quoted(s: &str) -> Option<Option<(String, &str)>> {
+    let rest = s.strip_prefix('"')?;
+    let mut quoted = String::new();
+    let mut chars = rest.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => { return Some(Some((quoted, &rest[i + 1..]))); },
+            '\\' => match chars.next() {
+                Some((_, c)) => { quoted.push(c); },
+                None => break,
+            },
+            c => { quoted.push(c); },
+        }
+    }
+    Some(None)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeymapError {
+    line: usize,
+    pub(super) kind: KeymapErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeymapErrorKind {
+    Key(KeyParseError),
+    MissingEquals,
+    MissingAction,
+    BadSection,
+    UnterminatedString,
+    UnknownParent,
+    ParentLoop,
+}
+
+impl KeymapError {
+    /// Returns the line the problem was found on, counting from 1.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn __description(&self) -> &str {
+        match &self.kind {
+            KeymapErrorKind::Key(e) => e.__description(),
+            KeymapErrorKind::MissingEquals => "expected `keys = action`",
+            KeymapErrorKind::MissingAction => "expected an action after `=`",
+            KeymapErrorKind::BadSection => "expected `[mode]` or `[mode: parent]`",
+            KeymapErrorKind::UnterminatedString => "missing closing quote",
+            KeymapErrorKind::UnknownParent => "parent mode isn't defined",
+            KeymapErrorKind::ParentLoop => "mode is its own ancestor",
+        }
+    }
+}
+
+impl Display for KeymapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.__description())
+    }
+}
+
+impl Error for KeymapError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::chord::ScriptedInput;
+    use crate::keymap::*;
+    use crate::parse::{ExtendedChar::*, SpecialChar::*};
+
+    #[test]
+    fn test_load_keymaps() {
+        let text = r#"
+            # A comment.
+            C-q = quit
+
+            [normal]
+            C-x C-s = save
+            gg = "goto top"       # trailing comment
+            <C-w>j = window-down
+            "=" = indent
+            "\"" = register
+
+            [insert: normal]
+            M-Left = word-left
+            [normal]
+            C-x C-s = write
+        "#;
+        let keymaps: Keymaps = text.parse().unwrap();
+        let default = keymaps.get(Keymaps::DEFAULT_MODE).unwrap();
+        assert_eq!(default.get(&[Byte(17)]), Some("quit"));
+        let normal = keymaps.get("normal").unwrap();
+        assert_eq!(normal.parent(), None);
+        assert_eq!(normal.len(), 5);
+        assert_eq!(normal.get(&[Byte(24), Byte(19)]), Some("write"));
+        assert_eq!(normal.get(&[Byte(b'g'), Byte(b'g')]), Some("goto top"));
+        assert_eq!(normal.get(&[Byte(23), Byte(b'j')]), Some("window-down"));
+        assert_eq!(normal.get(&[Byte(b'=')]), Some("indent"));
+        assert_eq!(normal.get(&[Byte(b'"')]), Some("register"));
+        assert!(normal.is_prefix(&[Byte(b'g')]));
+        assert!(!normal.is_prefix(&[Byte(b'g'), Byte(b'g')]));
+        let insert = keymaps.get("insert").unwrap();
+        assert_eq!(insert.parent(), Some("normal"));
+        assert_eq!(insert.get(&[Alt(LeftArrow)]), Some("word-left"));
+    }
+
+    #[test]
+    fn test_keymap_errors() {
+        let error = |text: &str| {
+            let e = text.parse::<Keymaps>().unwrap_err();
+            (e.line(), e.kind)
+        };
+        assert_eq!(error("a = b\nc"), (2, KeymapErrorKind::MissingEquals));
+        assert_eq!(error("a ="), (1, KeymapErrorKind::MissingAction));
+        assert_eq!(error("\"a = b"), (1, KeymapErrorKind::UnterminatedString));
+        assert_eq!(error("[normal"), (1, KeymapErrorKind::BadSection));
+        assert_eq!(error("[a: ]"), (1, KeymapErrorKind::BadSection));
+        assert!(matches!(error("\n<Nope> = x"), (2, KeymapErrorKind::Key(_))));
+        assert_eq!(error("[a: b]\nx = y"), (1, KeymapErrorKind::UnknownParent));
+        assert_eq!(error("[a: b]\n[b: c]\n[c: a]"), (1, KeymapErrorKind::ParentLoop));
+        assert_eq!(error("[a]\n[b: x]\n[c: y]"), (2, KeymapErrorKind::UnknownParent));
+        assert_eq!(format!("{}", "a =".parse::<Keymaps>().unwrap_err()), "line 1: expected an action after `=`");
+    }
+
+    fn dispatcher() -> Dispatcher {
+        let text = "
+            [normal]
+            C-x C-s = save
+            C-x C-c = quit
+            C-x = prefix-alone
+            g g = top
+            d w = delete-word
+            i = insert-mode
+
+            [insert: normal]
+            C-x C-s = save-and-stay
+            <Esc> = normal-mode
+        ";
+        Dispatcher::new(text.parse().unwrap(), "normal")
+    }
+
+    #[test]
+    fn test_dispatcher() {
+        let action = |action: &str, keys: &[ExtendedChar]| Resolved::Action { action: action.to_string(), keys: keys.to_vec() };
+        let now = Instant::now();
+        let mut dispatcher = dispatcher();
+        let push = |dispatcher: &mut Dispatcher, keys: &[ExtendedChar]| {
+            keys.iter().flat_map(|k| dispatcher.push(*k, now)).collect::<Vec<_>>()
+        };
+        assert_eq!(push(&mut dispatcher, &[Byte(b'i')]), vec![action("insert-mode", &[Byte(b'i')])]);
+        // Prefixes are held, with the ways to finish them listed.
+        assert_eq!(push(&mut dispatcher, &[Byte(24)]), vec![]);
+        assert_eq!(dispatcher.pending(), &[Byte(24)]);
+        assert_eq!(dispatcher.pending_bindings(), vec![(vec![Byte(3)], "quit"), (vec![Byte(19)], "save")]);
+        assert_eq!(push(&mut dispatcher, &[Byte(19)]), vec![action("save", &[Byte(24), Byte(19)])]);
+        // A bound prefix resolves when the next key doesn't continue it, which is looked up again.
+        assert_eq!(push(&mut dispatcher, &[Byte(24), Byte(b'i')]), vec![
+            action("prefix-alone", &[Byte(24)]),
+            action("insert-mode", &[Byte(b'i')]),
+        ]);
+        // A prefix that leads nowhere is unbound, and the key that ended it is looked up again.
+        assert_eq!(push(&mut dispatcher, &[Byte(b'd'), Byte(b'i')]), vec![
+            Resolved::Unbound(vec![Byte(b'd')]),
+            action("insert-mode", &[Byte(b'i')]),
+        ]);
+        assert_eq!(push(&mut dispatcher, &[Byte(b'd'), Byte(b'x')]), vec![Resolved::Unbound(vec![Byte(b'd')]), Resolved::Insert('x')]);
+        // Printable keys with no binding are inserted.
+        assert_eq!(push(&mut dispatcher, &[Byte(b'x'), Char('\u{1F600}'), Byte(1)]), vec![
+            Resolved::Insert('x'),
+            Resolved::Insert('\u{1F600}'),
+            Resolved::Unbound(vec![Byte(1)]),
+        ]);
+        // Modes on the stack fall through to their parents.
+        dispatcher.push_mode("insert");
+        assert_eq!(dispatcher.mode(), "insert");
+        assert_eq!(push(&mut dispatcher, &[Byte(24), Byte(19)]), vec![action("save-and-stay", &[Byte(24), Byte(19)])]);
+        assert_eq!(push(&mut dispatcher, &[Byte(b'g'), Byte(b'g')]), vec![action("top", &[Byte(b'g'), Byte(b'g')])]);
+        assert_eq!(dispatcher.pop_mode(), Some("insert".to_string()));
+        assert_eq!(dispatcher.pop_mode(), None);
+        assert_eq!(dispatcher.mode(), "normal");
+    }
+
+    #[test]
+    fn test_dispatcher_timeout() {
+        let ms = Duration::from_millis;
+        let mut dispatcher = dispatcher();
+        dispatcher.set_timeout(Some(ms(500)));
+        let start = Instant::now();
+        assert_eq!(dispatcher.push(Byte(b'g'), start), vec![]);
+        assert_eq!(dispatcher.deadline(), Some(start + ms(500)));
+        assert_eq!(dispatcher.push(Byte(b'g'), start + ms(600)), vec![Resolved::Insert('g')]);
+        assert_eq!(dispatcher.flush(), vec![Resolved::Insert('g')]);
+        assert_eq!(dispatcher.deadline(), None);
+    }
+
+    #[test]
+    fn test_prefix_argument() {
+        let argument = |keys: &[ExtendedChar]| {
+            let mut argument = PrefixArgument::new();
+            let rest: Vec<_> = keys.iter().filter_map(|&key| argument.push(key)).collect();
+            (argument.take(), rest)
+        };
+        assert_eq!(argument(&[Byte(21), Byte(b'x')]), (Some(4), vec![Byte(b'x')]));
+        assert_eq!(argument(&[Byte(21), Byte(21), Byte(21), Byte(b'x')]), (Some(64), vec![Byte(b'x')]));
+        assert_eq!(argument(&[Byte(21), Byte(b'1'), Byte(b'2'), Byte(b'x')]), (Some(12), vec![Byte(b'x')]));
+        assert_eq!(argument(&[Byte(21), Byte(b'5'), Byte(21), Byte(b'1')]), (Some(5), vec![Byte(b'1')]));
+        assert_eq!(argument(&[AltByte(b'3'), Byte(b'0'), Byte(b'x')]), (Some(30), vec![Byte(b'x')]));
+        assert_eq!(argument(&[AltByte(b'-'), Byte(b'x')]), (Some(-1), vec![Byte(b'x')]));
+        assert_eq!(argument(&[AltByte(b'-'), AltByte(b'2'), Byte(b'-')]), (Some(-2), vec![Byte(b'-')]));
+        assert_eq!(argument(&[Byte(21), Byte(b'-'), Byte(b'x')]), (Some(-1), vec![Byte(b'x')]));
+        // Without an argument started, digits and minus signs are ordinary keys.
+        assert_eq!(argument(&[Byte(b'1'), Byte(b'-')]), (None, vec![Byte(b'1'), Byte(b'-')]));
+    }
+
+    #[test]
+    fn test_counted_actions() {
+        let keys = vec![AltByte(b'3'), Byte(24), Byte(19), Byte(b'i'), Byte(21), Byte(b'g'), Byte(b'g'), Byte(b'2')];
+        let counted: Vec<_> = CountedActions::new(ScriptedInput::new(keys.into_iter().map(Some).collect()), dispatcher()).map(|c| (c.count, c.resolved)).collect();
+        let action = |action: &str, keys: &[ExtendedChar]| Resolved::Action { action: action.to_string(), keys: keys.to_vec() };
+        assert_eq!(counted, vec![
+            (Some(3), action("save", &[Byte(24), Byte(19)])),
+            (None, action("insert-mode", &[Byte(b'i')])),
+            (Some(4), action("top", &[Byte(b'g'), Byte(b'g')])),
+            (None, Resolved::Insert('2')),
+        ]);
+    }
+}
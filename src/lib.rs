@@ -1,2 +1,15 @@
+pub mod chord;
+pub mod complete;
+pub mod history;
+pub mod keymap;
+pub mod line;
 pub mod parse;
+mod pipe;
+pub mod poll;
+pub mod prompt;
+pub mod record;
+pub mod select;
+#[cfg(feature = "stream")]
+pub mod stream;
 pub mod user;
+pub mod vi;
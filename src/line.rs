@@ -0,0 +1,1385 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    io::{self, Stdout, Write},
+};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+use crate::{
+    complete::{Completer, common_prefix, format_columns},
+    history::History,
+    parse::{
+        ExtendedChar::{self, *},
+        SpecialChar::*,
+    },
+};
+
+/// The text of a line being edited and the position of the cursor in it, counted in characters.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LineBuffer {
+    text: Vec<char>,
+    cursor: usize,
+}
+
+impl LineBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn chars(&self) -> &[char] {
+        &self.text
+    }
+
+    pub fn text(&self) -> String {
+        self.text.iter().collect()
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn len(&self) -> usize {
+        self.text.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// Replaces the text and puts the cursor at the end of it.
+    pub fn set_text(&mut self, text: &str) {
+        self.text = text.chars().collect();
+        self.cursor = self.text.len();
+    }
+
+    /// Moves the cursor to `cursor`, or to the end of the text if that is past it.
+    pub fn set_cursor(&mut self, cursor: usize) {
+        self.cursor = cursor.min(self.text.len());
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    pub fn insert_str(&mut self, s: &str) {
+        let count = self.text.len();
+        self.text.splice(self.cursor..self.cursor, s.chars());
+        self.cursor += self.text.len() - count;
+    }
+
+    /// Removes the characters from `start` up to `end` and returns them, keeping the cursor on the same character.
+    pub fn remove(&mut self, start: usize, end: usize) -> String {
+        let end = end.min(self.text.len());
+        let start = start.min(end);
+        let removed = self.text.drain(start..end).collect();
+        if self.cursor >= end {
+            self.cursor -= end - start;
+        }
+        else if self.cursor > start {
+            self.cursor = start;
+        }
+        removed
+    }
+
+    /// Deletes the character before the cursor, returning false if there isn't one.
+    pub fn backspace(&mut self) -> bool {
+        if self.cursor == 0 { return false; }
+        self.remove(self.cursor - 1, self.cursor);
+        true
+    }
+
+    /// Deletes the character under the cursor, returning false if there isn't one.
+    pub fn delete(&mut self) -> bool {
+        if self.cursor == self.text.len() { return false; }
+        self.remove(self.cursor, self.cursor + 1);
+        true
+    }
+
+    pub fn move_left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn move_right(&mut self) {
+        self.set_cursor(self.cursor + 1);
+    }
+
+    pub fn move_home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn move_end(&mut self) {
+        self.cursor = self.text.len();
+    }
+
+    /// Returns the start of the line the cursor is on, just after the newline before it.
+    pub fn line_start(&self) -> usize {
+        self.text[..self.cursor].iter().rposition(|&c| c == '\n').map_or(0, |i| i + 1)
+    }
+
+    /// Returns the end of the line the cursor is on, at the newline after it.
+    pub fn line_end(&self) -> usize {
+        self.text[self.cursor..].iter().position(|&c| c == '\n').map_or(self.text.len(), |i| self.cursor + i)
+    }
+
+    /**
+      Moves the cursor to the line above, as close as it can get to the same column. Returns false
+      if the cursor is already on the first line.
+      */
+    pub fn move_up(&mut self) -> bool {
+        let start = self.line_start();
+        if start == 0 { return false; }
+        let column = self.column();
+        let above = self.text[..start - 1].iter().rposition(|&c| c == '\n').map_or(0, |i| i + 1);
+        self.cursor = self.index_at_column(above, column);
+        true
+    }
+
+    /**
+      Moves the cursor to the line below, as close as it can get to the same column. Returns false
+      if the cursor is already on the last line.
+      */
+    pub fn move_down(&mut self) -> bool {
+        let end = self.line_end();
+        if end == self.text.len() { return false; }
+        let column = self.column();
+        self.cursor = self.index_at_column(end + 1, column);
+        true
+    }
+
+    /// Returns the display column of the cursor within its line.
+    fn column(&self) -> usize {
+        self.text[self.line_start()..self.cursor].iter().fold(0, |col, &c| col + char_width(c, col))
+    }
+
+    /// Returns the last position on the line starting at `start` that isn't past `column`.
+    fn index_at_column(&self, start: usize, column: usize) -> usize {
+        let mut col = 0;
+        let mut i = start;
+        while i < self.text.len() && self.text[i] != '\n' {
+            col += char_width(self.text[i], col);
+            if col > column { break; }
+            i += 1;
+        }
+        i
+    }
+
+    /// Returns the start of the word before the cursor, skipping anything between the cursor and it.
+    pub fn word_start(&self) -> usize {
+        let mut pos = self.cursor;
+        while pos > 0 && !is_word(self.text[pos - 1]) {
+            pos -= 1;
+        }
+        while pos > 0 && is_word(self.text[pos - 1]) {
+            pos -= 1;
+        }
+        pos
+    }
+
+    /// Returns the end of the word after the cursor, skipping anything between the cursor and it.
+    pub fn word_end(&self) -> usize {
+        let mut pos = self.cursor;
+        while pos < self.text.len() && !is_word(self.text[pos]) {
+            pos += 1;
+        }
+        while pos < self.text.len() && is_word(self.text[pos]) {
+            pos += 1;
+        }
+        pos
+    }
+
+    /**
+      Swaps the character before the cursor with the one under it and moves the cursor past both.
+      At the end of the line the last two characters are swapped instead.
+      */
+    pub fn transpose(&mut self) -> bool {
+        if self.cursor == 0 || self.text.len() < 2 { return false; }
+        if self.cursor == self.text.len() {
+            self.cursor -= 1;
+        }
+        self.text.swap(self.cursor - 1, self.cursor);
+        self.cursor += 1;
+        true
+    }
+}
+
+/// The distance between tab stops, which tabs in the text are drawn up to with spaces.
+const TAB_WIDTH: usize = 8;
+
+/// Returns the number of columns that `c` takes up when it is drawn at column `col`.
+fn char_width(c: char, col: usize) -> usize {
+    match c {
+        '\t' => TAB_WIDTH - col % TAB_WIDTH,
+        _ => c.width().unwrap_or(0),
+    }
+}
+
+/// Returns true for the characters that word movement and deletion treat as part of a word.
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/**
+  Text removed by the kill commands, most recent first, for yanking back. Kills that follow each
+  other are joined into a single entry, so that yanking restores everything they removed.
+  */
+#[derive(Clone, Debug)]
+pub struct KillRing {
+    entries: VecDeque<String>,
+    capacity: usize,
+    yank: usize,
+}
+
+impl Default for KillRing {
+    fn default() -> Self {
+        Self::new(KillRing::DEFAULT_CAPACITY)
+    }
+}
+
+impl KillRing {
+    pub const DEFAULT_CAPACITY: usize = 60;
+
+    pub fn new(capacity: usize) -> Self {
+        KillRing {
+            entries: VecDeque::new(),
+            capacity: capacity.max(1),
+            yank: 0,
+        }
+    }
+
+    /// Adds a new entry, dropping the oldest one if the ring is full.
+    pub fn push(&mut self, text: String) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_back();
+        }
+        self.entries.push_front(text);
+        self.yank = 0;
+    }
+
+    /**
+      Joins `text` onto the most recent entry, at the end if it was killed after it and at the start
+      if it was killed before it.
+      */
+    pub fn extend(&mut self, text: &str, before: bool) {
+        match self.entries.front_mut() {
+            Some(entry) if before => { entry.insert_str(0, text); },
+            Some(entry) => { entry.push_str(text); },
+            None => { self.push(text.to_string()); },
+        }
+        self.yank = 0;
+    }
+
+    /// Returns the most recent entry, and starts rotating from it.
+    pub fn yank(&mut self) -> Option<&str> {
+        self.yank = 0;
+        self.entries.front().map(String::as_str)
+    }
+
+    /// Returns the entry before the one last yanked, wrapping around to the most recent one.
+    pub fn rotate(&mut self) -> Option<&str> {
+        if self.entries.is_empty() { return None; }
+        self.yank = (self.yank + 1) % self.entries.len();
+        self.entries.get(self.yank).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// What a [LineEditor] did with a key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Edit {
+    /// The line is still being edited.
+    Continue,
+    /// Enter was pressed, finishing the line.
+    Accept(String),
+    /// The line was abandoned with Ctrl-C.
+    Cancel,
+    /// Ctrl-D was pressed on an empty line.
+    EndOfFile,
+}
+
+/**
+  Decides whether the text in a [LineEditor] is complete when Enter is pressed. If it isn't, Enter
+  starts a new line in the text instead, the way a REPL carries on with an unfinished block.
+  Closures taking the text are validators too.
+  */
+pub trait Validator {
+    fn is_complete(&self, text: &str) -> bool;
+}
+
+impl<F: Fn(&str) -> bool> Validator for F {
+    fn is_complete(&self, text: &str) -> bool {
+        self(text)
+    }
+}
+
+/// A position on the screen relative to the start of the prompt, as a row and a column.
+type Position = (usize, usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LastEdit {
+    Other,
+    Kill,
+    Yank { start: usize, end: usize },
+    Cycle { start: usize, end: usize, index: usize },
+}
+
+/// An incremental search through the history, started with Ctrl-R or Ctrl-S.
+#[derive(Clone, Debug)]
+struct Search {
+    query: String,
+    backward: bool,
+    start: usize,
+    found: Option<usize>,
+    failed: bool,
+    original: LineBuffer,
+    original_pos: Option<usize>,
+}
+
+/**
+  A readline-style line editor that takes the characters from [parsed_input] and draws the line on
+  an output, stdout by default. It understands the usual emacs keys: the arrows, Home and End or
+  Ctrl-A and Ctrl-E, Ctrl-Left and Ctrl-Right or Alt-B and Alt-F for words, Backspace, Delete and
+  Ctrl-D, Ctrl-K, Ctrl-U, Ctrl-W, Alt-D and Alt-Backspace to kill, Ctrl-Y and Alt-Y to yank, Ctrl-T
+  to transpose and Ctrl-L to clear the screen. Both the control bytes and the terminal's editing
+  events are accepted, so it works with or without [editing_events].
+
+  Accepted lines are added to the [History]. Up and Down or Ctrl-P and Ctrl-N move through it,
+  Shift-Up and Shift-Down move to the entries that start with the text before the cursor, and
+  Ctrl-R and Ctrl-S search it incrementally, backwards and forwards. During a search, Enter accepts
+  the line found, Esc keeps it for editing, Ctrl-G goes back to the line from before the search and
+  any other editing key stops the search and is handled as usual.
+
+  With a [Completer] set, Tab completes the text before the cursor. A single candidate replaces it,
+  several candidates are replaced by what they have in common, and when that adds nothing they are
+  listed in columns below the line. Shift-Tab instead cycles through the candidates one at a time,
+  coming back around to the original text after the last one.
+
+  The text can run over several lines. With a [Validator] set, Enter only finishes the text once
+  the validator accepts it and otherwise inserts a newline, which Alt-Enter always does. Up and Down
+  move between the lines before moving through the history, while Home, End, Ctrl-K and Ctrl-U work
+  on the line the cursor is on. Text pasted with [BracketedPaste] on goes in exactly as it was
+  pasted, newlines and tabs included, without any of it being taken as editing keys.
+
+  Only the part of the text that changed is redrawn. Lines that are too long for the terminal wrap,
+  and the cursor is placed using the display width of each character, so that wide characters
+  such as CJK and emoji take up two columns. The prompts are assumed to be plain text, without
+  escape sequences.
+
+  [parsed_input]: crate::user::parsed_input
+  [editing_events]: crate::user::InputOptions::editing_events
+  [BracketedPaste]: crate::user::Protocol::BracketedPaste
+  */
+pub struct LineEditor<W: Write = Stdout> {
+    out: W,
+    prompt: String,
+    continuation: Option<String>,
+    buffer: LineBuffer,
+    kill_ring: KillRing,
+    last: LastEdit,
+    history: History,
+    history_pos: Option<usize>,
+    saved: Option<LineBuffer>,
+    search: Option<Search>,
+    completer: Option<Box<dyn Completer>>,
+    completions: Vec<String>,
+    validator: Option<Box<dyn Validator>>,
+    status: Option<String>,
+    shown_status: bool,
+    pasting: bool,
+    paste_cr: bool,
+    width: Option<usize>,
+    shown: Vec<char>,
+    shown_cursor: Position,
+    shown_end: Position,
+}
+
+impl LineEditor<Stdout> {
+    /// Creates an editor that draws on stdout.
+    pub fn new(prompt: &str) -> Self {
+        Self::with_output(io::stdout(), prompt)
+    }
+}
+
+impl<W: Write> LineEditor<W> {
+    pub fn with_output(out: W, prompt: &str) -> Self {
+        LineEditor {
+            out,
+            prompt: prompt.to_string(),
+            continuation: None,
+            buffer: LineBuffer::new(),
+            kill_ring: KillRing::default(),
+            last: LastEdit::Other,
+            history: History::new(),
+            history_pos: None,
+            saved: None,
+            search: None,
+            completer: None,
+            completions: Vec::new(),
+            validator: None,
+            status: None,
+            shown_status: false,
+            pasting: false,
+            paste_cr: false,
+            width: None,
+            shown: Vec::new(),
+            shown_cursor: (0, 0),
+            shown_end: (0, 0),
+        }
+    }
+
+    pub fn set_prompt(&mut self, prompt: &str) {
+        self.prompt = prompt.to_string();
+    }
+
+    /**
+      Sets the prompt drawn at the start of each line after the first. By default it is as many
+      spaces as the prompt is wide, so that the lines line up.
+      */
+    pub fn set_continuation_prompt(&mut self, prompt: &str) {
+        self.continuation = Some(prompt.to_string());
+    }
+
+    pub fn set_validator<V: Validator + 'static>(&mut self, validator: V) {
+        self.validator = Some(Box::new(validator));
+    }
+
+    /**
+      Sets a row of text to show beneath the text being edited, such as an error message, or
+      clears it. It is cut short to fit on one row, and shown the next time the editor draws.
+      */
+    pub fn set_status(&mut self, status: Option<&str>) {
+        self.status = status.map(str::to_string);
+    }
+
+    pub fn buffer(&self) -> &LineBuffer {
+        &self.buffer
+    }
+
+    /**
+      Gives access to the text for changing it directly, after which [refresh](LineEditor::refresh)
+      shows the changes. The next kill isn't joined to any that came before.
+      */
+    pub fn buffer_mut(&mut self) -> &mut LineBuffer {
+        self.last = LastEdit::Other;
+        &mut self.buffer
+    }
+
+    pub fn kill_ring(&mut self) -> &mut KillRing {
+        &mut self.kill_ring
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    pub fn history_mut(&mut self) -> &mut History {
+        &mut self.history
+    }
+
+    pub fn set_completer<C: Completer + 'static>(&mut self, completer: C) {
+        self.completer = Some(Box::new(completer));
+    }
+
+    /**
+      Sets the width of the terminal in columns, which the text wraps at. By default it is asked
+      for whenever it is needed.
+      */
+    pub fn set_width(&mut self, width: Option<usize>) {
+        self.width = width;
+    }
+
+    /// Replaces the history, for instance with one opened from a file.
+    pub fn set_history(&mut self, history: History) {
+        self.history = history;
+        self.history_pos = None;
+        self.saved = None;
+    }
+
+    pub fn output(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    /**
+      Reads a line from `input`, returning `None` at the end of the input or when Ctrl-D is
+      pressed on an empty line. Ctrl-C gives an error of kind [Interrupted](io::ErrorKind::Interrupted).
+      */
+    pub fn read_line<I: Iterator<Item = ExtendedChar>>(&mut self, input: I) -> io::Result<Option<String>> {
+        self.start()?;
+        for key in input {
+            match self.handle(key)? {
+                Edit::Continue => {},
+                Edit::Accept(line) => { return Ok(Some(line)); },
+                Edit::Cancel => { return Err(io::ErrorKind::Interrupted.into()); },
+                Edit::EndOfFile => { return Ok(None); },
+            }
+        }
+        self.finish()?;
+        Ok(None)
+    }
+
+    /// Starts a new, empty line and draws the prompt.
+    pub fn start(&mut self) -> io::Result<()> {
+        self.buffer = LineBuffer::new();
+        self.last = LastEdit::Other;
+        self.history_pos = None;
+        self.saved = None;
+        self.search = None;
+        self.pasting = false;
+        self.redraw()
+    }
+
+    /// Edits the line with a key, redrawing whatever it changed.
+    pub fn handle(&mut self, key: ExtendedChar) -> io::Result<Edit> {
+        if self.pasting {
+            return self.paste(key).map(|_| Edit::Continue);
+        }
+        if self.search.is_some() && !self.search_key(key)? {
+            return Ok(Edit::Continue);
+        }
+        let mut last = LastEdit::Other;
+        let buffer = &mut self.buffer;
+        match key {
+            PasteStart => {
+                self.pasting = true;
+                self.paste_cr = false;
+            },
+            AltByte(b'\r') => { buffer.insert('\n'); },
+            Byte(b'\r') | Byte(b'\n') if self.validator.as_ref().is_some_and(|v| !v.is_complete(&buffer.text())) => {
+                buffer.insert('\n');
+            },
+            Byte(b'\r') | Byte(b'\n') => {
+                self.finish()?;
+                let line = self.buffer.text();
+                // Failing to write the history file shouldn't lose the line.
+                let _ = self.history.add(&line);
+                self.history_pos = None;
+                self.saved = None;
+                return Ok(Edit::Accept(line));
+            },
+            Byte(3) | Interrupt => {
+                self.finish()?;
+                return Ok(Edit::Cancel);
+            },
+            Byte(4) | EndOfFile if buffer.is_empty() => {
+                self.finish()?;
+                return Ok(Edit::EndOfFile);
+            },
+            Byte(4) | EndOfFile | Normal(Delete) => { buffer.delete(); },
+            Byte(8) | Byte(0x7f) | Normal(Backspace) => { buffer.backspace(); },
+            Byte(2) | Normal(LeftArrow) => { buffer.move_left(); },
+            Byte(6) | Normal(RightArrow) => { buffer.move_right(); },
+            Byte(1) | Normal(Home) => { buffer.set_cursor(buffer.line_start()); },
+            Byte(5) | Normal(End) => { buffer.set_cursor(buffer.line_end()); },
+            AltByte(b'b') | Ctrl(LeftArrow) | Alt(LeftArrow) => { buffer.set_cursor(buffer.word_start()); },
+            AltByte(b'f') | Ctrl(RightArrow) | Alt(RightArrow) => { buffer.set_cursor(buffer.word_end()); },
+            Byte(11) => {
+                // At the end of a line, the newline is killed instead.
+                let end = match buffer.line_end() {
+                    end if end == buffer.cursor() && end < buffer.len() => end + 1,
+                    end => end,
+                };
+                last = self.kill(end);
+            },
+            Byte(21) | KillLine => {
+                let start = buffer.line_start();
+                last = self.kill(start);
+            },
+            Byte(23) | DeleteWord | AltByte(0x7f) | AltByte(8) => {
+                let start = buffer.word_start();
+                last = self.kill(start);
+            },
+            AltByte(b'd') | Ctrl(Delete) => {
+                let end = buffer.word_end();
+                last = self.kill(end);
+            },
+            Byte(25) => {
+                if let Some(text) = self.kill_ring.yank() {
+                    last = yank(buffer, text);
+                }
+            },
+            AltByte(b'y') => {
+                if let LastEdit::Yank { start, end } = self.last {
+                    if let Some(text) = self.kill_ring.rotate() {
+                        buffer.remove(start, end);
+                        last = yank(buffer, text);
+                    }
+                }
+            },
+            Byte(20) => { buffer.transpose(); },
+            Byte(b'\t') | Normal(Tab) => {
+                if let Some(candidates) = self.complete()? {
+                    return self.list(&candidates).map(|_| Edit::Continue);
+                }
+            },
+            Shift(Tab) => { last = self.cycle()?; },
+            Byte(16) | Normal(UpArrow) => {
+                if !buffer.move_up() {
+                    self.history_step(true);
+                }
+            },
+            Byte(14) | Normal(DownArrow) => {
+                if !buffer.move_down() {
+                    self.history_step(false);
+                }
+            },
+            Shift(UpArrow) => { self.history_prefix(true); },
+            Shift(DownArrow) => { self.history_prefix(false); },
+            Byte(18) | Byte(19) => {
+                self.search = Some(Search {
+                    query: String::new(),
+                    backward: key == Byte(18),
+                    start: self.history_pos.unwrap_or(self.history.len()),
+                    found: None,
+                    failed: false,
+                    original: self.buffer.clone(),
+                    original_pos: self.history_pos,
+                });
+                return self.redraw().map(|_| Edit::Continue);
+            },
+            Byte(12) => {
+                self.out.write_all(b"\x1b[H\x1b[2J")?;
+                self.forget_shown();
+                return self.redraw().map(|_| Edit::Continue);
+            },
+            Resumed => { return self.redraw().map(|_| Edit::Continue); },
+            _ => {
+                if let Some(c) = printable(key) {
+                    buffer.insert(c);
+                }
+            },
+        }
+        self.last = last;
+        self.refresh()?;
+        Ok(Edit::Continue)
+    }
+
+    /**
+      Handles a key during a bracketed paste. Newlines, whether the terminal sent them as CR, LF or
+      both, and tabs are inserted along with the printable characters, and the line is redrawn
+      once the paste ends.
+      */
+    fn paste(&mut self, key: ExtendedChar) -> io::Result<()> {
+        let cr = self.paste_cr;
+        self.paste_cr = key == Byte(b'\r');
+        match key {
+            PasteEnd => {
+                self.pasting = false;
+                self.last = LastEdit::Other;
+                return self.refresh();
+            },
+            Byte(b'\r') => { self.buffer.insert('\n'); },
+            Byte(b'\n') if !cr => { self.buffer.insert('\n'); },
+            Byte(b'\t') => { self.buffer.insert('\t'); },
+            // An escape in the pasted text followed by a character.
+            AltByte(b) => {
+                if let Some(c) = printable(Byte(b)) {
+                    self.buffer.insert(c);
+                }
+            },
+            _ => {
+                if let Some(c) = printable(key) {
+                    self.buffer.insert(c);
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Kills the text between the cursor and `to`, joining it to the last kill if that came just before.
+    fn kill(&mut self, to: usize) -> LastEdit {
+        let cursor = self.buffer.cursor();
+        let (start, end) = if to < cursor { (to, cursor) } else { (cursor, to) };
+        if start == end { return self.last; }
+        let text = self.buffer.remove(start, end);
+        if self.last == LastEdit::Kill {
+            self.kill_ring.extend(&text, to < cursor);
+        }
+        else {
+            self.kill_ring.push(text);
+        }
+        LastEdit::Kill
+    }
+
+    /// Completes the text before the cursor, returning the candidates to list if there was nothing to add.
+    fn complete(&mut self) -> io::Result<Option<Vec<String>>> {
+        let completer = match &self.completer {
+            Some(completer) => completer,
+            None => return Ok(None),
+        };
+        let cursor = self.buffer.cursor();
+        let (start, candidates) = completer.complete(&self.buffer.text(), cursor);
+        let start = start.min(cursor);
+        let replacement = match candidates.as_slice() {
+            [] => {
+                self.write("\x07")?;
+                return Ok(None);
+            },
+            [only] => only.clone(),
+            _ => common_prefix(&candidates),
+        };
+        if candidates.len() > 1 && replacement.chars().count() <= cursor - start {
+            return Ok(Some(candidates));
+        }
+        self.buffer.remove(start, cursor);
+        self.buffer.insert_str(&replacement);
+        Ok(None)
+    }
+
+    /// Replaces the text before the cursor with the next completion candidate.
+    fn cycle(&mut self) -> io::Result<LastEdit> {
+        let (start, index) = match self.last {
+            LastEdit::Cycle { start, end, index } => {
+                self.buffer.remove(start, end);
+                (start, index + 1)
+            },
+            _ => {
+                let completer = match &self.completer {
+                    Some(completer) => completer,
+                    None => return Ok(LastEdit::Other),
+                };
+                let cursor = self.buffer.cursor();
+                let (start, mut candidates) = completer.complete(&self.buffer.text(), cursor);
+                let start = start.min(cursor);
+                if candidates.is_empty() {
+                    self.write("\x07")?;
+                    return Ok(LastEdit::Other);
+                }
+                // The original text comes around again after the last candidate.
+                candidates.push(self.buffer.remove(start, cursor));
+                self.completions = candidates;
+                (start, 0)
+            },
+        };
+        let index = index % self.completions.len();
+        self.buffer.set_cursor(start);
+        self.buffer.insert_str(&self.completions[index]);
+        Ok(LastEdit::Cycle { start, end: self.buffer.cursor(), index })
+    }
+
+    /// Lists completion candidates in columns below the line, then draws the line again under them.
+    fn list(&mut self, candidates: &[String]) -> io::Result<()> {
+        let mut out = String::new();
+        self.leave(&mut out);
+        for row in format_columns(candidates, self.width()) {
+            out.push_str(&row);
+            out.push_str("\r\n");
+        }
+        self.write(&out)?;
+        self.redraw()
+    }
+
+    /// Moves to the next older or newer history entry.
+    fn history_step(&mut self, backward: bool) {
+        match (backward, self.history_pos) {
+            (true, Some(0)) => {},
+            (true, Some(pos)) => { self.show_history(pos - 1); },
+            (true, None) if !self.history.is_empty() => { self.show_history(self.history.len() - 1); },
+            (false, Some(pos)) => { self.show_history(pos + 1); },
+            _ => {},
+        }
+    }
+
+    /// Moves to the next entry that starts with the text before the cursor, leaving the cursor where it is.
+    fn history_prefix(&mut self, backward: bool) {
+        let cursor = self.buffer.cursor();
+        let prefix: String = self.buffer.chars()[..cursor].iter().collect();
+        let start = match self.history_pos {
+            Some(pos) if !backward => pos + 1,
+            Some(pos) => pos,
+            None => self.history.len(),
+        };
+        if let Some(index) = self.history.search_prefix(&prefix, start, backward) {
+            self.show_history(index);
+            self.buffer.set_cursor(cursor);
+        }
+    }
+
+    /**
+      Shows the history entry at `index`, or the line that was being edited before moving into the
+      history if `index` is past the newest entry.
+      */
+    fn show_history(&mut self, index: usize) {
+        match self.history.get(index) {
+            Some(entry) => {
+                if self.history_pos.is_none() {
+                    self.saved = Some(self.buffer.clone());
+                }
+                self.buffer.set_text(entry);
+                self.history_pos = Some(index);
+            },
+            None => {
+                if let Some(saved) = self.saved.take() {
+                    self.buffer = saved;
+                }
+                self.history_pos = None;
+            },
+        }
+    }
+
+    /**
+      Handles a key during an incremental search. Returns true if the key ended the search and
+      should then be handled as usual.
+      */
+    fn search_key(&mut self, key: ExtendedChar) -> io::Result<bool> {
+        let search = match &mut self.search {
+            Some(search) => search,
+            None => return Ok(true),
+        };
+        let (backward, found, start) = (search.backward, search.found, search.start);
+        match key {
+            Byte(18) | Byte(19) => {
+                // Searching again looks past the entry found, which may mean turning around.
+                search.backward = key == Byte(18);
+                let from = match found {
+                    Some(found) if search.backward => found,
+                    Some(found) => found + 1,
+                    None => start,
+                };
+                self.search_from(from);
+            },
+            Byte(8) | Byte(0x7f) | Normal(Backspace) => {
+                search.query.pop();
+                if search.query.is_empty() {
+                    // With nothing left to look for, the line goes back to how it was.
+                    search.found = None;
+                    search.failed = false;
+                    self.restore_original();
+                }
+                else {
+                    self.search_from(start);
+                }
+            },
+            Byte(7) => {
+                self.restore_original();
+                self.search = None;
+            },
+            Byte(0x1b) => { self.search = None; },
+            _ => match printable(key) {
+                Some(c) => {
+                    // The entry already found may still match the longer query.
+                    search.query.push(c);
+                    let from = match found {
+                        Some(found) if backward => found + 1,
+                        Some(found) => found,
+                        None => start,
+                    };
+                    self.search_from(from);
+                },
+                None => {
+                    self.search = None;
+                    self.redraw()?;
+                    return Ok(true);
+                },
+            },
+        }
+        self.redraw()?;
+        Ok(false)
+    }
+
+    /// Puts back the line from before the search.
+    fn restore_original(&mut self) {
+        if let Some(search) = &self.search {
+            self.buffer = search.original.clone();
+            self.history_pos = search.original_pos;
+            if self.history_pos.is_none() {
+                self.saved = None;
+            }
+        }
+    }
+
+    /// Looks for the search query from history position `from`, showing the entry found if there is one.
+    fn search_from(&mut self, from: usize) {
+        let search = match &mut self.search {
+            Some(search) => search,
+            None => return,
+        };
+        search.failed = false;
+        if search.query.is_empty() { return; }
+        match self.history.search(&search.query, from, search.backward) {
+            Some((index, at)) => {
+                search.found = Some(index);
+                if self.history_pos.is_none() {
+                    self.saved = Some(self.buffer.clone());
+                }
+                self.buffer.set_text(self.history.get(index).unwrap_or_default());
+                self.buffer.set_cursor(at);
+                self.history_pos = Some(index);
+            },
+            None => { search.failed = true; },
+        }
+    }
+
+    /// Returns the prompt to draw, which shows the query during a search.
+    fn current_prompt(&self) -> String {
+        match &self.search {
+            Some(search) => format!(
+                "({}{}-i-search)`{}': ",
+                if search.failed { "failed " } else { "" },
+                if search.backward { "reverse" } else { "forward" },
+                search.query,
+            ),
+            None => self.prompt.clone(),
+        }
+    }
+
+    /// Draws the prompt and the whole text again, starting from the row the prompt is on.
+    pub fn redraw(&mut self) -> io::Result<()> {
+        let prompt = self.current_prompt();
+        let (positions, continuation, width) = self.layout(&prompt);
+        let mut out = String::new();
+        move_to(&mut out, self.shown_cursor, (0, self.shown_cursor.1));
+        out.push('\r');
+        write_prompt(&mut out, &prompt, width);
+        let end = draw(&mut out, self.buffer.chars(), &positions, 0, positions[0], &continuation, width);
+        out.push_str(if self.shown_end.0 > end.0 || self.shown_status { "\x1b[J" } else { "\x1b[K" });
+        let mut at = end;
+        if let Some(status) = &self.status {
+            let status = truncate(status, width - 1);
+            out.push_str("\r\n");
+            out.push_str(&status);
+            at = (end.0 + 1, status.width());
+        }
+        self.shown_status = self.status.is_some();
+        self.show(&mut out, &positions, end, at);
+        self.write(&out)
+    }
+
+    /// Brings the text on the screen up to date, rewriting only from the first character that changed.
+    pub fn refresh(&mut self) -> io::Result<()> {
+        if self.shown_status || self.status.is_some() {
+            // The status row moves with the end of the text, so it is simplest to draw everything.
+            return self.redraw();
+        }
+        let (positions, continuation, width) = self.layout(&self.current_prompt());
+        let text = self.buffer.chars();
+        let same = self.shown.iter().zip(text).take_while(|(a, b)| a == b).count();
+        let mut out = String::new();
+        if same == self.shown.len() && same == text.len() {
+            // Only the cursor moved.
+            let cursor = positions[self.buffer.cursor()];
+            move_to(&mut out, self.shown_cursor, cursor);
+            self.shown_cursor = cursor;
+            return self.write(&out);
+        }
+        let start = after(text, &positions, same, width);
+        move_to(&mut out, self.shown_cursor, start);
+        let end = draw(&mut out, text, &positions, same, start, &continuation, width);
+        if self.shown_end.0 > end.0 {
+            out.push_str("\x1b[J");
+        }
+        else if self.shown_end > end {
+            out.push_str("\x1b[K");
+        }
+        self.show(&mut out, &positions, end, end);
+        self.write(&out)
+    }
+
+    /**
+      Moves the cursor into place from `at` after the text has been drawn up to `end`, and
+      remembers what is on the screen.
+      */
+    fn show(&mut self, out: &mut String, positions: &[Position], end: Position, at: Position) {
+        let cursor = positions[self.buffer.cursor()];
+        move_to(out, at, cursor);
+        self.shown = self.buffer.chars().to_vec();
+        self.shown_cursor = cursor;
+        self.shown_end = end;
+    }
+
+    /// Forgets what was on the screen, once the cursor is at the start of a clear row.
+    fn forget_shown(&mut self) {
+        self.shown.clear();
+        self.shown_cursor = (0, 0);
+        self.shown_end = (0, 0);
+    }
+
+    /// Works out where each character goes on the screen, returning that with the continuation prompt and the width.
+    fn layout(&self, prompt: &str) -> (Vec<Position>, String, usize) {
+        let width = self.width().max(1);
+        let continuation = match &self.continuation {
+            Some(continuation) => continuation.clone(),
+            None => " ".repeat(prompt.width()),
+        };
+        let positions = layout(self.buffer.chars(), prompt.width(), continuation.width(), width);
+        (positions, continuation, width)
+    }
+
+    fn width(&self) -> usize {
+        self.width.unwrap_or_else(terminal_width)
+    }
+
+    /// Leaves the cursor after the end of the text and moves on to the next row.
+    pub fn finish(&mut self) -> io::Result<()> {
+        let mut out = String::new();
+        self.leave(&mut out);
+        self.write(&out)
+    }
+
+    /// Moves the cursor to the start of the row after the text, clearing the status row, and forgets what was shown.
+    fn leave(&mut self, out: &mut String) {
+        move_to(out, self.shown_cursor, self.shown_end);
+        if self.shown_status {
+            out.push_str("\x1b[J");
+            self.shown_status = false;
+        }
+        out.push_str("\r\n");
+        self.forget_shown();
+    }
+
+    fn write(&mut self, s: &str) -> io::Result<()> {
+        self.out.write_all(s.as_bytes())?;
+        self.out.flush()
+    }
+}
+
+/// Returns the character that a key inserts, if it is a printable one.
+pub(crate) fn printable(key: ExtendedChar) -> Option<char> {
+    match key {
+        Byte(b) if (0x20..0x7f).contains(&b) || b >= 0xa0 => Some(char::from(b)),
+        Char(c) => Some(c),
+        _ => None,
+    }
+}
+
+/**
+  Works out where each character of `text` starts on the screen when it follows a prompt `prompt`
+  columns wide, with the lines after a newline following a continuation prompt `continuation`
+  columns wide, and rows wrapping at `width` columns. The extra position at the end is where the
+  cursor is left after the last character.
+  */
+fn layout(text: &[char], prompt: usize, continuation: usize, width: usize) -> Vec<Position> {
+    let mut positions = Vec::with_capacity(text.len() + 1);
+    let (mut row, mut col) = (prompt / width, prompt % width);
+    for &c in text {
+        if c == '\n' {
+            positions.push((row, col));
+            row += 1 + continuation / width;
+            col = continuation % width;
+            continue;
+        }
+        let mut w = char_width(c, col).min(width);
+        if col + w > width {
+            row += 1;
+            col = 0;
+            w = char_width(c, col).min(width);
+        }
+        positions.push((row, col));
+        col += w;
+        if col == width {
+            row += 1;
+            col = 0;
+        }
+    }
+    positions.push((row, col));
+    positions
+}
+
+/**
+  Returns where the cursor is left once `text` has been drawn up to `index`, which is where the
+  character there goes unless it is too wide to fit on the end of the row.
+  */
+fn after(text: &[char], positions: &[Position], index: usize, width: usize) -> Position {
+    match index.checked_sub(1) {
+        Some(i) if text[i] != '\n' => {
+            let (row, col) = positions[i];
+            let col = col + char_width(text[i], col).min(width);
+            if col == width { (row + 1, 0) } else { (row, col) }
+        },
+        _ => positions[index],
+    }
+}
+
+/**
+  Adds the characters of `text` from `from` on to `out`, with the cursor starting `at` the end of
+  the ones before, and returns where the cursor ends up. The rows are always ended with an explicit
+  line break, so that the cursor is where the layout expects whether or not the terminal wraps by
+  itself.
+  */
+fn draw(out: &mut String, text: &[char], positions: &[Position], from: usize, mut at: Position, continuation: &str, width: usize) -> Position {
+    for (i, &c) in text.iter().enumerate().skip(from) {
+        if c == '\n' {
+            out.push_str("\x1b[K\r\n");
+            write_prompt(out, continuation, width);
+            at = positions[i + 1];
+            continue;
+        }
+        if positions[i] != at {
+            // The character didn't fit on the end of the row.
+            out.push_str("\x1b[K\r\n");
+        }
+        let (row, col) = positions[i];
+        let w = char_width(c, col).min(width);
+        if c == '\t' {
+            out.extend(std::iter::repeat(' ').take(w));
+        }
+        else {
+            out.push(c);
+        }
+        at = (row, col + w);
+        if at.1 == width {
+            out.push_str("\r\n");
+            at = (row + 1, 0);
+        }
+    }
+    at
+}
+
+/// Adds a prompt to `out`, starting a new row if it ends exactly at the edge of the terminal.
+fn write_prompt(out: &mut String, prompt: &str, width: usize) {
+    out.push_str(prompt);
+    let w = prompt.width();
+    if w > 0 && w % width == 0 {
+        out.push_str("\r\n");
+    }
+}
+
+/// Adds the escape sequences that move the cursor from one position on the screen to another.
+fn move_to(out: &mut String, from: Position, to: Position) {
+    if to.0 < from.0 {
+        let _ = write!(out, "\x1b[{}A", from.0 - to.0);
+    }
+    else if to.0 > from.0 {
+        let _ = write!(out, "\x1b[{}B", to.0 - from.0);
+    }
+    if to.1 < from.1 {
+        let _ = write!(out, "\x1b[{}D", from.1 - to.1);
+    }
+    else if to.1 > from.1 {
+        let _ = write!(out, "\x1b[{}C", to.1 - from.1);
+    }
+}
+
+/// Returns as much of the start of `text` as fits in `width` columns.
+pub(crate) fn truncate(text: &str, width: usize) -> String {
+    let mut used = 0;
+    text.chars().take_while(|c| {
+        used += c.width().unwrap_or(0);
+        used <= width
+    }).collect()
+}
+
+/// Returns the width of the terminal on stdout, or 80 columns if it can't be found.
+pub(crate) fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    match unsafe { libc::ioctl(1, libc::TIOCGWINSZ, &mut size) } {
+        0 if size.ws_col > 0 => size.ws_col as usize,
+        _ => 80,
+    }
+}
+
+/// Inserts yanked text at the cursor and remembers where it went, for rotating it with Alt-Y.
+fn yank(buffer: &mut LineBuffer, text: &str) -> LastEdit {
+    let start = buffer.cursor();
+    buffer.insert_str(text);
+    LastEdit::Yank { start, end: buffer.cursor() }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::line::*;
+
+    #[test]
+    fn test_line_buffer() {
+        let mut buffer = LineBuffer::new();
+        buffer.insert_str("hello, big world");
+        assert_eq!(buffer.cursor(), 16);
+        assert_eq!(buffer.word_start(), 11);
+        buffer.set_cursor(buffer.word_start());
+        buffer.move_left();
+        assert_eq!(buffer.word_start(), 7);
+        assert_eq!(buffer.word_end(), 16);
+        buffer.set_cursor(5);
+        assert_eq!(buffer.word_end(), 10);
+        assert_eq!(buffer.remove(3, 7), "lo, ");
+        assert_eq!(buffer.text(), "helbig world");
+        assert_eq!(buffer.cursor(), 3);
+        assert!(buffer.backspace());
+        assert!(buffer.delete());
+        assert_eq!((buffer.text().as_str(), buffer.cursor()), ("heig world", 2));
+        assert!(buffer.transpose());
+        assert_eq!((buffer.text().as_str(), buffer.cursor()), ("hieg world", 3));
+        buffer.move_end();
+        assert!(buffer.transpose());
+        assert_eq!(buffer.text(), "hieg wordl");
+        buffer.move_home();
+        assert!(!buffer.transpose());
+        assert!(!buffer.backspace());
+    }
+
+    #[test]
+    fn test_kill_ring() {
+        let mut ring = KillRing::new(2);
+        assert_eq!(ring.yank(), None);
+        ring.push("one".to_string());
+        ring.extend(" two", false);
+        ring.extend("zero ", true);
+        assert_eq!(ring.yank(), Some("zero one two"));
+        ring.push("three".to_string());
+        ring.push("four".to_string());
+        assert_eq!(ring.len(), 2);
+        assert_eq!(ring.yank(), Some("four"));
+        assert_eq!(ring.rotate(), Some("three"));
+        assert_eq!(ring.rotate(), Some("four"));
+    }
+
+    fn type_keys(editor: &mut LineEditor<Vec<u8>>, keys: &[ExtendedChar]) -> Vec<Edit> {
+        keys.iter().map(|k| editor.handle(*k).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_line_editor() {
+        let mut editor = LineEditor::with_output(Vec::new(), "> ");
+        editor.start().unwrap();
+        type_keys(&mut editor, &[Byte(b'a'), Byte(b'b'), Char('\u{3b1}')]);
+        assert_eq!(String::from_utf8_lossy(editor.output()), "\r> \x1b[Kab\u{3b1}");
+        // Moving the cursor only moves the cursor, and edits only redraw from where they start.
+        editor.output().clear();
+        type_keys(&mut editor, &[Normal(Home), Normal(RightArrow), Byte(b'x')]);
+        assert_eq!(String::from_utf8_lossy(editor.output()), "\x1b[3D\x1b[1Cxb\u{3b1}\x1b[2D");
+        editor.output().clear();
+        type_keys(&mut editor, &[Byte(11)]);
+        assert_eq!(String::from_utf8_lossy(editor.output()), "\x1b[K");
+        assert_eq!(editor.buffer().text(), "ax");
+        // Consecutive kills are yanked back together.
+        type_keys(&mut editor, &[Byte(b' '), Byte(b'y'), Byte(b'z'), Byte(23), Byte(23), Byte(b'q'), Byte(25)]);
+        assert_eq!(editor.buffer().text(), "qax yz");
+        type_keys(&mut editor, &[AltByte(b'y')]);
+        assert_eq!(editor.buffer().text(), "qb\u{3b1}");
+        type_keys(&mut editor, &[Ctrl(LeftArrow), Normal(RightArrow), Byte(20)]);
+        assert_eq!((editor.buffer().text().as_str(), editor.buffer().cursor()), ("bq\u{3b1}", 2));
+        editor.output().clear();
+        assert_eq!(type_keys(&mut editor, &[Byte(b'\r')]), vec![Edit::Accept("bq\u{3b1}".to_string())]);
+        assert_eq!(String::from_utf8_lossy(editor.output()), "\x1b[1C\r\n");
+    }
+
+    #[test]
+    fn test_read_line() {
+        let mut editor = LineEditor::with_output(Vec::new(), "");
+        let mut input = vec![Byte(b'h'), Byte(b'i'), Byte(b'\r'), Byte(b'x'), Byte(4), Byte(21), Byte(4), Byte(b'y')].into_iter();
+        assert_eq!(editor.read_line(&mut input).unwrap(), Some("hi".to_string()));
+        assert_eq!(editor.read_line(&mut input).unwrap(), None);
+        assert_eq!(editor.read_line(&mut input).unwrap(), None);
+        let mut input = vec![Byte(b'h'), Interrupt].into_iter();
+        assert_eq!(editor.read_line(&mut input).unwrap_err().kind(), io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn test_line_history() {
+        let mut editor = LineEditor::with_output(Vec::new(), "");
+        for line in &["make", "ls -l", "make test"] {
+            editor.history_mut().add(line).unwrap();
+        }
+        editor.start().unwrap();
+        let line = |editor: &mut LineEditor<Vec<u8>>, keys: &[ExtendedChar]| {
+            type_keys(editor, keys);
+            (editor.buffer().text(), editor.buffer().cursor())
+        };
+        assert_eq!(line(&mut editor, &[Byte(b'm'), Byte(b'a'), Normal(UpArrow)]), ("make test".to_string(), 9));
+        assert_eq!(line(&mut editor, &[Normal(UpArrow), Normal(UpArrow), Byte(16)]), ("make".to_string(), 4));
+        assert_eq!(line(&mut editor, &[Normal(DownArrow), Normal(DownArrow)]), ("make test".to_string(), 9));
+        assert_eq!(line(&mut editor, &[Byte(14), Normal(DownArrow)]), ("ma".to_string(), 2));
+        // Prefix searches keep the cursor after the prefix.
+        assert_eq!(line(&mut editor, &[Shift(UpArrow)]), ("make test".to_string(), 2));
+        assert_eq!(line(&mut editor, &[Shift(UpArrow), Shift(UpArrow)]), ("make".to_string(), 2));
+        assert_eq!(line(&mut editor, &[Shift(DownArrow)]), ("make test".to_string(), 2));
+        // Incremental search shows each match with the cursor on it.
+        assert_eq!(line(&mut editor, &[Byte(18), Byte(b'l')]), ("ls -l".to_string(), 0));
+        assert_eq!(line(&mut editor, &[Byte(b's')]), ("ls -l".to_string(), 0));
+        editor.output().clear();
+        assert_eq!(line(&mut editor, &[Byte(18)]), ("ls -l".to_string(), 0));
+        assert_eq!(String::from_utf8_lossy(editor.output()), "\r(failed reverse-i-search)`ls': ls -l\x1b[K\x1b[5D");
+        assert_eq!(line(&mut editor, &[Byte(0x7f), Byte(7)]), ("make test".to_string(), 2));
+        assert_eq!(line(&mut editor, &[Byte(18), Byte(b'k')]), ("make".to_string(), 2));
+        // Rubbing out the whole query brings back the line from before the search.
+        assert_eq!(line(&mut editor, &[Byte(0x7f)]), ("make test".to_string(), 2));
+        assert_eq!(line(&mut editor, &[Byte(b'k')]), ("make".to_string(), 2));
+        // Other keys stop the search and then do what they usually do.
+        assert_eq!(line(&mut editor, &[Normal(RightArrow)]), ("make".to_string(), 3));
+        assert_eq!(type_keys(&mut editor, &[Byte(b'\r')]), vec![Edit::Accept("make".to_string())]);
+        assert_eq!(editor.history().iter().collect::<Vec<_>>(), vec!["ls -l", "make test", "make"]);
+    }
+
+    #[test]
+    fn test_line_completion() {
+        let mut editor = LineEditor::with_output(Vec::new(), "> ");
+        editor.set_completer(crate::complete::WordCompleter::new(vec!["help", "history", "hist", "quit"]));
+        editor.set_width(Some(12));
+        editor.start().unwrap();
+        type_keys(&mut editor, &[Byte(b'q'), Byte(b'\t')]);
+        assert_eq!(editor.buffer().text(), "quit");
+        type_keys(&mut editor, &[Byte(b' '), Byte(b'h'), Byte(b'i'), Normal(Tab)]);
+        assert_eq!(editor.buffer().text(), "quit hist");
+        // With nothing more in common, the candidates are listed and the line drawn again below them.
+        editor.output().clear();
+        type_keys(&mut editor, &[Byte(b'\t')]);
+        assert_eq!(editor.buffer().text(), "quit hist");
+        assert_eq!(String::from_utf8_lossy(editor.output()), "\r\nhist\r\nhistory\r\n\r> quit hist\x1b[K");
+        // Shift-Tab cycles through the candidates and back to the text that was there.
+        type_keys(&mut editor, &[Byte(0x7f), Byte(0x7f), Byte(0x7f), Shift(Tab)]);
+        assert_eq!(editor.buffer().text(), "quit help");
+        type_keys(&mut editor, &[Shift(Tab), Shift(Tab)]);
+        assert_eq!(editor.buffer().text(), "quit history");
+        type_keys(&mut editor, &[Shift(Tab)]);
+        assert_eq!(editor.buffer().text(), "quit h");
+        type_keys(&mut editor, &[Shift(Tab)]);
+        assert_eq!(editor.buffer().text(), "quit help");
+        editor.output().clear();
+        // The line now fills the row, so the cursor moves on to the next one before the bell.
+        type_keys(&mut editor, &[Byte(b'x'), Byte(b'\t')]);
+        assert_eq!(String::from_utf8_lossy(editor.output()), "x\r\n\x07");
+    }
+
+    #[test]
+    fn test_line_multiline() {
+        let mut editor = LineEditor::with_output(Vec::new(), "> ");
+        editor.set_validator(|text: &str| text.matches('(').count() <= text.matches(')').count());
+        editor.set_width(Some(20));
+        editor.start().unwrap();
+        // Enter starts a new line until the brackets are closed.
+        type_keys(&mut editor, &[Byte(b'('), Byte(b'a'), Byte(b'b'), Byte(b'\r')]);
+        assert_eq!(editor.buffer().text(), "(ab\n");
+        editor.output().clear();
+        type_keys(&mut editor, &[Byte(b'c')]);
+        assert_eq!(String::from_utf8_lossy(editor.output()), "c");
+        // Up and Down keep the column, and only go through the history from the first and last lines.
+        type_keys(&mut editor, &[Normal(UpArrow)]);
+        assert_eq!(editor.buffer().cursor(), 1);
+        type_keys(&mut editor, &[Normal(End), Normal(DownArrow), Byte(0x1b)]);
+        assert_eq!(editor.buffer().cursor(), 5);
+        type_keys(&mut editor, &[Byte(1), Byte(11)]);
+        assert_eq!(editor.buffer().text(), "(ab\n");
+        // Killing at the end of a line joins it to the next.
+        type_keys(&mut editor, &[Normal(UpArrow), Byte(5), Byte(11)]);
+        assert_eq!(editor.buffer().text(), "(ab");
+        type_keys(&mut editor, &[Byte(23)]);
+        assert_eq!(editor.buffer().text(), "(");
+        // A paste goes in as it is, with its line breaks, without Enter finishing the text.
+        type_keys(&mut editor, &[PasteStart, Byte(b'x'), Byte(b'\r'), Byte(b'\n'), Byte(b'\t'), Byte(b')'), Byte(3), PasteEnd]);
+        assert_eq!(editor.buffer().text(), "(x\n\t)");
+        editor.output().clear();
+        assert_eq!(type_keys(&mut editor, &[Byte(b'\r')]), vec![Edit::Accept("(x\n\t)".to_string())]);
+        assert_eq!(String::from_utf8_lossy(editor.output()), "\r\n");
+        // Wide characters take up two columns, and are moved to the next row rather than split.
+        editor.set_width(Some(6));
+        editor.start().unwrap();
+        editor.output().clear();
+        type_keys(&mut editor, &[Byte(b'a'), Byte(b'b'), Byte(b'c'), Char('\u{4e2d}')]);
+        assert_eq!(String::from_utf8_lossy(editor.output()), "abc\x1b[K\r\n\u{4e2d}");
+        editor.output().clear();
+        type_keys(&mut editor, &[Normal(Home)]);
+        assert_eq!(String::from_utf8_lossy(editor.output()), "\x1b[1A");
+    }
+
+    #[test]
+    fn test_layout() {
+        let text: Vec<char> = "a\u{4e2d}b\ncd\te".chars().collect();
+        assert_eq!(layout(&text, 2, 2, 80), vec![(0, 2), (0, 3), (0, 5), (0, 6), (1, 2), (1, 3), (1, 4), (1, 8), (1, 9)]);
+        assert_eq!(layout(&text, 2, 2, 4), vec![(0, 2), (1, 0), (1, 2), (1, 3), (2, 2), (2, 3), (3, 0), (4, 0), (4, 1)]);
+    }
+}
@@ -19,6 +19,28 @@ pub enum ExtendedChar {
     CtrlShift(SpecialChar),
     CtrlAlt(SpecialChar),
     CtrlShiftAlt(SpecialChar),
+    /// The terminal's interrupt character (usually Ctrl-C) was typed.
+    Interrupt,
+    /// The terminal's quit character (usually Ctrl-\\) was typed.
+    Quit,
+    /// The terminal's suspend character (usually Ctrl-Z) was typed.
+    Suspend,
+    /// The terminal's line kill character (usually Ctrl-U) was typed.
+    KillLine,
+    /// The terminal's word erase character (usually Ctrl-W) was typed.
+    DeleteWord,
+    /// The terminal's end of file character (usually Ctrl-D) was typed.
+    EndOfFile,
+    /// A sequence of keys registered with a [ChordMatcher](crate::chord::ChordMatcher), by its id.
+    Chord(usize),
+    /// The session stopped the process for job control and is about to give up the terminal.
+    Suspended,
+    /// The process was continued and the terminal is back in the session's mode.
+    Resumed,
+    /// The start of text pasted while [BracketedPaste](crate::user::Protocol::BracketedPaste) is enabled.
+    PasteStart,
+    /// The end of pasted text.
+    PasteEnd,
 }
 
 impl Display for ExtendedChar {
@@ -45,6 +67,17 @@ impl Display for ExtendedChar {
             CtrlShift(e) => { write!(f, "<Ctrl-Shift-{:?}>", e) },
             CtrlAlt(e) => { write!(f, "<Ctrl-Alt-{:?}>", e) },
             CtrlShiftAlt(e) => { write!(f, "<Ctrl-Shift-Alt-{:?}>", e) },
+            Interrupt => { write!(f, "<Interrupt>") },
+            Quit => { write!(f, "<Quit>") },
+            Suspend => { write!(f, "<Suspend>") },
+            KillLine => { write!(f, "<KillLine>") },
+            DeleteWord => { write!(f, "<DeleteWord>") },
+            EndOfFile => { write!(f, "<EndOfFile>") },
+            Chord(id) => { write!(f, "<Chord-{}>", id) },
+            Suspended => { write!(f, "<Suspended>") },
+            Resumed => { write!(f, "<Resumed>") },
+            PasteStart => { write!(f, "<PasteStart>") },
+            PasteEnd => { write!(f, "<PasteEnd>") },
         }
     }
 }
@@ -237,6 +270,10 @@ pub static CHAR_CODES: phf::Map<&'static str, ExtendedChar> = phf_map! {
     "\x1b[1~" => Normal(Home),
     "\x1b[2" => Partial,
     "\x1b[20" => Partial,
+    "\x1b[200" => Partial,
+    "\x1b[200~" => PasteStart,
+    "\x1b[201" => Partial,
+    "\x1b[201~" => PasteEnd,
     "\x1b[20;" => Partial,
     "\x1b[20;2" => Partial,
     "\x1b[20;2~" => Shift(F9),
@@ -389,7 +426,7 @@ pub static CHAR_CODES: phf::Map<&'static str, ExtendedChar> = phf_map! {
     "\x1b[H" => Normal(Home),
 };
 
-static U8_TO_PRINT: [&'static str; 256] = [
+static U8_TO_PRINT: [&str; 256] = [
     "NUL", "Ctrl-A", "Ctrl-B", "Ctrl-C", "Ctrl-D", "Ctrl-E", "Ctrl-F", "Ctrl-G",
     "Ctrl-H", "Tab", "Ctrl-J", "Ctrl-K", "Ctrl-L", "Ctrl-M", "Ctrl-N", "Ctrl-O",
     "Ctrl-P", "Ctrl-Q", "Ctrl-R", "Ctrl-S", "Ctrl-T", "Ctrl-U", "Ctrl-V", "Ctrl-W",
@@ -474,12 +511,70 @@ pub fn encode_extended_string(v: &[u8]) -> Vec<ExtendedChar> {
     ext_str
 }
 
+/**
+  An incremental decoder for terminal input. Bytes are pushed in one at a time, and any bytes that
+  could still be the start of a longer escape sequence are held until more input arrives or the
+  decoder is flushed.
+  */
+#[derive(Clone, Debug, Default)]
+pub struct Decoder {
+    buffer: Vec<u8>,
+    matched: bool,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a byte of input and returns any characters that it completes.
+    pub fn push(&mut self, b: u8) -> Vec<ExtendedChar> {
+        self.buffer.push(b);
+        self.matched = false;
+        // Invalid UTF-8 is treated as partial input.
+        if let Ok(parse_buffer) = std::str::from_utf8(&self.buffer) {
+            match ExtendedChar::from_str(parse_buffer) {
+                Err(_) => { return self.flush(); },
+                Ok(Partial) => {},
+                Ok(scc) => {
+                    self.buffer.clear();
+                    self.matched = true;
+                    return vec![scc];
+                },
+            }
+        }
+        Vec::new()
+    }
+
+    /// Stops waiting for the rest of a sequence and returns the held input as plain characters.
+    pub fn flush(&mut self) -> Vec<ExtendedChar> {
+        let ext_str = encode_extended_string(&self.buffer);
+        self.buffer.clear();
+        ext_str
+    }
+
+    /// Returns true if the last byte pushed completed a known escape sequence.
+    pub fn matched(&self) -> bool {
+        self.matched
+    }
+
+    /// Returns true if there is input held waiting for the rest of a sequence.
+    pub fn is_pending(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
+    /// Returns the input held waiting for the rest of a sequence.
+    pub fn held(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
 impl FromStr for ExtendedChar {
     type Err = ParseMapError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match CHAR_CODES.get(s) {
-            Some(t) => Ok(t.clone()),
+            Some(t) => Ok(*t),
             None => Err(ParseMapError{kind: MapErrorKind::NotFound}),
         }
     }
@@ -510,3 +605,404 @@ impl Display for ParseMapError {
 }
 
 impl Error for ParseMapError {}
+
+/**
+  Parses a single key written in a human notation, which is also what [Display] produces. A key
+  can be written in several ways:
+
+  * A character on its own: `a`, `<`, `é`. Characters up to U+FF are bytes, as the decoder reports
+    them, and `'…'` quotes a character the way `{:?}` formats it.
+  * A name in angle brackets, like `<Left>`, `<PageUp>`, `<F5>`, `<Esc>`, `<Enter>`, `<Space>`,
+    `<Tab>`, `<DEL>`, `<NUL>`, `<lt>` (for `<`) or `<0x9B>`.
+  * Modifiers in front of a key, separated by dashes: `C-x`, `M-Left`, `<C-S-Up>`,
+    `<Ctrl-Shift-F5>`, `<Alt-Ctrl-A>`. `C`/`Ctrl`, `S`/`Shift` and `M`/`A`/`Alt`/`Meta` are
+    understood, ignoring case, and the brackets can be left off when there is a modifier.
+
+  Ctrl with a character gives its control code, Alt with a byte gives an `AltByte`, and Shift with
+  a letter gives the capital. With the special keys any combination can be used.
+  */
+pub fn parse_key(s: &str) -> Result<ExtendedChar, KeyParseError> {
+    let keys = parse_token(s)?;
+    match keys.as_slice() {
+        [key] => Ok(*key),
+        [] => Err(KeyParseError{kind: KeyErrorKind::Empty}),
+        _ => Err(KeyParseError{kind: KeyErrorKind::NotOneKey}),
+    }
+}
+
+/**
+  A sequence of keys, such as `C-x C-s` or `<C-w>j`. Keys are separated by whitespace or written
+  next to each other, each in the notation described by [parse_key], and `gg` is two keys. Emacs'
+  `RET`, `SPC`, `TAB`, `ESC`, `DEL` and `NUL` can be written without brackets.
+  */
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct KeySequence(pub Vec<ExtendedChar>);
+
+impl FromStr for KeySequence {
+    type Err = KeyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut keys = Vec::new();
+        for token in s.split_ascii_whitespace() {
+            keys.extend(parse_token(token)?);
+        }
+        if keys.is_empty() {
+            return Err(KeyParseError{kind: KeyErrorKind::Empty});
+        }
+        Ok(KeySequence(keys))
+    }
+}
+
+impl Display for KeySequence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, key) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            // Spaces would be taken as separators.
+            match key {
+                Byte(b' ') => { write!(f, "<Space>")?; },
+                AltByte(b' ') => { write!(f, "<Alt-Space>")?; },
+                key => { write!(f, "{}", key)?; },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a piece of a key sequence that contains no whitespace into the keys it names.
+fn parse_token(token: &str) -> Result<Vec<ExtendedChar>, KeyParseError> {
+    if let Some(c) = parse_char_literal(token) {
+        return Ok(vec![char_key(c)]);
+    }
+    let abbreviation = match token {
+        "RET" => Some(b'\r'),
+        "SPC" => Some(b' '),
+        "TAB" => Some(b'\t'),
+        "ESC" => Some(0x1b),
+        "DEL" => Some(0x7f),
+        "NUL" => Some(0),
+        _ => None,
+    };
+    if let Some(b) = abbreviation {
+        return Ok(vec![Byte(b)]);
+    }
+    if let Some((prefix, rest)) = token.split_once('-') {
+        if !rest.is_empty() && modifier(prefix).is_some() {
+            return Ok(vec![parse_spec(token)?]);
+        }
+    }
+    let mut keys = Vec::new();
+    let mut rest = token;
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            // The key inside may itself be a '>', so try each closing bracket in turn.
+            let mut result = None;
+            for (end, _) in rest.match_indices('>').filter(|(end, _)| *end > 1) {
+                match parse_spec(&rest[1..end]) {
+                    Ok(key) => {
+                        result = Some(Ok((key, end)));
+                        break;
+                    },
+                    Err(e) => { result = result.or(Some(Err(e))); },
+                }
+            }
+            if let Some(result) = result {
+                let (key, end) = result?;
+                keys.push(key);
+                rest = &rest[end + 1..];
+                continue;
+            }
+        }
+        keys.push(char_key(c));
+        rest = &rest[c.len_utf8()..];
+    }
+    Ok(keys)
+}
+
+#[derive(Clone, Copy, Default)]
+struct Modifiers {
+    ctrl: bool,
+    shift: bool,
+    alt: bool,
+}
+
+fn modifier(name: &str) -> Option<fn(&mut Modifiers)> {
+    match name.to_ascii_lowercase().as_str() {
+        "c" | "ctrl" | "control" => Some(|m| m.ctrl = true),
+        "s" | "shift" => Some(|m| m.shift = true),
+        "m" | "a" | "alt" | "meta" => Some(|m| m.alt = true),
+        _ => None,
+    }
+}
+
+/// Parses a single key with any modifiers in front of it, without the angle brackets.
+fn parse_spec(spec: &str) -> Result<ExtendedChar, KeyParseError> {
+    let mut modifiers = Modifiers::default();
+    let mut rest = spec;
+    // A modifier is only one if something follows its dash, so `C--` is Ctrl and '-'.
+    while let Some((prefix, name)) = rest.split_once('-') {
+        match modifier(prefix) {
+            Some(apply) if !name.is_empty() => {
+                apply(&mut modifiers);
+                rest = name;
+            },
+            _ => break,
+        }
+    }
+    let bad_modifier = KeyParseError{kind: KeyErrorKind::BadModifier};
+    let base = base_key(rest).ok_or(KeyParseError{kind: KeyErrorKind::UnknownKey})?;
+    let special = match base {
+        // The Tab key sends a byte, and only reaches the terminal as a special key with Shift.
+        Normal(Tab) if !modifiers.shift && !modifiers.ctrl => Byte(b'\t'),
+        Normal(e) => return Ok(match (modifiers.ctrl, modifiers.shift, modifiers.alt) {
+            (false, false, false) => Normal(e),
+            (false, true, false) => Shift(e),
+            (false, false, true) => Alt(e),
+            (true, false, false) => Ctrl(e),
+            (false, true, true) => ShiftAlt(e),
+            (true, true, false) => CtrlShift(e),
+            (true, false, true) => CtrlAlt(e),
+            (true, true, true) => CtrlShiftAlt(e),
+        }),
+        key => key,
+    };
+    let mut b = match special {
+        Byte(b) => b,
+        _ if !(modifiers.ctrl || modifiers.shift || modifiers.alt) => return Ok(special),
+        _ => return Err(bad_modifier),
+    };
+    if modifiers.shift {
+        if !b.is_ascii_alphabetic() { return Err(bad_modifier); }
+        b = b.to_ascii_uppercase();
+    }
+    if modifiers.ctrl {
+        b = match b.to_ascii_uppercase() {
+            b' ' => 0,
+            b'?' => 0x7f,
+            c @ b'@'..=b'_' => c & 0x1f,
+            _ => return Err(bad_modifier),
+        };
+    }
+    Ok(if modifiers.alt { AltByte(b) } else { Byte(b) })
+}
+
+/**
+  Returns the key with the given name, or for a single character the character itself. Special
+  keys come back as `Normal` for the modifiers to be applied to.
+  */
+fn base_key(name: &str) -> Option<ExtendedChar> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(char_key(c));
+    }
+    if let Some(c) = parse_char_literal(name) {
+        return Some(char_key(c));
+    }
+    let lower = name.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        return u8::from_str_radix(hex, 16).ok().filter(|_| hex.len() == 2).map(Byte);
+    }
+    if let Some(id) = lower.strip_prefix("chord-") {
+        return id.parse().ok().map(Chord);
+    }
+    if let Some(n) = lower.strip_prefix('f') {
+        let keys = [F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12];
+        if let Ok(n @ 1..=12) = n.parse::<usize>() {
+            return Some(Normal(keys[n - 1]));
+        }
+    }
+    Some(match lower.as_str() {
+        "nul" => Byte(0),
+        "esc" | "escape" => Byte(0x1b),
+        "enter" | "return" | "ret" | "cr" => Byte(b'\r'),
+        "space" | "spc" => Byte(b' '),
+        "del" => Byte(0x7f),
+        "lt" => Byte(b'<'),
+        "tab" => Normal(Tab),
+        "backspace" => Normal(Backspace),
+        "delete" => Normal(Delete),
+        "insert" => Normal(Insert),
+        "home" => Normal(Home),
+        "end" => Normal(End),
+        "pageup" | "pgup" => Normal(PageUp),
+        "pagedown" | "pgdn" => Normal(PageDown),
+        "up" | "uparrow" => Normal(UpArrow),
+        "down" | "downarrow" => Normal(DownArrow),
+        "right" | "rightarrow" => Normal(RightArrow),
+        "left" | "leftarrow" => Normal(LeftArrow),
+        "partial" => Partial,
+        "interrupt" => Interrupt,
+        "quit" => Quit,
+        "suspend" => Suspend,
+        "killline" => KillLine,
+        "deleteword" => DeleteWord,
+        "endoffile" => EndOfFile,
+        "suspended" => Suspended,
+        "resumed" => Resumed,
+        "pastestart" => PasteStart,
+        "pasteend" => PasteEnd,
+        _ => return None,
+    })
+}
+
+/// Returns the key for a character, which is a byte when the decoder would report it as one.
+fn char_key(c: char) -> ExtendedChar {
+    if '\u{ff}' >= c { Byte(c as u8) } else { Char(c) }
+}
+
+/// Parses a character quoted the way `{:?}` formats it, such as `'x'` or `'\u{200b}'`.
+fn parse_char_literal(s: &str) -> Option<char> {
+    let inner = s.strip_prefix('\'')?.strip_suffix('\'')?;
+    let mut chars = inner.chars();
+    let c = match chars.next()? {
+        '\\' => match chars.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            '0' => '\0',
+            c @ ('\\' | '\'' | '"') => c,
+            'u' => {
+                let hex = chars.as_str().strip_prefix('{')?.strip_suffix('}')?;
+                chars = "".chars();
+                char::from_u32(u32::from_str_radix(hex, 16).ok()?)?
+            },
+            _ => return None,
+        },
+        c => c,
+    };
+    if chars.next().is_some() { None } else { Some(c) }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyParseError {
+    pub(super) kind: KeyErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyErrorKind {
+    Empty,
+    UnknownKey,
+    BadModifier,
+    NotOneKey,
+}
+
+impl KeyParseError {
+    pub fn __description(&self) -> &str {
+        match self.kind {
+            KeyErrorKind::Empty => "no key given",
+            KeyErrorKind::UnknownKey => "unknown key name",
+            KeyErrorKind::BadModifier => "modifier can't be used with this key",
+            KeyErrorKind::NotOneKey => "more than one key given",
+        }
+    }
+}
+
+impl Display for KeyParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.__description().fmt(f)
+    }
+}
+
+impl Error for KeyParseError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse::*;
+
+    #[test]
+    fn test_decoder() {
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.push(b'a'), vec![Byte(b'a')]);
+        assert!(!decoder.is_pending());
+        // A complete escape sequence.
+        for b in b"\x1b[1;5" {
+            assert_eq!(decoder.push(*b), vec![]);
+            assert!(decoder.is_pending());
+        }
+        assert_eq!(decoder.push(b'A'), vec![Ctrl(UpArrow)]);
+        assert!(decoder.matched());
+        assert!(!decoder.is_pending());
+        // An escape followed by something that isn't a known sequence.
+        assert_eq!(decoder.push(0x1b), vec![]);
+        assert_eq!(decoder.push(b'x'), vec![AltByte(b'x')]);
+        assert!(!decoder.matched());
+        // A lone escape is held until flushed.
+        assert_eq!(decoder.push(0x1b), vec![]);
+        assert_eq!(decoder.flush(), vec![Byte(0x1b)]);
+        assert!(!decoder.is_pending());
+        // Multi-byte UTF-8 is held until the character is complete.
+        let mut buf = [0; 4];
+        let bytes = '\u{1F600}'.encode_utf8(&mut buf).as_bytes();
+        for b in &bytes[..3] {
+            assert_eq!(decoder.push(*b), vec![]);
+        }
+        assert_eq!(decoder.push(bytes[3]), vec![Char('\u{1F600}')]);
+        // Bracketed paste markers share a prefix with F9.
+        let decode = |decoder: &mut Decoder, bytes: &[u8]| bytes.iter().flat_map(|b| decoder.push(*b)).collect::<Vec<_>>();
+        assert_eq!(decode(&mut decoder, b"\x1b[200~a\x1b[201~\x1b[20~"), vec![PasteStart, Byte(b'a'), PasteEnd, Normal(F9)]);
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key = |s| parse_key(s).unwrap();
+        assert_eq!(key("a"), Byte(b'a'));
+        assert_eq!(key("C-x"), Byte(24));
+        assert_eq!(key("c-X"), Byte(24));
+        assert_eq!(key("M-Left"), Alt(LeftArrow));
+        assert_eq!(key("<C-S-Up>"), CtrlShift(UpArrow));
+        assert_eq!(key("<Ctrl-Shift-F5>"), CtrlShift(F5));
+        assert_eq!(key("<Alt-Ctrl-A>"), AltByte(1));
+        assert_eq!(key("M--"), AltByte(b'-'));
+        assert_eq!(key("S-a"), Byte(b'A'));
+        assert_eq!(key("<S-Tab>"), Shift(Tab));
+        assert_eq!(key("<Enter>"), Byte(b'\r'));
+        assert_eq!(key("<esc>"), Byte(0x1b));
+        assert_eq!(key("<C-Space>"), Byte(0));
+        assert_eq!(key("<0x9b>"), Byte(0x9b));
+        assert_eq!(key("'\u{1F600}'"), Char('\u{1F600}'));
+        assert_eq!(key("\u{1F600}"), Char('\u{1F600}'));
+        assert_eq!(key("<Chord-3>"), Chord(3));
+        assert_eq!(key("<Interrupt>"), Interrupt);
+        let error = |s| parse_key(s).unwrap_err().kind;
+        assert_eq!(error(""), KeyErrorKind::Empty);
+        assert_eq!(error("ab"), KeyErrorKind::NotOneKey);
+        assert_eq!(error("<Lefty>"), KeyErrorKind::UnknownKey);
+        assert_eq!(error("S-1"), KeyErrorKind::BadModifier);
+        assert_eq!(error("<M-Interrupt>"), KeyErrorKind::BadModifier);
+    }
+
+    #[test]
+    fn test_key_display_round_trip() {
+        let mut keys = vec![Interrupt, Quit, Suspend, KillLine, DeleteWord, EndOfFile, Suspended, Resumed, PasteStart, PasteEnd, Chord(7)];
+        keys.extend((0..=u8::MAX).map(Byte));
+        keys.extend((0..=u8::MAX).map(AltByte));
+        keys.extend(['\u{100}', '\u{1F600}', '\u{200b}', '\u{3000}'].iter().map(|c| Char(*c)));
+        let specials = [
+            Backspace, Delete, Insert, Home, End, PageUp, PageDown, UpArrow, DownArrow, RightArrow,
+            LeftArrow, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+        ];
+        for e in specials.iter().copied() {
+            keys.extend(&[Normal(e), Shift(e), Alt(e), Ctrl(e), ShiftAlt(e), CtrlShift(e), CtrlAlt(e), CtrlShiftAlt(e)]);
+        }
+        // Tab is written the same way as the byte the Tab key sends unless Shift or Ctrl is held.
+        keys.extend(&[Shift(Tab), Ctrl(Tab), ShiftAlt(Tab), CtrlShift(Tab), CtrlAlt(Tab), CtrlShiftAlt(Tab)]);
+        for key in &keys {
+            assert_eq!(parse_key(&key.to_string()), Ok(*key), "{}", key);
+        }
+        let sequence = KeySequence(keys);
+        assert_eq!(sequence.to_string().parse(), Ok(sequence));
+    }
+
+    #[test]
+    fn test_key_sequence() {
+        let keys = |s: &str| s.parse::<KeySequence>().unwrap().0;
+        assert_eq!(keys("C-x C-s"), vec![Byte(24), Byte(19)]);
+        assert_eq!(keys("gg"), vec![Byte(b'g'), Byte(b'g')]);
+        assert_eq!(keys("<C-w>j"), vec![Byte(23), Byte(b'j')]);
+        assert_eq!(keys("C-x RET"), vec![Byte(24), Byte(b'\r')]);
+        assert_eq!(keys("<lt>a>"), vec![Byte(b'<'), Byte(b'a'), Byte(b'>')]);
+        assert_eq!("  ".parse::<KeySequence>().unwrap_err().kind, KeyErrorKind::Empty);
+        assert_eq!(KeySequence(vec![Byte(b' '), AltByte(b' ')]).to_string(), "<Space> <Alt-Space>");
+    }
+}
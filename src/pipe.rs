@@ -0,0 +1,116 @@
+use std::{
+    io,
+    os::unix::io::RawFd,
+    time::Duration,
+};
+#[cfg(any(test, feature = "stream"))]
+use crate::user;
+
+/**
+  Waits until `fd` is readable or the timeout expires, returning true if it is readable. A timeout
+  of `None` waits forever. Being interrupted by a signal counts as a timeout.
+  */
+pub(crate) fn wait_readable(fd: RawFd, timeout: Option<Duration>) -> io::Result<bool> {
+    let mut poll_fd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    match unsafe { libc::poll(&mut poll_fd, 1, poll_timeout(timeout)) } {
+        -1 => {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(err) }
+        },
+        n => Ok(n > 0),
+    }
+}
+
+/// Converts a timeout into the millisecond value expected by `poll(2)`.
+pub(crate) fn poll_timeout(timeout: Option<Duration>) -> libc::c_int {
+    match timeout {
+        // Round up so that a sub-millisecond wait doesn't turn into a busy loop.
+        Some(t) => t.as_micros().div_ceil(1000).min(libc::c_int::MAX as u128) as libc::c_int,
+        None => -1,
+    }
+}
+
+/// A non-blocking pipe used to wake a thread that is waiting in `poll(2)`.
+pub(crate) struct SelfPipe {
+    fds: [RawFd; 2],
+}
+
+impl SelfPipe {
+    pub(crate) fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for fd in &fds {
+            unsafe {
+                libc::fcntl(*fd, libc::F_SETFL, libc::O_NONBLOCK);
+                libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+        }
+        Ok(SelfPipe { fds })
+    }
+
+    pub(crate) fn read_fd(&self) -> RawFd {
+        self.fds[0]
+    }
+
+    pub(crate) fn notify(&self) {
+        // If the pipe is full then a wakeup is already pending, so a failed write doesn't matter.
+        let _ = unsafe { libc::write(self.fds[1], [0u8].as_ptr() as *const libc::c_void, 1) };
+    }
+
+    #[cfg(feature = "stream")]
+    pub(crate) fn drain(fd: RawFd) {
+        let mut buffer = [0u8; 64];
+        while unsafe { libc::read(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) } > 0 {}
+    }
+}
+
+impl Drop for SelfPipe {
+    fn drop(&mut self) {
+        for fd in &self.fds {
+            unsafe { libc::close(*fd); }
+        }
+    }
+}
+
+/**
+  Puts a descriptor into non-blocking mode, and gives it back the flags it had when dropped. The
+  flags are also registered to be put back by [restore_terminal](crate::user::restore_terminal).
+  */
+#[cfg(any(test, feature = "stream"))]
+pub(crate) struct NonBlocking {
+    fd: RawFd,
+    flags: libc::c_int,
+    registered: bool,
+}
+
+#[cfg(any(test, feature = "stream"))]
+impl NonBlocking {
+    pub(crate) fn set(fd: RawFd) -> io::Result<Self> {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let registered = user::save_file_flags(fd, flags);
+        if unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            let err = io::Error::last_os_error();
+            if registered {
+                user::forget_file_flags();
+            }
+            return Err(err);
+        }
+        Ok(NonBlocking { fd, flags, registered })
+    }
+}
+
+#[cfg(any(test, feature = "stream"))]
+impl Drop for NonBlocking {
+    fn drop(&mut self) {
+        // The flags belong to the open file, which stdin shares with the shell that started us.
+        unsafe { libc::fcntl(self.fd, libc::F_SETFL, self.flags); }
+        if self.registered {
+            user::forget_file_flags();
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    collections::VecDeque,
+    io,
+    os::unix::io::{AsRawFd, RawFd},
+    time::{Duration, Instant},
+};
+use zeroize::Zeroizing;
+use crate::{
+    parse::ExtendedChar,
+    pipe::wait_readable,
+    user::{
+        ControlChars,
+        InputOptions,
+        InputParser,
+        RawModeGuard,
+        Status,
+        handle_job_control_signals,
+        input_ended,
+        read_input,
+        suspend,
+    },
+};
+
+/**
+  A single-threaded alternative to [parsed_input](crate::user::parsed_input). Rather than passing
+  each byte between two threads, it waits on the terminal with `poll(2)`, using the escape sequence
+  timeout as the poll timeout, and reads all of the available input at once.
+
+  The terminal file descriptor is exposed through [AsRawFd] so that it can be registered with an
+  external event loop. Whenever the descriptor becomes readable, or the [escape_deadline] passes,
+  call [try_next] until it returns `None`.
+
+  [escape_deadline]: PollInput::escape_deadline
+  [try_next]: PollInput::try_next
+  */
+pub struct PollInput {
+    fd: RawFd,
+    guard: RawModeGuard,
+    parser: InputParser,
+    events: VecDeque<ExtendedChar>,
+    escape_deadline: Option<Instant>,
+    done: bool,
+}
+
+/**
+  Puts stdin into raw mode and returns a [PollInput] reading parsed characters from it. The
+  original terminal settings are restored when the [PollInput] is dropped.
+  */
+pub fn poll_input(quit_sequence: &'static [u8]) -> io::Result<PollInput> {
+    poll_input_with(quit_sequence, InputOptions::default())
+}
+
+pub fn poll_input_with(quit_sequence: &'static [u8], options: InputOptions) -> io::Result<PollInput> {
+    poll_input_from(0, quit_sequence, options)
+}
+
+/// Like [poll_input_with], but reads from the terminal open on `fd` instead of stdin.
+pub(crate) fn poll_input_from(fd: RawFd, quit_sequence: &'static [u8], options: InputOptions) -> io::Result<PollInput> {
+    if options.job_control {
+        handle_job_control_signals()?;
+    }
+    let guard = RawModeGuard::with_mode(fd, options.mode)?;
+    let control = ControlChars::from_termios(guard.original());
+    Ok(PollInput {
+        fd,
+        guard,
+        parser: InputParser::new(quit_sequence, &options, control),
+        events: VecDeque::new(),
+        escape_deadline: None,
+        done: false,
+    })
+}
+
+impl PollInput {
+    /**
+      Returns the next character if one can be decoded from the input that is already available,
+      without waiting. A return value of `None` means either that nothing is ready yet or that the
+      input has finished; use [is_done] to tell them apart.
+
+      [is_done]: PollInput::is_done
+      */
+    pub fn try_next(&mut self) -> Option<ExtendedChar> {
+        self.next_event(Some(Instant::now()))
+    }
+
+    /// Waits up to `timeout` for the next character.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<ExtendedChar> {
+        self.next_event(Some(Instant::now() + timeout))
+    }
+
+    /// Waits until `deadline` for the next character.
+    pub fn next_deadline(&mut self, deadline: Instant) -> Option<ExtendedChar> {
+        self.next_event(Some(deadline))
+    }
+
+    /// Returns true once the input has finished and no more characters will be returned.
+    pub fn is_done(&self) -> bool {
+        self.done && self.events.is_empty()
+    }
+
+    /**
+      Returns the time at which held input that might be the start of an escape sequence will be
+      given up on and returned as plain characters, if any input is being held.
+      */
+    pub fn escape_deadline(&self) -> Option<Instant> {
+        self.escape_deadline
+    }
+
+    fn next_event(&mut self, deadline: Option<Instant>) -> Option<ExtendedChar> {
+        let mut waited = false;
+        loop {
+            let now = Instant::now();
+            if self.parser.take_resumed() {
+                self.events.push_back(ExtendedChar::Resumed);
+            }
+            if self.escape_deadline.is_some_and(|d| d <= now) {
+                self.escape_deadline = None;
+                self.flush();
+            }
+            if let Some(e) = self.events.pop_front() {
+                return Some(e);
+            }
+            if self.done { return None; }
+            if waited && deadline.is_some_and(|d| d <= now) { return None; }
+            let wake = match (deadline, self.escape_deadline) {
+                (Some(d), Some(e)) => Some(d.min(e)),
+                (d, e) => d.or(e),
+            };
+            match wait_readable(self.fd, wake.map(|w| w.saturating_duration_since(now))) {
+                Ok(true) => { self.read_available(); },
+                Ok(false) => {},
+                Err(_) => { self.finish(); },
+            }
+            waited = true;
+        }
+    }
+
+    fn read_available(&mut self) {
+        // Wiped afterwards, since the input may be a password.
+        let mut buffer = Zeroizing::new([0u8; 64]);
+        let count = match read_input(self.fd, &mut *buffer) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted || e.kind() == io::ErrorKind::WouldBlock => { return; },
+            Ok(0) if !input_ended(self.fd, self.guard.mode()) => { return; },
+            Ok(0) | Err(_) => {
+                self.finish();
+                return;
+            },
+            Ok(count) => count,
+        };
+        let mut arrived = Some(Instant::now());
+        let mut events = Vec::new();
+        for &b in &buffer[..count] {
+            let status = self.parser.push(b, arrived.take(), &mut events);
+            self.events.extend(events.drain(..));
+            match status {
+                Status::Continue => {},
+                Status::Suspend => {
+                    suspend();
+                    self.events.push_back(ExtendedChar::Resumed);
+                },
+                Status::Quit => {
+                    self.finish();
+                    return;
+                },
+            }
+        }
+        self.escape_deadline = if self.parser.is_pending() {
+            Some(Instant::now() + self.parser.timeout())
+        }
+        else {
+            None
+        };
+    }
+
+    fn flush(&mut self) {
+        let mut events = Vec::new();
+        let status = self.parser.flush(&mut events);
+        self.events.extend(events);
+        if status == Status::Quit {
+            self.escape_deadline = None;
+            self.done = true;
+        }
+    }
+
+    fn finish(&mut self) {
+        self.flush();
+        self.escape_deadline = None;
+        self.done = true;
+    }
+}
+
+impl Iterator for PollInput {
+    type Item = ExtendedChar;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event(None)
+    }
+}
+
+impl AsRawFd for PollInput {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        parse::{ExtendedChar::*, SpecialChar::*},
+        poll::*,
+        user::{Pty, lock_saved_terminal},
+    };
+
+    fn input(pty: &Pty) -> PollInput {
+        let options = InputOptions { job_control: false, ..InputOptions::default() };
+        poll_input_from(pty.slave, &[], options).unwrap()
+    }
+
+    #[test]
+    fn test_poll_input() {
+        let _lock = lock_saved_terminal();
+        let mut pty = Pty::open();
+        let mut input = input(&pty);
+        // Nothing has been typed, so there is nothing to wait for.
+        let started = Instant::now();
+        assert_eq!(input.try_next(), None);
+        assert!(started.elapsed() < Duration::from_millis(50));
+        assert!(!input.is_done());
+        // A sequence read along with other keys is still decoded whole.
+        pty.type_keys(b"a\x1b[A");
+        assert_eq!(input.next_timeout(Duration::from_secs(1)), Some(Byte(b'a')));
+        assert_eq!(input.try_next(), Some(Normal(UpArrow)));
+        assert_eq!(input.escape_deadline(), None);
+        // A lone escape is held until its deadline, and then given up on.
+        pty.type_keys(b"\x1b");
+        assert_eq!(input.next_timeout(Duration::from_millis(1)), None);
+        let deadline = input.escape_deadline().unwrap();
+        assert_eq!(input.try_next(), None);
+        assert_eq!(input.next_deadline(deadline + Duration::from_secs(1)), Some(Byte(0x1b)));
+        assert!(Instant::now() >= deadline);
+        assert_eq!(input.escape_deadline(), None);
+        // Hanging up the terminal ends the input.
+        unsafe { libc::close(pty.master); }
+        pty.master = -1;
+        assert_eq!(input.next_timeout(Duration::from_secs(1)), None);
+        assert!(input.is_done());
+        assert_eq!(input.next(), None);
+    }
+}
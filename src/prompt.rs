@@ -0,0 +1,426 @@
+use std::{
+    fmt::{self, Display},
+    io::{self, Write},
+    ops::{Bound, RangeBounds},
+    path::PathBuf,
+};
+use unicode_width::UnicodeWidthChar;
+pub use zeroize::Zeroizing;
+use crate::{
+    line::{Edit, LineEditor, printable},
+    parse::{
+        ExtendedChar::{self, *},
+        SpecialChar::*,
+    },
+    poll::poll_input_with,
+    user::InputOptions,
+};
+
+/// How the characters of a password are shown as they are typed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaskStyle {
+    /// Nothing is shown, as with `getpass(3)`.
+    Hidden,
+    /// Each character is shown as an asterisk.
+    Asterisk,
+    /// The last character typed is shown as it is and the ones before it as asterisks.
+    LastCharVisible,
+}
+
+/**
+  Shows `prompt` on stdout and reads a password from the terminal on stdin, returning `None` at
+  the end of the input or when Ctrl-D is pressed on an empty password. Ctrl-C gives an error of
+  kind [Interrupted](io::ErrorKind::Interrupted).
+
+  The terminal is put into raw mode for the duration, with echo off, and put back afterwards
+  however the function returns, including when it panics. Stdin is read directly, so no other
+  input session should be running at the same time; with one running, use [read_password_with].
+  */
+pub fn read_password(prompt: &str, style: MaskStyle) -> io::Result<Option<Zeroizing<String>>> {
+    let options = InputOptions {
+        signal_events: true,
+        editing_events: true,
+        ..InputOptions::default()
+    };
+    let mut input = poll_input_with(&[], options)?;
+    read_password_with(&mut input, &mut io::stdout(), prompt, style)
+}
+
+/**
+  Reads a password from `input`, showing the prompt and the mask on `out`. The input should come
+  from a terminal with echo off, as it does from any of the input sessions in this crate.
+
+  Backspace deletes the last character and Ctrl-U all of them, and Enter finishes. The password is
+  kept in memory that is zeroed once it is no longer needed, including whatever was deleted, and
+  it is returned in a [Zeroizing] wrapper so that it goes on being wiped when the caller drops it.
+  */
+pub fn read_password_with<I: IntoIterator<Item = ExtendedChar>, W: Write>(input: I, out: &mut W, prompt: &str, style: MaskStyle) -> io::Result<Option<Zeroizing<String>>> {
+    let mut password = Zeroizing::new(String::new());
+    let mut count = 0;
+    // The width of the last character, while it is showing as typed.
+    let mut shown = None;
+    out.write_all(prompt.as_bytes())?;
+    out.flush()?;
+    for key in input {
+        // The echo may hold the last character as it was typed.
+        let mut echo = Zeroizing::new(String::new());
+        match key {
+            Byte(b'\r') | Byte(b'\n') => {
+                out.write_all(b"\r\n")?;
+                out.flush()?;
+                return Ok(Some(password));
+            },
+            Byte(3) | Interrupt => {
+                out.write_all(b"\r\n")?;
+                out.flush()?;
+                return Err(io::ErrorKind::Interrupted.into());
+            },
+            Byte(4) | EndOfFile if password.is_empty() => {
+                out.write_all(b"\r\n")?;
+                out.flush()?;
+                return Ok(None);
+            },
+            Byte(0x7f) | Byte(8) | Normal(Backspace) => {
+                if password.pop().is_some() {
+                    erase(&mut echo, style, 1, shown.take());
+                    count -= 1;
+                }
+            },
+            Byte(21) | KillLine => {
+                erase(&mut echo, style, count, shown.take());
+                password.clear();
+                count = 0;
+            },
+            _ => {
+                let c = match printable(key) {
+                    Some(c) => c,
+                    None => continue,
+                };
+                // Growing the string in place could leave a copy behind in the memory it moves out of.
+                if password.len() + c.len_utf8() > password.capacity() {
+                    let mut grown = Zeroizing::new(String::with_capacity((password.capacity() * 2).max(32)));
+                    grown.push_str(&password);
+                    password = grown;
+                }
+                password.push(c);
+                count += 1;
+                match style {
+                    MaskStyle::Hidden => {},
+                    MaskStyle::Asterisk => { echo.push('*'); },
+                    MaskStyle::LastCharVisible => {
+                        if let Some(width) = shown {
+                            // Cover the character with a mask and blank whatever the mask doesn't cover.
+                            push_repeated(&mut echo, "\x08", width);
+                            echo.push('*');
+                            push_repeated(&mut echo, " ", width.saturating_sub(1));
+                            push_repeated(&mut echo, "\x08", width.saturating_sub(1));
+                        }
+                        echo.push(c);
+                        shown = Some(c.width().unwrap_or(0));
+                    },
+                }
+            },
+        }
+        out.write_all(echo.as_bytes())?;
+        out.flush()?;
+    }
+    out.write_all(b"\r\n")?;
+    out.flush()?;
+    Ok(None)
+}
+
+/**
+  Adds the output that rubs out the last `count` characters. They are all masks, except that the
+  last one is showing as typed if `shown` gives its width.
+  */
+fn erase(echo: &mut String, style: MaskStyle, count: usize, shown: Option<usize>) {
+    if style == MaskStyle::Hidden || count == 0 { return; }
+    rub_out(echo, shown.unwrap_or(1));
+    for _ in 1..count {
+        rub_out(echo, 1);
+    }
+}
+
+/// Adds the output that blanks the `width` columns before the cursor and moves back over them.
+fn rub_out(echo: &mut String, width: usize) {
+    push_repeated(echo, "\x08", width);
+    push_repeated(echo, " ", width);
+    push_repeated(echo, "\x08", width);
+}
+
+/// Adds `s` to the echo `count` times.
+fn push_repeated(echo: &mut String, s: &str, count: usize) {
+    for _ in 0..count {
+        echo.push_str(s);
+    }
+}
+
+/// What a prompt such as [read_value] gave back when it wasn't left by the end of the input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Prompted<T> {
+    /// A value was entered.
+    Value(T),
+    /// The prompt was dismissed with Escape.
+    Cancelled,
+}
+
+impl<T> Prompted<T> {
+    /// Returns the value entered, or `None` if the prompt was cancelled.
+    pub fn value(self) -> Option<T> {
+        match self {
+            Prompted::Value(value) => Some(value),
+            Prompted::Cancelled => None,
+        }
+    }
+}
+
+/**
+  Reads a line from `input` with a [LineEditor] drawing on `out`, and returns the value that
+  `parse` makes of it. While `parse` gives an error, the message is shown beneath the line and
+  the prompt stays open for the line to be corrected. Escape gives [Cancelled](Prompted::Cancelled)
+  and Ctrl-C an error of kind [Interrupted](io::ErrorKind::Interrupted). Returns `None` if the
+  input ends, or Ctrl-D is pressed on an empty line.
+  */
+pub fn read_value<T, I: IntoIterator<Item = ExtendedChar>, W: Write, F: Fn(&str) -> Result<T, String>>(input: I, out: &mut W, prompt: &str, parse: F) -> io::Result<Option<Prompted<T>>> {
+    let mut editor = LineEditor::with_output(out, prompt);
+    editor.start()?;
+    for key in input {
+        match key {
+            Byte(b'\r') | Byte(b'\n') => match parse(&editor.buffer().text()) {
+                Ok(value) => {
+                    editor.set_status(None);
+                    editor.finish()?;
+                    return Ok(Some(Prompted::Value(value)));
+                },
+                Err(message) => {
+                    editor.set_status(Some(&message));
+                    editor.redraw()?;
+                },
+            },
+            Byte(0x1b) => {
+                editor.set_status(None);
+                editor.finish()?;
+                return Ok(Some(Prompted::Cancelled));
+            },
+            _ => match editor.handle(key)? {
+                Edit::Continue => {},
+                Edit::Cancel => { return Err(io::ErrorKind::Interrupted.into()); },
+                _ => { return Ok(None); },
+            },
+        }
+    }
+    editor.set_status(None);
+    editor.finish()?;
+    Ok(None)
+}
+
+/// Reads a whole number in `range`, as for [read_value].
+pub fn read_integer<I: IntoIterator<Item = ExtendedChar>, W: Write, R: RangeBounds<i64>>(input: I, out: &mut W, prompt: &str, range: R) -> io::Result<Option<Prompted<i64>>> {
+    read_value(input, out, prompt, |text| {
+        let error = || format!("Enter a whole number{}", describe_range(&range));
+        let value = text.trim().parse().map_err(|_| error())?;
+        if range.contains(&value) { Ok(value) } else { Err(error()) }
+    })
+}
+
+/// Reads a number in `range`, which may have a fractional part but must be finite, as for [read_value].
+pub fn read_float<I: IntoIterator<Item = ExtendedChar>, W: Write, R: RangeBounds<f64>>(input: I, out: &mut W, prompt: &str, range: R) -> io::Result<Option<Prompted<f64>>> {
+    read_value(input, out, prompt, |text| {
+        let error = || format!("Enter a number{}", describe_range(&range));
+        let value: f64 = text.trim().parse().map_err(|_| error())?;
+        if value.is_finite() && range.contains(&value) { Ok(value) } else { Err(error()) }
+    })
+}
+
+/// Reads a line that `pattern` matches in full, as for [read_value], with `message` shown until it does.
+#[cfg(feature = "regex")]
+pub fn read_matching<I: IntoIterator<Item = ExtendedChar>, W: Write>(input: I, out: &mut W, prompt: &str, pattern: &regex::Regex, message: &str) -> io::Result<Option<Prompted<String>>> {
+    read_value(input, out, prompt, |text| {
+        match pattern.find(text) {
+            Some(found) if found.start() == 0 && found.end() == text.len() => Ok(text.to_string()),
+            _ => Err(message.to_string()),
+        }
+    })
+}
+
+/// A date in the Gregorian calendar, as read by [read_date].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    /// Returns the date, or `None` if there is no such day.
+    pub fn new(year: i32, month: u32, day: u32) -> Option<Self> {
+        let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+        let days = match month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if leap => 29,
+            2 => 28,
+            _ => return None,
+        };
+        if day == 0 || day > days { return None; }
+        Some(Date { year, month, day })
+    }
+}
+
+impl Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// Reads a date written as `YYYY-MM-DD`, as for [read_value].
+pub fn read_date<I: IntoIterator<Item = ExtendedChar>, W: Write>(input: I, out: &mut W, prompt: &str) -> io::Result<Option<Prompted<Date>>> {
+    read_value(input, out, prompt, |text| {
+        let parts: Vec<&str> = text.trim().split('-').collect();
+        let date = match parts[..] {
+            [year, month, day] if year.len() == 4 && month.len() == 2 && day.len() == 2 => {
+                match (year.parse(), month.parse(), day.parse()) {
+                    (Ok(year), Ok(month), Ok(day)) => Date::new(year, month, day),
+                    _ => None,
+                }
+            },
+            _ => None,
+        };
+        date.ok_or_else(|| "Enter a date as YYYY-MM-DD".to_string())
+    })
+}
+
+/// What [read_path] requires of a path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathCheck {
+    /// Any path will do.
+    Any,
+    /// Something must exist at the path.
+    Exists,
+    /// The path must be an existing file.
+    File,
+    /// The path must be an existing directory.
+    Directory,
+    /// Nothing may exist at the path yet, as for a file to be created.
+    Missing,
+}
+
+/// Reads a path that passes `check`, as for [read_value]. Relative paths are taken from the current directory.
+pub fn read_path<I: IntoIterator<Item = ExtendedChar>, W: Write>(input: I, out: &mut W, prompt: &str, check: PathCheck) -> io::Result<Option<Prompted<PathBuf>>> {
+    read_value(input, out, prompt, |text| {
+        if text.is_empty() { return Err("Enter a path".to_string()); }
+        let path = PathBuf::from(text);
+        let error = match check {
+            PathCheck::Any => None,
+            PathCheck::Exists if !path.exists() => Some("Nothing exists at that path"),
+            PathCheck::File if !path.is_file() => Some("There is no file at that path"),
+            PathCheck::Directory if !path.is_dir() => Some("There is no directory at that path"),
+            PathCheck::Missing if path.exists() => Some("Something already exists at that path"),
+            _ => None,
+        };
+        match error {
+            Some(error) => Err(error.to_string()),
+            None => Ok(path),
+        }
+    })
+}
+
+/// Describes the values in a range, for the error messages of the number prompts.
+fn describe_range<T: Display, R: RangeBounds<T>>(range: &R) -> String {
+    let lower = match range.start_bound() {
+        Bound::Included(start) => Some(format!("at least {}", start)),
+        Bound::Excluded(start) => Some(format!("above {}", start)),
+        Bound::Unbounded => None,
+    };
+    let upper = match range.end_bound() {
+        Bound::Included(end) => Some(format!("at most {}", end)),
+        Bound::Excluded(end) => Some(format!("below {}", end)),
+        Bound::Unbounded => None,
+    };
+    match (range.start_bound(), range.end_bound(), lower, upper) {
+        (Bound::Included(start), Bound::Included(end), _, _) => format!(" from {} to {}", start, end),
+        (_, _, Some(lower), Some(upper)) => format!(" {} and {}", lower, upper),
+        (_, _, lower, upper) => lower.or(upper).map(|bound| format!(" {}", bound)).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prompt::*;
+
+    fn read(keys: &[ExtendedChar], style: MaskStyle) -> (io::Result<Option<Zeroizing<String>>>, String) {
+        let mut out = Vec::new();
+        let result = read_password_with(keys.iter().copied(), &mut out, "Password: ", style);
+        (result, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_read_password() {
+        let keys = [Byte(b'a'), Char('\u{e9}'), Byte(b'c'), Byte(0x7f), Byte(b'd'), Byte(b'\r')];
+        let (result, out) = read(&keys, MaskStyle::Hidden);
+        assert_eq!(result.unwrap().as_deref().map(String::as_str), Some("a\u{e9}d"));
+        assert_eq!(out, "Password: \r\n");
+        let (_, out) = read(&keys, MaskStyle::Asterisk);
+        assert_eq!(out, "Password: ***\x08 \x08*\r\n");
+        let (_, out) = read(&keys, MaskStyle::LastCharVisible);
+        assert_eq!(out, "Password: a\x08*\u{e9}\x08*c\x08 \x08d\r\n");
+        // A wide character takes up two columns, both as it is shown and as it is rubbed out.
+        let keys = [Char('\u{754c}'), Byte(b'b'), Byte(0x7f), Byte(0x7f), Char('\u{754c}'), KillLine, Byte(b'\r')];
+        let (result, out) = read(&keys, MaskStyle::LastCharVisible);
+        assert_eq!(result.unwrap().as_deref().map(String::as_str), Some(""));
+        assert_eq!(out, "Password: \u{754c}\x08\x08* \x08b\x08 \x08\x08 \x08\u{754c}\x08\x08  \x08\x08\r\n");
+        // Ctrl-U clears everything typed so far, and the arrows are ignored.
+        let keys = [Byte(b'a'), Byte(b'b'), KillLine, Normal(LeftArrow), Byte(b'c'), Byte(b'\n')];
+        let (result, out) = read(&keys, MaskStyle::Asterisk);
+        assert_eq!(result.unwrap().as_deref().map(String::as_str), Some("c"));
+        assert_eq!(out, "Password: **\x08 \x08\x08 \x08*\r\n");
+        // A long password survives the buffer being replaced as it grows.
+        let long: Vec<_> = "x".repeat(100).bytes().map(Byte).chain(Some(Byte(b'\r'))).collect();
+        assert_eq!(read(&long, MaskStyle::Hidden).0.unwrap().unwrap().len(), 100);
+        assert_eq!(read(&[Byte(4)], MaskStyle::Hidden).0.unwrap(), None);
+        assert_eq!(read(&[Byte(b'a'), Byte(4)], MaskStyle::Hidden).0.unwrap(), None);
+        assert_eq!(read(&[Byte(b'a'), Interrupt], MaskStyle::Hidden).0.unwrap_err().kind(), io::ErrorKind::Interrupted);
+    }
+
+    fn keys(s: &str) -> Vec<ExtendedChar> {
+        s.bytes().map(Byte).collect()
+    }
+
+    #[test]
+    fn test_read_value() {
+        let mut out = Vec::new();
+        assert_eq!(read_integer(keys("x\r\x7f12\r\x7f\r"), &mut out, "n: ", 1..=10).unwrap(), Some(Prompted::Value(1)));
+        let out = String::from_utf8(out).unwrap();
+        // The error is shown beneath the line until the line is accepted.
+        assert!(out.starts_with("\rn: \x1b[Kx\rn: x\x1b[K\r\nEnter a whole number from 1 to 10\x1b[1A\x1b[29D"));
+        assert!(out.ends_with("\rn: 1\x1b[J\r\nEnter a whole number from 1 to 10\x1b[1A\x1b[29D\x1b[J\r\n"));
+        assert_eq!(read_integer(keys("-3\r"), &mut Vec::new(), "", ..0).unwrap(), Some(Prompted::Value(-3)));
+        assert_eq!(read_float(keys(" 2.5\r"), &mut Vec::new(), "", 0.0..).unwrap(), Some(Prompted::Value(2.5)));
+        // Escape, Ctrl-C and the end of the input each leave the prompt in their own way.
+        assert_eq!(read_float(keys("inf\r\x1b"), &mut Vec::new(), "", ..).unwrap(), Some(Prompted::Cancelled));
+        assert_eq!(read_integer(keys("5\x03"), &mut Vec::new(), "", ..).unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert_eq!(read_integer(keys("5"), &mut Vec::new(), "", ..).unwrap(), None);
+        assert_eq!(read_integer(keys("\x04"), &mut Vec::new(), "", ..).unwrap(), None);
+        assert_eq!(describe_range(&(0.5..)), " at least 0.5");
+        assert_eq!(describe_range(&(1..10)), " at least 1 and below 10");
+        assert_eq!(describe_range::<i64, _>(&(..)), "");
+        let date = read_date(keys("2023-02-29\r\x7f8\r"), &mut Vec::new(), "").unwrap().and_then(Prompted::value).unwrap();
+        assert_eq!((date, date.to_string()), (Date { year: 2023, month: 2, day: 28 }, "2023-02-28".to_string()));
+        assert_eq!(Date::new(2024, 2, 29).map(|d| d.day), Some(29));
+        assert_eq!(Date::new(1900, 2, 29), None);
+        let here = std::env::current_dir().unwrap();
+        let dir = here.to_str().unwrap();
+        assert_eq!(read_path(keys(&format!("{}\r", dir)), &mut Vec::new(), "", PathCheck::Directory).unwrap(), Some(Prompted::Value(here.clone())));
+        let keys = keys(&format!("{}\r\x15Cargo.toml\r", dir));
+        assert_eq!(read_path(keys, &mut Vec::new(), "", PathCheck::File).unwrap(), Some(Prompted::Value(PathBuf::from("Cargo.toml"))));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_read_matching() {
+        let pattern = regex::Regex::new("[a-z]+").unwrap();
+        let mut out = Vec::new();
+        assert_eq!(read_matching(keys("ab1\r\x7f\r"), &mut out, "", &pattern, "Letters only").unwrap(), Some(Prompted::Value("ab".to_string())));
+        assert!(String::from_utf8(out).unwrap().contains("Letters only"));
+    }
+}
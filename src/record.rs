@@ -0,0 +1,422 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+use termios::Termios;
+use crate::{
+    parse::ExtendedChar,
+    pipe::{SelfPipe, wait_readable},
+    user::{ByteSource, ControlChars, InputByte, InputOptions, StdinIterator, TimedByte, user_session},
+};
+
+/// The first line of a recording.
+const HEADER: &str = "# terminal_input recording: microseconds since the first read, then its bytes in hex";
+
+/**
+  Writes the bytes read from the terminal to a recording that can be [replayed](replay_input)
+  later, one read to a line. Each line holds the time of the read in microseconds, counted from the
+  first read, followed by the bytes it returned in hex:
+
+  ```text
+  # terminal_input recording: microseconds since the first read, then its bytes in hex
+  control 03 1c 1a 7f 15 17 04 16
+  0 61
+  153042 1b5b41
+  ```
+
+  The `control` line lists the terminal's interrupt, quit, suspend, erase, kill, word erase, end of
+  file and literal next characters, with `-` for the ones that are disabled. Everything typed ends
+  up in the recording, passwords included.
+  */
+pub struct Recorder<W: Write> {
+    out: W,
+    start: Option<Instant>,
+    chunk: Vec<u8>,
+}
+
+impl Recorder<BufWriter<File>> {
+    /// Creates a recording at `path`, replacing any file that is already there.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Recorder::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(out: W) -> Self {
+        Recorder {
+            out,
+            start: None,
+            chunk: Vec::new(),
+        }
+    }
+
+    /// Writes the header, with the control characters the recorded session uses.
+    fn start(&mut self, control: &ControlChars) -> io::Result<()> {
+        writeln!(self.out, "{}", HEADER)?;
+        writeln!(self.out, "control {}", format_control(control))?;
+        self.out.flush()
+    }
+
+    /**
+      Adds a byte from a read that finished at `arrived`, writing out the read once its last byte
+      has been added.
+      */
+    pub fn record(&mut self, input: &InputByte, arrived: Instant) -> io::Result<()> {
+        self.chunk.push(input.byte);
+        if !input.end_of_chunk { return Ok(()); }
+        let start = *self.start.get_or_insert(arrived);
+        write!(self.out, "{} ", arrived.saturating_duration_since(start).as_micros())?;
+        for b in self.chunk.drain(..) {
+            write!(self.out, "{:02x}", b)?;
+        }
+        writeln!(self.out)?;
+        // Flushed every time, so that the recording is complete even if the program then crashes.
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/**
+  Starts a session like [parsed_input_with](crate::user::parsed_input_with) that also writes what
+  is read to `recorder`. Only writing the header can fail; later write errors are ignored, so that
+  a full disk doesn't stop the input.
+  */
+pub fn recorded_input<W: Write + Send + 'static>(quit_sequence: &'static [u8], options: InputOptions, mut recorder: Recorder<W>) -> io::Result<StdinIterator<ExtendedChar>> {
+    // The session reads the same characters for itself.
+    let control = Termios::from_fd(0).map(|t| ControlChars::from_termios(&t)).unwrap_or_default();
+    recorder.start(&control)?;
+    let tee = Box::new(move |timed: &TimedByte| {
+        let _ = recorder.record(&timed.input, timed.arrived);
+    });
+    Ok(StdinIterator::spawn(move |sender, stop| {
+        user_session(sender, quit_sequence, options, stop, ByteSource::Terminal, Some(tee));
+    }))
+}
+
+/// One read from the terminal: when it finished, counted from the first read, and what it returned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    pub time: Duration,
+    pub bytes: Vec<u8>,
+}
+
+/// A recording made by a [Recorder], read back for [replay_input].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Recording {
+    control: ControlChars,
+    chunks: Vec<Chunk>,
+}
+
+impl Recording {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        fs::read_to_string(path)?.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    /// Adds a read at `time`, or at the time of the last one if that is later.
+    pub fn push(&mut self, time: Duration, bytes: &[u8]) {
+        let time = self.chunks.last().map_or(time, |last| time.max(last.time));
+        self.chunks.push(Chunk { time, bytes: bytes.to_vec() });
+    }
+}
+
+impl FromStr for Recording {
+    type Err = RecordingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut recording = Recording::new();
+        for (i, line) in s.lines().enumerate() {
+            let error = |kind| RecordingError { line: i + 1, kind };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(control) = line.strip_prefix("control ") {
+                recording.control = parse_control(control).ok_or_else(|| error(RecordingErrorKind::BadControl))?;
+                continue;
+            }
+            let (time, bytes) = line.split_once(' ').ok_or_else(|| error(RecordingErrorKind::BadBytes))?;
+            let time = time.parse().map(Duration::from_micros).map_err(|_| error(RecordingErrorKind::BadTime))?;
+            let bytes = parse_bytes(bytes.trim()).ok_or_else(|| error(RecordingErrorKind::BadBytes))?;
+            if recording.chunks.last().is_some_and(|last| last.time > time) {
+                return Err(error(RecordingErrorKind::OutOfOrder));
+            }
+            recording.push(time, &bytes);
+        }
+        Ok(recording)
+    }
+}
+
+fn format_control(control: &ControlChars) -> String {
+    let chars = [
+        control.interrupt,
+        control.quit,
+        control.suspend,
+        control.erase,
+        control.kill,
+        control.word_erase,
+        control.eof,
+        control.literal_next,
+    ];
+    let chars: Vec<_> = chars.iter().map(|c| c.map_or("-".to_string(), |c| format!("{:02x}", c))).collect();
+    chars.join(" ")
+}
+
+fn parse_control(text: &str) -> Option<ControlChars> {
+    let chars = text.split_whitespace().map(|c| match c {
+        "-" => Some(None),
+        _ => parse_bytes(c).filter(|b| b.len() == 1).map(|b| Some(b[0])),
+    });
+    match chars.collect::<Option<Vec<_>>>()?[..] {
+        [interrupt, quit, suspend, erase, kill, word_erase, eof, literal_next] => Some(ControlChars {
+            interrupt,
+            quit,
+            suspend,
+            erase,
+            kill,
+            word_erase,
+            eof,
+            literal_next,
+        }),
+        _ => None,
+    }
+}
+
+/// Parses a non-empty string of hex digit pairs.
+fn parse_bytes(text: &str) -> Option<Vec<u8>> {
+    if text.is_empty() || text.len() % 2 != 0 || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect()
+}
+
+/// How quickly [replay_input] sends the recorded reads.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pace {
+    /// Keep to the recorded times, divided by the speed, so that 2.0 replays twice as fast.
+    Speed(f64),
+    /// Send everything at once.
+    Immediate,
+}
+
+impl Default for Pace {
+    fn default() -> Self {
+        Pace::Speed(1.0)
+    }
+}
+
+/**
+  Replays a recording through the same session as [parsed_input_with], in place of the terminal,
+  returning the characters it produces. The recorded control characters are used, and
+  [job_control](InputOptions::job_control) is turned off, since there is no terminal to stop for.
+
+  At [Immediate](Pace::Immediate) pace every read is queued up at once with its recorded time, so
+  held input is given up on after the escape timeout exactly as it was when it was recorded. At
+  other paces the reads arrive like keys being typed, with the gaps scaled by the speed, so the
+  timeout sees the scaled gaps too.
+
+  Panics if the speed isn't a positive number.
+
+  [parsed_input_with]: crate::user::parsed_input_with
+  */
+pub fn replay_input(recording: Recording, quit_sequence: &'static [u8], options: InputOptions, pace: Pace) -> StdinIterator<ExtendedChar> {
+    if let Pace::Speed(speed) = pace {
+        assert!(speed > 0.0 && speed.is_finite(), "replay speed must be positive, not {}", speed);
+    }
+    let options = InputOptions { job_control: false, ..options };
+    StdinIterator::spawn(move |to_main, stop| {
+        let Recording { control, chunks } = recording;
+        let (to_session, bytes) = mpsc::channel();
+        let sender = match pace {
+            Pace::Immediate => {
+                let start = Instant::now();
+                for chunk in &chunks {
+                    send_chunk(&to_session, chunk, start + chunk.time);
+                }
+                // The session ends once it has read everything.
+                drop(to_session);
+                None
+            },
+            Pace::Speed(speed) => {
+                let stop = stop.clone();
+                Some(thread::spawn(move || {feed(&chunks, &to_session, speed, stop.as_deref());}))
+            },
+        };
+        let source = ByteSource::Injected { control, bytes, sender };
+        user_session(to_main, quit_sequence, options, stop, source, None);
+    })
+}
+
+/// Sends the recorded reads at their recorded times divided by `speed`, until `stop` is notified.
+fn feed(chunks: &[Chunk], to_session: &mpsc::Sender<TimedByte>, speed: f64, stop: Option<&SelfPipe>) {
+    let start = Instant::now();
+    for chunk in chunks {
+        let due = start + chunk.time.div_f64(speed);
+        loop {
+            let wait = due.saturating_duration_since(Instant::now());
+            if wait.is_zero() {
+                break;
+            }
+            match stop {
+                Some(stop) => {
+                    if wait_readable(stop.read_fd(), Some(wait)).unwrap_or(true) {
+                        return;
+                    }
+                },
+                None => { thread::sleep(wait); },
+            }
+        }
+        if !send_chunk(to_session, chunk, Instant::now()) {
+            return;
+        }
+    }
+}
+
+/// Sends the bytes of a read that finished at `arrived`, returning false if the session has ended.
+fn send_chunk(to_session: &mpsc::Sender<TimedByte>, chunk: &Chunk, arrived: Instant) -> bool {
+    chunk.bytes.iter().enumerate().all(|(i, b)| {
+        let input = InputByte { byte: *b, end_of_chunk: i + 1 == chunk.bytes.len() };
+        to_session.send(TimedByte { input, arrived }).is_ok()
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordingError {
+    line: usize,
+    kind: RecordingErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordingErrorKind {
+    BadTime,
+    BadBytes,
+    BadControl,
+    OutOfOrder,
+}
+
+impl RecordingError {
+    /// Returns the line the problem was found on, counting from 1.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn kind(&self) -> &RecordingErrorKind {
+        &self.kind
+    }
+
+    pub fn __description(&self) -> &str {
+        match self.kind {
+            RecordingErrorKind::BadTime => "expected a time in microseconds",
+            RecordingErrorKind::BadBytes => "expected a time followed by bytes in hex",
+            RecordingErrorKind::BadControl => "expected eight control characters in hex, or `-`",
+            RecordingErrorKind::OutOfOrder => "read is earlier than the one before it",
+        }
+    }
+}
+
+impl Display for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.__description())
+    }
+}
+
+impl Error for RecordingError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::record::*;
+    use crate::parse::{ExtendedChar::*, SpecialChar::*};
+
+    #[test]
+    fn test_recorder() {
+        let mut recorder = Recorder::new(Vec::new());
+        let control = ControlChars { interrupt: Some(3), erase: Some(0x7f), ..ControlChars::default() };
+        recorder.start(&control).unwrap();
+        let start = Instant::now();
+        let reads: [(u64, &[u8]); 3] = [(0, b"a"), (1500, b"\x1b[A"), (250_000, b"\x1b")];
+        for (time, bytes) in reads.iter() {
+            let arrived = start + Duration::from_micros(*time);
+            for (i, b) in bytes.iter().enumerate() {
+                recorder.record(&InputByte { byte: *b, end_of_chunk: i + 1 == bytes.len() }, arrived).unwrap();
+            }
+        }
+        let text = String::from_utf8(recorder.into_inner()).unwrap();
+        let expected = "control 03 - - 7f - - - -\n0 61\n1500 1b5b41\n250000 1b\n";
+        assert_eq!(text, format!("{}\n{}", HEADER, expected));
+        // Reading it back gives the same reads.
+        let recording: Recording = text.parse().unwrap();
+        assert_eq!(recording.control, control);
+        let chunks: Vec<_> = recording.chunks().iter().map(|c| (c.time.as_micros() as u64, c.bytes.as_slice())).collect();
+        assert_eq!(chunks, reads.to_vec());
+    }
+
+    #[test]
+    fn test_recording_errors() {
+        let error = |text: &str| text.parse::<Recording>().map(|_| ()).map_err(|e| (e.line(), e.kind().clone()));
+        assert_eq!(error("# comment\n\n5 0a\n"), Ok(()));
+        assert_eq!(error("5 0a\nx 0a"), Err((2, RecordingErrorKind::BadTime)));
+        assert_eq!(error("5 0a0"), Err((1, RecordingErrorKind::BadBytes)));
+        assert_eq!(error("5 +a"), Err((1, RecordingErrorKind::BadBytes)));
+        assert_eq!(error("5"), Err((1, RecordingErrorKind::BadBytes)));
+        assert_eq!(error("5 0a\n4 0a"), Err((2, RecordingErrorKind::OutOfOrder)));
+        assert_eq!(error("control 03 -"), Err((1, RecordingErrorKind::BadControl)));
+        assert_eq!(format!("{}", "5 zz".parse::<Recording>().unwrap_err()), "line 1: expected a time followed by bytes in hex");
+    }
+
+    #[test]
+    fn test_replay_input() {
+        let options = || InputOptions {
+            escape_timeout: crate::user::EscapeTimeout::Fixed(Duration::from_millis(20)),
+            signal_events: true,
+            ..InputOptions::default()
+        };
+        let ms = Duration::from_millis;
+        let mut recording = Recording::new();
+        recording.control.interrupt = Some(3);
+        // An escape given up on, then one completed by a read just inside the timeout.
+        recording.push(ms(0), b"\x1b");
+        recording.push(ms(100), b"[A\x1b");
+        recording.push(ms(110), b"[B\x03");
+        recording.push(ms(150), b"q");
+        recording.push(ms(160), b"not reached");
+        let expected = vec![Byte(0x1b), Byte(b'['), Byte(b'A'), Normal(DownArrow), Interrupt, Byte(b'q')];
+        let keys: Vec<_> = replay_input(recording.clone(), b"q", options(), Pace::Immediate).collect();
+        assert_eq!(keys, expected);
+        // At a pace, the reads arrive as they would be typed, here with gaps far from the timeout.
+        let start = Instant::now();
+        let keys: Vec<_> = replay_input(recording.clone(), b"q", options(), Pace::Speed(2.0)).collect();
+        assert_eq!(keys, expected);
+        assert!(start.elapsed() >= ms(75));
+        // Without a pace, the recorded gaps decide even right at the timeout.
+        let mut edge = Recording::new();
+        edge.push(ms(0), b"\x1b");
+        edge.push(ms(19), b"[A\x1b");
+        edge.push(ms(39), b"[B");
+        let keys: Vec<_> = replay_input(edge, b"", options(), Pace::Immediate).collect();
+        assert_eq!(keys, vec![Normal(UpArrow), Byte(0x1b), Byte(b'['), Byte(b'B')]);
+        // Dropping a replay stops it without waiting for the rest.
+        recording.push(ms(60_000), b"x");
+        let start = Instant::now();
+        let mut replay = replay_input(recording, b"", options(), Pace::default());
+        assert_eq!(replay.next(), Some(Byte(0x1b)));
+        drop(replay);
+        assert!(start.elapsed() < ms(10_000));
+    }
+}
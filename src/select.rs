@@ -0,0 +1,388 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+};
+use unicode_width::UnicodeWidthStr;
+use crate::{
+    line::{printable, terminal_width, truncate},
+    parse::{
+        ExtendedChar::{self, *},
+        SpecialChar::*,
+    },
+};
+
+/// The number of items shown at once by the lists, which scroll to keep the current item in view.
+pub const PAGE_SIZE: usize = 10;
+
+/**
+  Asks a yes or no question, returning the answer once `y` or `n` is typed. Enter gives `default`
+  if there is one. Returns `None` if Escape is pressed or the input ends, and an error of kind
+  [Interrupted](io::ErrorKind::Interrupted) for Ctrl-C.
+
+  Like the other prompts here, it reads keys from `input`, such as a
+  [parsed_input](crate::user::parsed_input) session, and draws on `out` from the line the cursor
+  is on, leaving just the prompt and the answer behind once it is done.
+  */
+pub fn confirm<I: IntoIterator<Item = ExtendedChar>, W: Write>(input: I, out: &mut W, prompt: &str, default: Option<bool>) -> io::Result<Option<bool>> {
+    let hint = match default {
+        Some(true) => "[Y/n]",
+        Some(false) => "[y/N]",
+        None => "[y/n]",
+    };
+    let header = format!("{} {} ", prompt, hint);
+    let mut screen = Inline::new(out);
+    screen.draw(&header, &[] as &[&str])?;
+    for key in input {
+        let answer = match key {
+            Byte(b'y') | Byte(b'Y') => Some(true),
+            Byte(b'n') | Byte(b'N') => Some(false),
+            Byte(b'\r') | Byte(b'\n') => default,
+            _ => match cancel(key) {
+                Some(result) => return screen.finish(&header, "").and(result.map(|_| None)),
+                None => None,
+            },
+        };
+        if let Some(answer) = answer {
+            screen.finish(&header, if answer { "yes" } else { "no" })?;
+            return Ok(Some(answer));
+        }
+    }
+    screen.finish(&header, "")?;
+    Ok(None)
+}
+
+/**
+  Lets one of `items` be picked with the arrow keys, starting from `default`, and returns its
+  index once Enter is pressed. Up and Down (or Ctrl-P and Ctrl-N) wrap around at the ends, while
+  PageUp, PageDown, Home and End jump through the list. Escape, Ctrl-C and the end of the input
+  are handled as for [confirm].
+  */
+pub fn select<I: IntoIterator<Item = ExtendedChar>, W: Write, S: AsRef<str>>(input: I, out: &mut W, prompt: &str, items: &[S], default: usize) -> io::Result<Option<usize>> {
+    let mut list = List::new(items.len(), default);
+    let mut screen = Inline::new(out);
+    screen.draw(prompt, &list.rows(items, |_| ""))?;
+    for key in input {
+        match key {
+            Byte(b'\r') | Byte(b'\n') if !items.is_empty() => {
+                screen.finish(prompt, items[list.current].as_ref())?;
+                return Ok(Some(list.current));
+            },
+            _ => {
+                if let Some(result) = cancel(key) {
+                    return screen.finish(prompt, "").and(result.map(|_| None));
+                }
+                list.navigate(key);
+            },
+        }
+        screen.draw(prompt, &list.rows(items, |_| ""))?;
+    }
+    screen.finish(prompt, "")?;
+    Ok(None)
+}
+
+/**
+  Lets any number of `items` be chosen, with Space toggling the current one, and returns the
+  indexes of the chosen items in order once Enter is pressed. The items set in `chosen` start out
+  chosen. The keys are otherwise the same as for [select].
+  */
+pub fn multi_select<I: IntoIterator<Item = ExtendedChar>, W: Write, S: AsRef<str>>(input: I, out: &mut W, prompt: &str, items: &[S], chosen: &[bool]) -> io::Result<Option<Vec<usize>>> {
+    let mut chosen: Vec<bool> = (0..items.len()).map(|i| chosen.get(i).copied().unwrap_or(false)).collect();
+    let mut list = List::new(items.len(), 0);
+    let mut screen = Inline::new(out);
+    let mark = |chosen: &[bool], i: usize| if chosen[i] { "[x] " } else { "[ ] " };
+    screen.draw(prompt, &list.rows(items, |i| mark(&chosen, i)))?;
+    for key in input {
+        match key {
+            Byte(b' ') if !items.is_empty() => { chosen[list.current] = !chosen[list.current]; },
+            Byte(b'\r') | Byte(b'\n') => {
+                let indexes: Vec<usize> = (0..items.len()).filter(|&i| chosen[i]).collect();
+                let names: Vec<&str> = indexes.iter().map(|&i| items[i].as_ref()).collect();
+                screen.finish(prompt, &names.join(", "))?;
+                return Ok(Some(indexes));
+            },
+            _ => {
+                if let Some(result) = cancel(key) {
+                    return screen.finish(prompt, "").and(result.map(|_| None));
+                }
+                list.navigate(key);
+            },
+        }
+        screen.draw(prompt, &list.rows(items, |i| mark(&chosen, i)))?;
+    }
+    screen.finish(prompt, "")?;
+    Ok(None)
+}
+
+/**
+  Lets one of `items` be picked by typing part of it, and returns its index once Enter is pressed.
+  The list only shows the items that contain the typed characters in order, though not
+  necessarily next to each other, with the closest matches first. Backspace and Ctrl-U edit what
+  was typed, and the other keys are the same as for [select].
+  */
+pub fn fuzzy_select<I: IntoIterator<Item = ExtendedChar>, W: Write, S: AsRef<str>>(input: I, out: &mut W, prompt: &str, items: &[S]) -> io::Result<Option<usize>> {
+    let mut query = String::new();
+    let mut matches: Vec<usize> = (0..items.len()).collect();
+    let mut list = List::new(matches.len(), 0);
+    let mut screen = Inline::new(out);
+    let header = |query: &str| format!("{} {}", prompt, query);
+    screen.draw(&header(&query), &list.rows(&select_items(items, &matches), |_| ""))?;
+    for key in input {
+        let mut filter = false;
+        match key {
+            Byte(b'\r') | Byte(b'\n') if !matches.is_empty() => {
+                let index = matches[list.current];
+                screen.finish(prompt, items[index].as_ref())?;
+                return Ok(Some(index));
+            },
+            Byte(0x7f) | Byte(8) | Normal(Backspace) => { filter = query.pop().is_some(); },
+            Byte(21) | KillLine => {
+                query.clear();
+                filter = true;
+            },
+            _ => {
+                if let Some(result) = cancel(key) {
+                    return screen.finish(prompt, "").and(result.map(|_| None));
+                }
+                match printable(key) {
+                    Some(c) => {
+                        query.push(c);
+                        filter = true;
+                    },
+                    None => { list.navigate(key); },
+                }
+            },
+        }
+        if filter {
+            matches = fuzzy_filter(items, &query);
+            list = List::new(matches.len(), 0);
+        }
+        screen.draw(&header(&query), &list.rows(&select_items(items, &matches), |_| ""))?;
+    }
+    screen.finish(prompt, "")?;
+    Ok(None)
+}
+
+/// Returns `Some` with the result to give if `key` cancels a prompt.
+fn cancel(key: ExtendedChar) -> Option<io::Result<()>> {
+    match key {
+        Byte(0x1b) => Some(Ok(())),
+        Byte(3) | Interrupt => Some(Err(io::ErrorKind::Interrupted.into())),
+        _ => None,
+    }
+}
+
+fn select_items<'a, S: AsRef<str>>(items: &'a [S], indexes: &[usize]) -> Vec<&'a str> {
+    indexes.iter().map(|&i| items[i].as_ref()).collect()
+}
+
+/**
+  Returns the indexes of the items that contain the characters of `query` in order, ignoring case,
+  with the best matches first and the rest in their original order.
+  */
+fn fuzzy_filter<S: AsRef<str>>(items: &[S], query: &str) -> Vec<usize> {
+    let mut scored: Vec<(i64, usize)> = items.iter().enumerate()
+        .filter_map(|(i, item)| fuzzy_score(query, item.as_ref()).map(|score| (score, i)))
+        .collect();
+    scored.sort_by_key(|&(score, i)| (-score, i));
+    scored.into_iter().map(|(_, i)| i).collect()
+}
+
+/**
+  Scores how well `text` matches `query`, or returns `None` if it doesn't contain its characters
+  in order. Characters matched one after another and at the start of words count for more, and
+  matches that start later count for less.
+  */
+fn fuzzy_score(query: &str, text: &str) -> Option<i64> {
+    let mut query = query.chars().flat_map(char::to_lowercase).peekable();
+    let mut score = 0;
+    let mut last: Option<usize> = None;
+    let mut previous = None;
+    for (i, c) in text.chars().enumerate() {
+        let q = match query.peek() {
+            Some(&q) => q,
+            None => break,
+        };
+        if c.to_lowercase().eq(std::iter::once(q)) {
+            score += 1;
+            match last {
+                Some(last) if last + 1 == i => { score += 5; },
+                None => { score -= i as i64; },
+                _ => {},
+            }
+            if !previous.is_some_and(char::is_alphanumeric) {
+                score += 3;
+            }
+            last = Some(i);
+            query.next();
+        }
+        previous = Some(c);
+    }
+    if query.peek().is_some() { None } else { Some(score) }
+}
+
+/// The position in a list of items and the page of it that is shown.
+struct List {
+    len: usize,
+    current: usize,
+    top: usize,
+}
+
+impl List {
+    fn new(len: usize, current: usize) -> Self {
+        let mut list = List { len, current: current.min(len.saturating_sub(1)), top: 0 };
+        list.scroll();
+        list
+    }
+
+    fn navigate(&mut self, key: ExtendedChar) {
+        if self.len == 0 { return; }
+        self.current = match key {
+            Byte(16) | Normal(UpArrow) => (self.current + self.len - 1) % self.len,
+            Byte(14) | Normal(DownArrow) => (self.current + 1) % self.len,
+            Normal(PageUp) => self.current.saturating_sub(PAGE_SIZE),
+            Normal(PageDown) => (self.current + PAGE_SIZE).min(self.len - 1),
+            Normal(Home) => 0,
+            Normal(End) => self.len - 1,
+            _ => self.current,
+        };
+        self.scroll();
+    }
+
+    /// Moves the page so that the current item is on it.
+    fn scroll(&mut self) {
+        if self.current < self.top {
+            self.top = self.current;
+        }
+        else if self.current >= self.top + PAGE_SIZE {
+            self.top = self.current + 1 - PAGE_SIZE;
+        }
+    }
+
+    /// Returns the rows that show the current page of `items`, each with a mark given by `mark`.
+    fn rows<'a, S: AsRef<str>, F: Fn(usize) -> &'a str>(&self, items: &[S], mark: F) -> Vec<String> {
+        let end = (self.top + PAGE_SIZE).min(self.len);
+        (self.top..end).map(|i| {
+            let pointer = if i == self.current { "> " } else { "  " };
+            format!("{}{}{}", pointer, mark(i), items[i].as_ref())
+        }).collect()
+    }
+}
+
+/**
+  Draws a prompt and the rows below it from the line the cursor is on, replacing what was drawn
+  there before, and leaves the cursor at the end of the prompt.
+  */
+struct Inline<'a, W: Write> {
+    out: &'a mut W,
+    width: usize,
+}
+
+impl<'a, W: Write> Inline<'a, W> {
+    fn new(out: &'a mut W) -> Self {
+        Inline { out, width: terminal_width() }
+    }
+
+    fn draw<S: AsRef<str>>(&mut self, header: &str, rows: &[S]) -> io::Result<()> {
+        let mut text = String::new();
+        text.push('\r');
+        text.push_str(header);
+        text.push_str("\x1b[J");
+        for row in rows {
+            text.push_str("\r\n");
+            // The rows are cut short rather than wrapped, so that each takes up just one.
+            text.push_str(&truncate(row.as_ref(), self.width.saturating_sub(1)));
+        }
+        if !rows.is_empty() {
+            let _ = write!(text, "\x1b[{}A\r", rows.len());
+            let column = header.width();
+            if column > 0 {
+                let _ = write!(text, "\x1b[{}C", column);
+            }
+        }
+        self.out.write_all(text.as_bytes())?;
+        self.out.flush()
+    }
+
+    /// Clears the rows and ends the prompt's line with the answer.
+    fn finish(&mut self, header: &str, answer: &str) -> io::Result<()> {
+        let line = format!("{} {}", header.trim_end(), answer);
+        write!(self.out, "\r{}\x1b[J\r\n", line.trim_end())?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::select::*;
+
+    fn keys(s: &str) -> Vec<ExtendedChar> {
+        s.bytes().map(Byte).collect()
+    }
+
+    #[test]
+    fn test_confirm() {
+        let mut out = Vec::new();
+        assert_eq!(confirm(keys("xy"), &mut out, "Go?", None).unwrap(), Some(true));
+        assert_eq!(String::from_utf8(out).unwrap(), "\rGo? [y/n] \x1b[J\rGo? [y/n] yes\x1b[J\r\n");
+        assert_eq!(confirm(keys("\r"), &mut Vec::new(), "Go?", Some(false)).unwrap(), Some(false));
+        assert_eq!(confirm(keys("\rN"), &mut Vec::new(), "Go?", None).unwrap(), Some(false));
+        assert_eq!(confirm(keys("\x1b"), &mut Vec::new(), "Go?", Some(true)).unwrap(), None);
+        assert_eq!(confirm(keys("\x03"), &mut Vec::new(), "Go?", None).unwrap_err().kind(), io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn test_select() {
+        let items = ["red", "green", "blue"];
+        let mut out = Vec::new();
+        assert_eq!(select(vec![Normal(DownArrow), Byte(b'\r')], &mut out, "Colour?", &items, 0).unwrap(), Some(1));
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("\rColour?\x1b[J\r\n> red\r\n  green\r\n  blue\x1b[3A\r\x1b[7C"));
+        assert!(out.ends_with("\rColour? green\x1b[J\r\n"));
+        assert_eq!(select(vec![Normal(UpArrow), Byte(b'\r')], &mut Vec::new(), "", &items, 0).unwrap(), Some(2));
+        assert_eq!(select(vec![Normal(End), Byte(16), Byte(b'\r')], &mut Vec::new(), "", &items, 0).unwrap(), Some(1));
+        assert_eq!(select(vec![Byte(b'\r')], &mut Vec::new(), "", &items, 5).unwrap(), Some(2));
+        assert_eq!(select(vec![Byte(b'\r')], &mut Vec::new(), "", &[] as &[&str], 0).unwrap(), None);
+        // Paging scrolls the list to keep the current item shown.
+        let many: Vec<String> = (0..25).map(|i| i.to_string()).collect();
+        let mut list = List::new(many.len(), 0);
+        list.navigate(Normal(PageDown));
+        list.navigate(Normal(PageDown));
+        assert_eq!((list.current, list.top), (20, 11));
+        assert_eq!(list.rows(&many, |_| "").last().unwrap(), "> 20");
+        list.navigate(Normal(PageUp));
+        list.navigate(Normal(Home));
+        assert_eq!((list.current, list.top), (0, 0));
+        list.navigate(Normal(UpArrow));
+        assert_eq!((list.current, list.top), (24, 15));
+    }
+
+    #[test]
+    fn test_multi_select() {
+        let items = ["a", "b", "c"];
+        let mut out = Vec::new();
+        let chosen = multi_select(vec![Byte(b' '), Normal(DownArrow), Normal(DownArrow), Byte(b' '), Byte(b'\r')], &mut out, "Pick:", &items, &[false, true]);
+        assert_eq!(chosen.unwrap(), Some(vec![0, 1, 2]));
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("\rPick:\x1b[J\r\n> [ ] a\r\n  [x] b\r\n  [ ] c\x1b[3A\r\x1b[5C"));
+        assert!(out.ends_with("\rPick: a, b, c\x1b[J\r\n"));
+        assert_eq!(multi_select(keys(" \x1b"), &mut Vec::new(), "", &items, &[]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_fuzzy_select() {
+        let items = ["cargo build", "cargo test", "git status", "git stash"];
+        assert_eq!(fuzzy_filter(&items, "gst"), vec![2, 3, 1]);
+        assert_eq!(fuzzy_filter(&items, "cat"), vec![1]);
+        assert_eq!(fuzzy_filter(&items, "CB"), vec![0]);
+        assert_eq!(fuzzy_filter(&items, ""), vec![0, 1, 2, 3]);
+        assert_eq!(fuzzy_filter(&items, "xyz"), Vec::<usize>::new());
+        assert_eq!(fuzzy_select(keys("gsh\r"), &mut Vec::new(), "Run:", &items).unwrap(), Some(3));
+        assert_eq!(fuzzy_select(keys("gx\x7f\r"), &mut Vec::new(), "Run:", &items).unwrap(), Some(2));
+        assert_eq!(fuzzy_select(keys("xyz\r\x15\r"), &mut Vec::new(), "Run:", &items).unwrap(), Some(0));
+        let mut out = Vec::new();
+        fuzzy_select(vec![Byte(b't'), Normal(DownArrow), Byte(b'\r')], &mut out, "Run:", &items).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\rRun: t\x1b[J\r\n> git status\r\n  git stash\r\n  cargo test\x1b[3A\r\x1b[6C"));
+        assert!(out.ends_with("\rRun: git stash\x1b[J\r\n"));
+    }
+}
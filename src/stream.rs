@@ -0,0 +1,308 @@
+use std::{
+    io,
+    os::unix::io::{AsRawFd, RawFd},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+    time::Instant,
+};
+use futures_core::Stream;
+use crate::{
+    parse::ExtendedChar,
+    pipe::{NonBlocking, SelfPipe, poll_timeout},
+    poll::{PollInput, poll_input},
+};
+
+/**
+  An asynchronous stream of parsed terminal input that works with any executor.
+
+  The terminal is only ever read from inside [poll_next](Stream::poll_next), and only after
+  `poll(2)` reports that input is waiting, so the stream never blocks the task polling it. While the
+  stream is pending, a small watcher thread waits on the terminal and wakes the task when input
+  arrives, or when the escape sequence timeout expires so that a lone escape is delivered with the
+  same timing as [user_handler](crate::user::user_handler).
+  */
+pub struct InputStream {
+    input: PollInput,
+    watch: Arc<Mutex<WatchState>>,
+    pipe: SelfPipe,
+    watcher: Option<thread::JoinHandle<()>>,
+    _nonblocking: NonBlocking,
+}
+
+#[derive(Default)]
+struct WatchState {
+    waker: Option<Waker>,
+    deadline: Option<Instant>,
+    closed: bool,
+}
+
+/**
+  Puts stdin into raw mode and returns an [InputStream] of the parsed characters. The original
+  terminal settings are restored when the stream is dropped.
+  */
+pub fn input_stream(quit_sequence: &'static [u8]) -> io::Result<InputStream> {
+    InputStream::new(poll_input(quit_sequence)?)
+}
+
+impl InputStream {
+    /**
+      Creates a stream of the characters read by `input`. The terminal is put into non-blocking
+      mode, so a spurious wakeup can never stall a read, and its flags are put back on drop.
+      */
+    pub fn new(input: PollInput) -> io::Result<Self> {
+        let nonblocking = NonBlocking::set(input.as_raw_fd())?;
+        let pipe = SelfPipe::new()?;
+        let watch = Arc::new(Mutex::new(WatchState::default()));
+        let watcher = {
+            let (fd, wake_fd, watch) = (input.as_raw_fd(), pipe.read_fd(), watch.clone());
+            thread::spawn(move || {watch_handler(fd, wake_fd, &watch);})
+        };
+        Ok(InputStream {
+            input,
+            watch,
+            pipe,
+            watcher: Some(watcher),
+            _nonblocking: nonblocking,
+        })
+    }
+}
+
+impl Stream for InputStream {
+    type Item = ExtendedChar;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some(e) = this.input.try_next() {
+            return Poll::Ready(Some(e));
+        }
+        if this.input.is_done() {
+            return Poll::Ready(None);
+        }
+        {
+            let mut state = this.watch.lock().unwrap();
+            state.waker = Some(cx.waker().clone());
+            state.deadline = this.input.escape_deadline();
+        }
+        this.pipe.notify();
+        Poll::Pending
+    }
+}
+
+impl AsRawFd for InputStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.input.as_raw_fd()
+    }
+}
+
+impl Drop for InputStream {
+    fn drop(&mut self) {
+        self.watch.lock().unwrap().closed = true;
+        self.pipe.notify();
+        if let Some(handle) = self.watcher.take() {
+            handle.join().unwrap();
+        }
+    }
+}
+
+/**
+  Waits for input on `fd` on behalf of a pending [InputStream]. Whenever the stream registers a
+  waker it writes to the self-pipe behind `wake_fd`, which makes this re-read the shared state.
+  Once input is ready or the escape deadline has passed, the waker is woken and dropped, and only
+  the self-pipe is watched until the stream registers a new one.
+  */
+fn watch_handler(fd: RawFd, wake_fd: RawFd, watch: &Mutex<WatchState>) {
+    loop {
+        let (armed, deadline) = {
+            let state = watch.lock().unwrap();
+            if state.closed { break; }
+            (state.waker.is_some(), state.deadline)
+        };
+        let mut fds = [
+            libc::pollfd { fd: wake_fd, events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd, events: libc::POLLIN, revents: 0 },
+        ];
+        let (count, timeout) = if armed {
+            (2, deadline.map(|d| d.saturating_duration_since(Instant::now())))
+        }
+        else {
+            (1, None)
+        };
+        let result = unsafe { libc::poll(fds.as_mut_ptr(), count, poll_timeout(timeout)) };
+        if result < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+            continue;
+        }
+        if result >= 0 && fds[0].revents != 0 {
+            SelfPipe::drain(wake_fd);
+            continue;
+        }
+        // Either input is ready, the escape deadline has passed, or polling failed. In every case
+        // the stream needs to look at the terminal itself.
+        if let Some(waker) = watch.lock().unwrap().waker.take() {
+            waker.wake();
+        }
+        if result < 0 { break; }
+    }
+}
+
+/**
+  An asynchronous stream of parsed terminal input driven by the tokio reactor, for applications
+  that already run on tokio. It behaves like [InputStream] but waits on the terminal with an
+  [AsyncFd](tokio::io::unix::AsyncFd) and a tokio timer instead of a watcher thread.
+  */
+#[cfg(feature = "tokio")]
+pub struct TokioInputStream {
+    input: tokio::io::unix::AsyncFd<PollInput>,
+    sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+    _nonblocking: NonBlocking,
+}
+
+/**
+  Puts stdin into raw mode and returns a [TokioInputStream] of the parsed characters. This must be
+  called from within a tokio runtime.
+  */
+#[cfg(feature = "tokio")]
+pub fn tokio_input_stream(quit_sequence: &'static [u8]) -> io::Result<TokioInputStream> {
+    TokioInputStream::new(poll_input(quit_sequence)?)
+}
+
+#[cfg(feature = "tokio")]
+impl TokioInputStream {
+    /**
+      Creates a stream of the characters read by `input`, putting the terminal into non-blocking
+      mode until the stream is dropped. This must be called from within a tokio runtime.
+      */
+    pub fn new(input: PollInput) -> io::Result<Self> {
+        use tokio::io::{Interest, unix::AsyncFd};
+
+        let nonblocking = NonBlocking::set(input.as_raw_fd())?;
+        // The PollInput owns stdin's terminal settings and always reports the same descriptor,
+        // which stays open for the life of the process.
+        let input = unsafe { AsyncFd::register_with_interest(input, Interest::READABLE) }
+            .map_err(|e| e.into_parts().1)?;
+        Ok(TokioInputStream {
+            input,
+            sleep: None,
+            _nonblocking: nonblocking,
+        })
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Stream for TokioInputStream {
+    type Item = ExtendedChar;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        use std::future::Future;
+
+        let this = self.get_mut();
+        loop {
+            if let Some(e) = this.input.get_mut().try_next() {
+                return Poll::Ready(Some(e));
+            }
+            if this.input.get_ref().is_done() {
+                return Poll::Ready(None);
+            }
+            if let Some(deadline) = this.input.get_ref().escape_deadline() {
+                let deadline = tokio::time::Instant::from_std(deadline);
+                let sleep = this.sleep.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+                sleep.as_mut().reset(deadline);
+                if sleep.as_mut().poll(cx).is_ready() {
+                    continue;
+                }
+            }
+            match this.input.poll_read_ready(cx) {
+                Poll::Ready(Ok(mut guard)) => { guard.clear_ready(); },
+                Poll::Ready(Err(_)) => { return Poll::Ready(None); },
+                Poll::Pending => { return Poll::Pending; },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        task::Wake,
+        time::Duration,
+    };
+    use crate::{
+        parse::SpecialChar::*,
+        poll::poll_input_from,
+        stream::*,
+        user::{InputOptions, Pty, lock_saved_terminal},
+    };
+
+    fn input(pty: &Pty) -> PollInput {
+        let options = InputOptions { job_control: false, ..InputOptions::default() };
+        poll_input_from(pty.slave, &[], options).unwrap()
+    }
+
+    fn nonblocking(pty: &Pty) -> bool {
+        unsafe { libc::fcntl(pty.slave, libc::F_GETFL) & libc::O_NONBLOCK != 0 }
+    }
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Runs `future` on this thread, parking between polls until it is woken.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Arc::new(ThreadWaker(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    fn next<S: Stream + Unpin>(stream: &mut S) -> impl Future<Output = Option<S::Item>> + '_ {
+        std::future::poll_fn(move |cx| Pin::new(&mut *stream).poll_next(cx))
+    }
+
+    #[test]
+    fn test_input_stream() {
+        let _lock = lock_saved_terminal();
+        let pty = Pty::open();
+        let mut stream = InputStream::new(input(&pty)).unwrap();
+        assert!(nonblocking(&pty));
+        pty.type_keys(b"a\x1b[A");
+        assert_eq!(block_on(next(&mut stream)), Some(ExtendedChar::Byte(b'a')));
+        assert_eq!(block_on(next(&mut stream)), Some(ExtendedChar::Normal(UpArrow)));
+        // A lone escape is woken for once the timeout passes.
+        pty.type_keys(b"\x1b");
+        let started = Instant::now();
+        assert_eq!(block_on(next(&mut stream)), Some(ExtendedChar::Byte(0x1b)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        drop(stream);
+        assert!(!nonblocking(&pty));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_tokio_input_stream() {
+        let _lock = lock_saved_terminal();
+        let pty = Pty::open();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let mut stream = TokioInputStream::new(input(&pty)).unwrap();
+            assert!(nonblocking(&pty));
+            pty.type_keys(b"\x1b[B\x1b");
+            assert_eq!(next(&mut stream).await, Some(ExtendedChar::Normal(DownArrow)));
+            let started = Instant::now();
+            assert_eq!(next(&mut stream).await, Some(ExtendedChar::Byte(0x1b)));
+            assert!(started.elapsed() < Duration::from_secs(1));
+        });
+        drop(runtime);
+        assert!(!nonblocking(&pty));
+    }
+}
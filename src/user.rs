@@ -1,14 +1,23 @@
 //extern crate termios;
 
 use std::{
+    cell::UnsafeCell,
     io::{
         self,
-        Read,
+        Write,
+    },
+    mem::{self, MaybeUninit},
+    os::unix::io::RawFd,
+    panic,
+    ptr,
+    sync::{
+        Arc,
+        Once,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        mpsc,
     },
-    str::FromStr,
-    sync::mpsc,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 use termios::{
     Termios,
@@ -16,39 +25,200 @@ use termios::{
     tcsetattr,
 };
 use crate::{
-    parse::{ExtendedChar, encode_extended_string},
+    parse::{Decoder, ExtendedChar},
+    pipe::{SelfPipe, poll_timeout},
 };
 
 pub struct StdinIterator<C> {
     join_handle: Option<thread::JoinHandle<()>>,
     receiver: mpsc::Receiver<Option<C>>,
+    stop: Option<Arc<SelfPipe>>,
     done: bool,
 }
 
+/**
+  How long to wait after a byte that might be the start of an escape sequence (such as a lone ESC)
+  before deciding that the rest of the sequence isn't coming and returning the input as-is.
+
+  A short timeout makes the Escape key responsive, which matters for modal editors, but over a slow
+  connection the bytes of a single key can arrive far enough apart that the sequence gets split.
+  The adaptive mode measures the delays inside sequences that were recognized and keeps the timeout
+  comfortably above them.
+  */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EscapeTimeout {
+    /// Always wait for the given time.
+    Fixed(Duration),
+    /// Start at `initial` and adjust to the measured delays, staying between `min` and `max`.
+    Adaptive {
+        initial: Duration,
+        min: Duration,
+        max: Duration,
+    },
+}
+
+impl Default for EscapeTimeout {
+    fn default() -> Self {
+        EscapeTimeout::Fixed(TIMEOUT)
+    }
+}
+
+/// Settings for a parsed input session.
+#[derive(Clone, Debug, Default)]
+pub struct InputOptions {
+    /**
+      How long to wait for the rest of an escape sequence. The default is a
+      [Fixed](EscapeTimeout::Fixed) 100ms; [Adaptive](EscapeTimeout::Adaptive) learns from the
+      delays inside the sequences that arrive, for connections too slow for a fixed timeout.
+      */
+    pub escape_timeout: EscapeTimeout,
+    /// The terminal mode to read in.
+    pub mode: TermMode,
+    /**
+      When the terminal's suspend character (usually Ctrl-Z) is typed, [suspend] the process as the
+      shell would if signals were enabled. The session reports `Suspended` just before stopping and
+      `Resumed` once it has the terminal back, so the application knows to redraw.
+      */
+    pub job_control: bool,
+    /**
+      Report the terminal's interrupt, quit and suspend characters as `Interrupt`, `Quit` and
+      `Suspend` rather than as the bytes themselves, leaving it to the application to decide what
+      they mean. The characters are the ones the terminal was set up with, so they follow `stty`.
+      With [job_control](InputOptions::job_control) on as well, the suspend character suspends.
+      */
+    pub signal_events: bool,
+    /**
+      Report the terminal's editing characters the way canonical mode would treat them: the erase
+      character as `Normal(Backspace)`, whether it is DEL or Ctrl-H, and the line kill, word erase
+      and end of file characters as `KillLine`, `DeleteWord` and `EndOfFile`. The literal next
+      character (usually Ctrl-V) is swallowed and the byte after it is reported as a plain `Byte`.
+
+      None of these, nor the characters for [signal_events](InputOptions::signal_events) and
+      [job_control](InputOptions::job_control), are treated specially between `PasteStart` and
+      `PasteEnd`, so pasted text always arrives as it was pasted.
+      */
+    pub editing_events: bool,
+    /**
+      Ends the session when the parsed characters match, in addition to the quit sequence of raw
+      bytes. The characters up to and including the one that completes the match are delivered.
+      */
+    pub quit: Option<QuitTrigger>,
+}
+
+/**
+  What ends a parsed input session, in terms of the parsed characters rather than the bytes the
+  terminal sends for them, so that for instance F10 quits however the terminal encodes it.
+  */
+#[derive(Clone, Debug)]
+pub enum QuitTrigger {
+    /// The session ends once these characters have been typed in a row.
+    Keys(Vec<ExtendedChar>),
+    /// The session ends at the first character for which this returns true.
+    Predicate(fn(&ExtendedChar) -> bool),
+}
+
 pub fn raw_input(quit_sequence: &'static [u8]) -> StdinIterator<u8> {
+    raw_input_with(quit_sequence, TermMode::Raw)
+}
+
+pub fn raw_input_with(quit_sequence: &'static [u8], mode: TermMode) -> StdinIterator<u8> {
     // Set up channel
     let (sender, receiver) = mpsc::channel();
-    let join_handle = Some(thread::spawn(move || {raw_handler(sender, &quit_sequence);}));
+    let join_handle = Some(thread::spawn(move || {raw_handler_with(sender, quit_sequence, mode);}));
     StdinIterator {
         join_handle,
         receiver,
+        stop: None,
         done: false,
     }
 }
 
 pub fn parsed_input(quit_sequence: &'static [u8]) -> StdinIterator<ExtendedChar> {
-    // Set up channel
-    let (sender, receiver) = mpsc::channel();
-    let join_handle = Some(thread::spawn(move || {user_handler(sender, &quit_sequence);}));
-    StdinIterator {
-        join_handle,
-        receiver,
-        done: false,
+    parsed_input_with(quit_sequence, InputOptions::default())
+}
+
+pub fn parsed_input_with(quit_sequence: &'static [u8], options: InputOptions) -> StdinIterator<ExtendedChar> {
+    StdinIterator::spawn(move |sender, stop| {user_session(sender, quit_sequence, options, stop, ByteSource::Terminal, None);})
+}
+
+impl StdinIterator<ExtendedChar> {
+    /**
+      Runs `session` on its own thread, sending characters to the iterator. The session is given
+      the pipe that is notified when the iterator is dropped, if one could be made.
+      */
+    pub(crate) fn spawn<F: FnOnce(mpsc::Sender<Option<ExtendedChar>>, Option<Arc<SelfPipe>>) + Send + 'static>(session: F) -> Self {
+        // Set up channel
+        let (sender, receiver) = mpsc::channel();
+        let stop = SelfPipe::new().ok().map(Arc::new);
+        let handler_stop = stop.clone();
+        let join_handle = Some(thread::spawn(move || {session(sender, handler_stop);}));
+        StdinIterator {
+            join_handle,
+            receiver,
+            stop,
+            done: false,
+        }
+    }
+}
+
+impl<C> StdinIterator<C> {
+    /**
+      Returns the next input item if one is already available, without waiting. A return value of
+      `None` means either that nothing is ready yet or that the input has finished; use [is_done]
+      to tell them apart.
+
+      [is_done]: StdinIterator::is_done
+      */
+    pub fn try_next(&mut self) -> Option<C> {
+        if self.done { return None; }
+        match self.receiver.try_recv() {
+            Ok(Some(c)) => Some(c),
+            Err(mpsc::TryRecvError::Empty) => None,
+            _ => {
+                self.done = true;
+                None
+            },
+        }
+    }
+
+    /**
+      Waits up to `timeout` for the next input item. Returns `None` if the timeout expires or the
+      input has finished.
+      */
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<C> {
+        if self.done { return None; }
+        match self.receiver.recv_timeout(timeout) {
+            Ok(Some(c)) => Some(c),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            _ => {
+                self.done = true;
+                None
+            },
+        }
+    }
+
+    /**
+      Waits until `deadline` for the next input item. A deadline that has already passed behaves
+      like [try_next].
+
+      [try_next]: StdinIterator::try_next
+      */
+    pub fn next_deadline(&mut self, deadline: Instant) -> Option<C> {
+        self.next_timeout(deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Returns true once the input has finished and no more items will be returned.
+    pub fn is_done(&self) -> bool {
+        self.done
     }
 }
 
 impl<C> Drop for StdinIterator<C> {
     fn drop(&mut self) {
+        // Stop reading, in case the iterator is dropped before the input has finished.
+        if let Some(stop) = &self.stop {
+            stop.notify();
+        }
         if let Some(handle) = self.join_handle.take() {
             handle.join().unwrap();
         }
@@ -74,7 +244,7 @@ impl<C> Iterator for StdinIterator<C> {
   This compares the first value against a partial portion of the second input value and returns
   true if it is an exact match.
   */
-fn substr_cmp(buf: &[u8], seq: &[u8]) -> bool {
+fn substr_cmp<T: PartialEq>(buf: &[T], seq: &[T]) -> bool {
     if seq.len() < buf.len() { return false; }
     let mut i = 0;
     for b in buf.iter() {
@@ -88,7 +258,7 @@ fn substr_cmp(buf: &[u8], seq: &[u8]) -> bool {
     true
 }
 
-pub fn check_quit_buffer(c: u8, buf: &mut Vec<u8>, seq: &[u8]) -> bool {
+pub fn check_quit_buffer<T: PartialEq + Copy>(c: T, buf: &mut Vec<T>, seq: &[T]) -> bool {
     let i = buf.len();
     if i >= seq.len() { return false; }
     buf.push(c);
@@ -109,105 +279,1191 @@ pub fn check_quit_buffer(c: u8, buf: &mut Vec<u8>, seq: &[u8]) -> bool {
     }
 }
 
-static TIMEOUT: Duration = Duration::from_millis(100);
-pub fn user_handler<T: From<Option<ExtendedChar>>>(to_main: mpsc::Sender<T>, quit_sequence: &'static [u8]) {
-    let (to_user, from_stdin) = mpsc::channel();
-    let stdin_quit = quit_sequence.clone();
-    let stdin_thread = thread::spawn(move || {stdin_handler(to_user, &stdin_quit);});
-    let mut quit_buf = Vec::new();
+pub(crate) static TIMEOUT: Duration = Duration::from_millis(100);
 
-    let mut input_buffer = Vec::new();
-    loop {
-        let b = if input_buffer.is_empty() {
-            from_stdin.recv().unwrap()
+/**
+  Keeps track of the escape sequence timeout for an input session. In the adaptive mode it keeps a
+  smoothed average and variation of the delays between reads that continue a sequence, much like a
+  TCP retransmission timer, and only learns from sequences that turned out to be recognized, or
+  that would have been if it had waited longer.
+  */
+#[derive(Clone, Debug)]
+pub struct EscapeTimer {
+    mode: EscapeTimeout,
+    average: Option<Duration>,
+    variation: Duration,
+    last_input: Option<Instant>,
+    gaps: Vec<Duration>,
+}
+
+impl EscapeTimer {
+    pub fn new(mode: EscapeTimeout) -> Self {
+        EscapeTimer {
+            mode,
+            average: None,
+            variation: Duration::ZERO,
+            last_input: None,
+            gaps: Vec::new(),
+        }
+    }
+
+    /// Returns the current escape sequence timeout.
+    pub fn timeout(&self) -> Duration {
+        match self.mode {
+            EscapeTimeout::Fixed(timeout) => timeout,
+            EscapeTimeout::Adaptive { initial, min, max } => match self.average {
+                Some(average) => (average * 2 + self.variation * 4).clamp(min, max),
+                None => initial.clamp(min, max),
+            },
+        }
+    }
+
+    /**
+      Records input arriving at `now`. `continues` is true when the input extends an incomplete
+      sequence, in which case the delay since the previous input is measured.
+      */
+    pub fn input(&mut self, now: Instant, continues: bool) {
+        if continues {
+            if let Some(last) = self.last_input {
+                self.gaps.push(now.saturating_duration_since(last));
+            }
+        }
+        self.last_input = Some(now);
+    }
+
+    /**
+      Records the end of a sequence. The delays measured within it are only learned from if it was
+      `recognized`, since a sequence that timed out tells us nothing about how long to wait.
+      */
+    pub fn sequence_end(&mut self, recognized: bool) {
+        if recognized {
+            for gap in mem::take(&mut self.gaps) {
+                self.learn(gap);
+            }
+        }
+        self.gaps.clear();
+    }
+
+    /**
+      Records that a sequence was given up on too soon, because the read that would have completed
+      it came `gap` after the one before.
+      */
+    pub fn missed(&mut self, gap: Duration) {
+        self.learn(gap);
+    }
+
+    /// Returns how long it has been at `now` since the last input.
+    fn since_input(&self, now: Instant) -> Option<Duration> {
+        self.last_input.map(|last| now.saturating_duration_since(last))
+    }
+
+    fn learn(&mut self, gap: Duration) {
+        if let EscapeTimeout::Adaptive { .. } = self.mode {
+            match self.average {
+                None => {
+                    self.average = Some(gap);
+                    self.variation = gap / 2;
+                },
+                Some(average) => {
+                    self.variation = (self.variation * 3 + (average.max(gap) - average.min(gap))) / 4;
+                    self.average = Some((average * 7 + gap) / 8);
+                },
+            }
+        }
+    }
+}
+
+/**
+  The characters that the terminal was set up to treat specially, read from the `c_cc` array of its
+  original settings. Raw mode turns that handling off, so these arrive as ordinary input.
+  */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ControlChars {
+    pub(crate) interrupt: Option<u8>,
+    pub(crate) quit: Option<u8>,
+    pub(crate) suspend: Option<u8>,
+    pub(crate) erase: Option<u8>,
+    pub(crate) kill: Option<u8>,
+    pub(crate) word_erase: Option<u8>,
+    pub(crate) eof: Option<u8>,
+    pub(crate) literal_next: Option<u8>,
+}
+
+impl ControlChars {
+    pub(crate) fn from_termios(termios: &Termios) -> Self {
+        ControlChars {
+            interrupt: control_char(termios, termios::VINTR),
+            quit: control_char(termios, termios::VQUIT),
+            suspend: control_char(termios, termios::VSUSP),
+            erase: control_char(termios, termios::VERASE),
+            kill: control_char(termios, termios::VKILL),
+            word_erase: control_char(termios, termios::os::target::VWERASE),
+            eof: control_char(termios, termios::VEOF),
+            literal_next: control_char(termios, termios::os::target::VLNEXT),
+        }
+    }
+}
+
+/// Returns the character at `index` in the settings' `c_cc`, unless it has been disabled.
+fn control_char(termios: &Termios, index: usize) -> Option<u8> {
+    match termios.c_cc[index] {
+        libc::_POSIX_VDISABLE => None,
+        c => Some(c),
+    }
+}
+
+/// What an input handler needs to do after a byte has been parsed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Status {
+    Continue,
+    /// The suspend character was typed, so the handler should [suspend] and then report `Resumed`.
+    Suspend,
+    /// The quit sequence has been entered.
+    Quit,
+}
+
+/**
+  The parsing state shared by the input handlers: escape sequence decoding, the escape timeout and
+  watching for the quit sequence.
+  */
+pub(crate) struct InputParser {
+    decoder: Decoder,
+    timer: EscapeTimer,
+    control: ControlChars,
+    job_control: bool,
+    signal_events: bool,
+    editing_events: bool,
+    literal_next: bool,
+    pasting: bool,
+    quit_sequence: &'static [u8],
+    quit_buf: Vec<u8>,
+    quit_trigger: Option<QuitTrigger>,
+    quit_keys: Vec<ExtendedChar>,
+    /// Input that was given up on after the timeout, and the delay before the read after it.
+    missed: Vec<u8>,
+    missed_gap: Option<Duration>,
+}
+
+impl InputParser {
+    pub(crate) fn new(quit_sequence: &'static [u8], options: &InputOptions, control: ControlChars) -> Self {
+        InputParser {
+            decoder: Decoder::new(),
+            timer: EscapeTimer::new(options.escape_timeout),
+            control,
+            job_control: options.job_control,
+            signal_events: options.signal_events,
+            editing_events: options.editing_events,
+            literal_next: false,
+            pasting: false,
+            quit_sequence,
+            quit_buf: Vec::new(),
+            quit_trigger: options.quit.clone(),
+            quit_keys: Vec::new(),
+            missed: Vec::new(),
+            missed_gap: None,
+        }
+    }
+
+    /**
+      Handles one byte of input, adding any completed characters to `out`. `arrived` is the time
+      the read that returned this byte finished, or `None` if the byte came from the same read as
+      the previous one.
+      */
+    pub(crate) fn push(&mut self, b: u8, arrived: Option<Instant>, out: &mut Vec<ExtendedChar>) -> Status {
+        let start = out.len();
+        self.check_missed(b, arrived);
+        if self.take_resumed() {
+            out.push(ExtendedChar::Resumed);
+        }
+        let status = if self.literal_next {
+            self.literal_next = false;
+            out.push(ExtendedChar::Byte(b));
+            Status::Continue
+        }
+        else if self.editing_events && !self.pasting && Some(b) == self.control.literal_next {
+            self.flush_held(out);
+            self.literal_next = true;
+            Status::Continue
         }
         else {
-            match from_stdin.recv_timeout(TIMEOUT) {
-                Ok(c) => c,
-                Err(mpsc::RecvTimeoutError::Timeout) => {
-                    for e in encode_extended_string(&input_buffer) {
-                        to_main.send(Some(e).into()).unwrap();
-                    }
-                    input_buffer.clear();
-                    from_stdin.recv().unwrap()
+            match self.control_event(b).filter(|_| !self.pasting) {
+                Some(event) => {
+                    // Control characters are never part of a sequence, so whatever is held is complete.
+                    self.flush_held(out);
+                    out.push(event);
+                    if event == ExtendedChar::Suspended { Status::Suspend } else { Status::Continue }
                 },
-                Err(e) => {
-                    panic!("Recieved non-timeout error: {:?}", e);
+                None => {
+                    if let Some(now) = arrived {
+                        self.timer.input(now, self.decoder.is_pending());
+                    }
+                    for e in self.decoder.push(b) {
+                        match e {
+                            ExtendedChar::PasteStart => { self.pasting = true; },
+                            ExtendedChar::PasteEnd => { self.pasting = false; },
+                            _ => {},
+                        }
+                        out.push(e);
+                    }
+                    if !self.decoder.is_pending() {
+                        self.timer.sequence_end(self.decoder.matched());
+                    }
+                    Status::Continue
                 },
             }
         };
-        input_buffer.push(b);
-        match String::from_utf8(input_buffer.clone()) {
-            Ok(parse_buffer) => {
-                match ExtendedChar::from_str(&parse_buffer) {
-                    Err(_) => {
-                        for e in encode_extended_string(&input_buffer) {
-                            to_main.send(Some(e).into()).unwrap();
-                        }
-                        input_buffer.clear();
-                    },
-                    Ok(ExtendedChar::Partial) => {},
-                    Ok(scc) => {
-                        to_main.send(Some(scc).into()).unwrap();
-                        input_buffer.clear();
-                    },
+        // Monitor input for quit sequence.
+        let quit = check_quit_buffer(b, &mut self.quit_buf, self.quit_sequence);
+        if quit {
+            self.flush_held(out);
+        }
+        if self.check_quit_trigger(out, start) || quit {
+            return Status::Quit;
+        }
+        status
+    }
+
+    /**
+      Looks for the quit trigger among the characters added to `out` from `start` on, dropping any
+      that come after it. Returns true if it was found.
+      */
+    fn check_quit_trigger(&mut self, out: &mut Vec<ExtendedChar>, start: usize) -> bool {
+        let quit_keys = &mut self.quit_keys;
+        let found = match &self.quit_trigger {
+            None => None,
+            Some(QuitTrigger::Keys(keys)) => {
+                out[start..].iter().position(|e| check_quit_buffer(*e, quit_keys, keys))
+            },
+            Some(QuitTrigger::Predicate(quits)) => out[start..].iter().position(quits),
+        };
+        match found {
+            Some(i) => {
+                out.truncate(start + i + 1);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /**
+      Returns the event for `b` if it is a control character the session is reporting. The earlier
+      entries win when the terminal uses the same character for more than one thing.
+      */
+    fn control_event(&self, b: u8) -> Option<ExtendedChar> {
+        use crate::parse::{ExtendedChar::*, SpecialChar::Backspace};
+
+        let control = &self.control;
+        let events = [
+            (self.job_control, control.suspend, Suspended),
+            (self.signal_events, control.interrupt, Interrupt),
+            (self.signal_events, control.quit, Quit),
+            (self.signal_events, control.suspend, Suspend),
+            (self.editing_events, control.erase, Normal(Backspace)),
+            (self.editing_events, control.kill, KillLine),
+            (self.editing_events, control.word_erase, DeleteWord),
+            (self.editing_events, control.eof, EndOfFile),
+        ];
+        events.iter().find(|(on, c, _)| *on && *c == Some(b)).map(|(_, _, event)| *event)
+    }
+
+    /**
+      Returns true, once, after the process has been continued from a stop that the session didn't
+      initiate itself, such as `kill -TSTP` from another shell.
+      */
+    pub(crate) fn take_resumed(&self) -> bool {
+        self.job_control && RESUMED.swap(false, Ordering::SeqCst)
+    }
+
+    /**
+      Gives up on any partial sequence, adding the held input to `out` as plain characters. This
+      can complete the quit trigger, for instance when it is a lone Escape.
+      */
+    pub(crate) fn flush(&mut self, out: &mut Vec<ExtendedChar>) -> Status {
+        let start = out.len();
+        self.missed = self.decoder.held().to_vec();
+        self.missed_gap = None;
+        self.flush_held(out);
+        if self.check_quit_trigger(out, start) { Status::Quit } else { Status::Continue }
+    }
+
+    /**
+      Follows the read after input was given up on, to see whether it would have completed a
+      sequence. If it would have, the timeout was too short and the timer learns the delay, which
+      it couldn't otherwise do once the timeout is short enough to split every sequence.
+      */
+    fn check_missed(&mut self, b: u8, arrived: Option<Instant>) {
+        if self.missed.is_empty() { return; }
+        match (arrived, self.missed_gap) {
+            (Some(now), None) => { self.missed_gap = self.timer.since_input(now); },
+            (None, Some(_)) => {},
+            // Only the first read after the timeout can carry the rest of the sequence.
+            _ => {
+                self.missed.clear();
+                return;
+            },
+        }
+        self.missed.push(b);
+        match std::str::from_utf8(&self.missed).map(|s| s.parse::<ExtendedChar>()) {
+            Ok(Ok(ExtendedChar::Partial)) => {},
+            Ok(Ok(_)) => {
+                if let Some(gap) = self.missed_gap {
+                    self.timer.missed(gap);
                 }
+                self.missed.clear();
             },
-            Err(_) => {},   // Invalid UTF-8, treat as partial input
+            _ => { self.missed.clear(); },
         }
-        // Monitor input for quit sequence.
-        if check_quit_buffer(b, &mut quit_buf, &quit_sequence) {
-            if 0 != input_buffer.len() {
-                for e in encode_extended_string(&input_buffer) {
-                    to_main.send(Some(e).into()).unwrap();
+    }
+
+    fn flush_held(&mut self, out: &mut Vec<ExtendedChar>) {
+        if self.decoder.is_pending() {
+            self.timer.sequence_end(false);
+            out.extend(self.decoder.flush());
+        }
+    }
+
+    /// Returns true if input is being held waiting for the rest of a sequence.
+    pub(crate) fn is_pending(&self) -> bool {
+        self.decoder.is_pending()
+    }
+
+    pub(crate) fn timeout(&self) -> Duration {
+        self.timer.timeout()
+    }
+}
+
+pub fn user_handler<T: From<Option<ExtendedChar>>>(to_main: mpsc::Sender<T>, quit_sequence: &'static [u8]) {
+    user_handler_with(to_main, quit_sequence, InputOptions::default());
+}
+
+pub fn user_handler_with<T: From<Option<ExtendedChar>>>(to_main: mpsc::Sender<T>, quit_sequence: &'static [u8], options: InputOptions) {
+    user_session(to_main, quit_sequence, options, SelfPipe::new().ok().map(Arc::new), ByteSource::Terminal, None);
+}
+
+/// Something that is shown each byte read by a session before it is parsed.
+pub(crate) type Tee = Box<dyn FnMut(&TimedByte) + Send>;
+
+/// Where a session's bytes come from.
+pub(crate) enum ByteSource {
+    /// The terminal, read on a thread of its own.
+    Terminal,
+    /**
+      Bytes sent by something else, such as a replay, along with the control characters to treat
+      them with. The session waits for the thread sending them, if there is one, before it ends.
+      */
+    Injected {
+        control: ControlChars,
+        bytes: mpsc::Receiver<TimedByte>,
+        sender: Option<thread::JoinHandle<()>>,
+    },
+}
+
+/**
+  Runs a [user_handler] session on the bytes from `source` that also ends when `stop` is notified,
+  whoever notifies it. The session notifies it itself once parsing has finished, to stop the
+  thread sending the bytes. If there is a `tee`, it is given the bytes on a thread of its own, so
+  that it can't hold up the reader.
+  */
+pub(crate) fn user_session<T: From<Option<ExtendedChar>>>(to_main: mpsc::Sender<T>, quit_sequence: &'static [u8], options: InputOptions, stop: Option<Arc<SelfPipe>>, source: ByteSource, tee: Option<Tee>) {
+    if options.job_control {
+        let _ = handle_job_control_signals();  // Typing the suspend character still works without them.
+    }
+    let (control, from_stdin, stdin_thread) = match source {
+        ByteSource::Terminal => {
+            let stdin = 0;
+            // Raw mode leaves c_cc alone, so it doesn't matter whether the reader has changed it yet.
+            let control = Termios::from_fd(stdin).map(|t| ControlChars::from_termios(&t)).unwrap_or_default();
+            let (to_user, from_stdin) = mpsc::channel();
+            let mode = options.mode;
+            // Without the pipe, the reader only notices the session has ended with the next key press.
+            let stop_fd = stop.as_ref().map(|pipe| pipe.read_fd());
+            let stdin_thread = thread::spawn(move || {
+                read_terminal(|input| to_user.send(input).is_ok(), quit_sequence, mode, stop_fd);
+            });
+            (control, from_stdin, Some(stdin_thread))
+        },
+        ByteSource::Injected { control, bytes, sender } => (control, bytes, sender),
+    };
+    let (from_stdin, tee_thread) = match tee {
+        Some(mut tee) => {
+            let (to_parser, from_tee) = mpsc::channel();
+            let tee_thread = thread::spawn(move || {
+                for input in from_stdin {
+                    tee(&input);
+                    if to_parser.send(input).is_err() {
+                        break;
+                    }
                 }
-                input_buffer.clear();
+            });
+            (from_tee, Some(tee_thread))
+        },
+        None => (from_stdin, None),
+    };
+    parse_handler(from_stdin, &to_main, quit_sequence, &options, control);
+    to_main.send(None.into()).unwrap();
+    if let Some(pipe) = &stop {
+        pipe.notify();
+    }
+    if let Some(stdin_thread) = stdin_thread {
+        stdin_thread.join().unwrap();
+    }
+    if let Some(tee_thread) = tee_thread {
+        tee_thread.join().unwrap();
+    }
+}
+
+/**
+  Parses the bytes coming from a session's [ByteSource] and sends the resulting characters to
+  `to_main`, until the quit sequence is entered or the input ends.
+
+  Held input is only given up on once the escape timeout passes after a byte that ended its read.
+  If a read stopped partway through a sequence the rest may simply not have arrived yet, but when
+  more bytes followed in the same read they are already on their way, so a lone ESC at the end of
+  a read is ambiguous while an ESC in the middle of one always starts a sequence.
+
+  Held input is also given up on when the next read arrived after the timeout, even if it was
+  already waiting, so that bytes that queued up are parsed as they would have been on time.
+  */
+pub(crate) fn parse_handler<T: From<Option<ExtendedChar>>>(from_stdin: mpsc::Receiver<TimedByte>, to_main: &mpsc::Sender<T>, quit_sequence: &'static [u8], options: &InputOptions, control: ControlChars) {
+    let mut parser = InputParser::new(quit_sequence, options, control);
+    let mut events = Vec::new();
+    let mut chunk_ended = true;
+    let mut last_arrived = None;
+    loop {
+        let input = if parser.is_pending() && chunk_ended {
+            match from_stdin.recv_timeout(parser.timeout()) {
+                Ok(input) => input,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    let status = parser.flush(&mut events);
+                    for e in events.drain(..) {
+                        to_main.send(Some(e).into()).unwrap();
+                    }
+                    if status == Status::Quit {
+                        break;
+                    }
+                    match from_stdin.recv() {
+                        Ok(input) => input,
+                        Err(_) => break,
+                    }
+                },
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        else {
+            match from_stdin.recv() {
+                Ok(input) => input,
+                Err(_) => break,
+            }
+        };
+        let late = last_arrived.is_some_and(|last| input.arrived.saturating_duration_since(last) >= parser.timeout());
+        if parser.is_pending() && chunk_ended && late && parser.flush(&mut events) == Status::Quit {
+            for e in events.drain(..) {
+                to_main.send(Some(e).into()).unwrap();
             }
             break;
         }
+        let arrived = if chunk_ended { Some(input.arrived) } else { None };
+        last_arrived = Some(input.arrived);
+        let status = parser.push(input.input.byte, arrived, &mut events);
+        chunk_ended = input.input.end_of_chunk;
+        for e in events.drain(..) {
+            to_main.send(Some(e).into()).unwrap();
+        }
+        match status {
+            Status::Continue => {},
+            Status::Suspend => {
+                suspend();
+                to_main.send(Some(ExtendedChar::Resumed).into()).unwrap();
+            },
+            Status::Quit => { break; },
+        }
     }
-    drop(from_stdin);
-    to_main.send(None.into()).unwrap();
-    stdin_thread.join().unwrap();
+    // The input ended, so whatever is still held isn't going to be completed.
+    parser.flush(&mut events);
+    for e in events {
+        to_main.send(Some(e).into()).unwrap();
+    }
+}
+
+/**
+  How the terminal is set up while input is being read. Every mode turns off echo and line
+  buffering, so that keys arrive as they are pressed; they differ in which of the terminal's own
+  input processing is left on.
+  */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TermMode {
+    /// Nothing is processed: Ctrl-C, Ctrl-S, Ctrl-V and the like all arrive as input.
+    #[default]
+    Raw,
+    /**
+      The keyboard signals, XON/XOFF flow control and carriage return translation are left as they
+      were, so Ctrl-C still interrupts the process and Enter arrives as a newline. With signals
+      left on, [restore_on_signals] and [handle_job_control_signals] keep the terminal usable.
+      */
+    Cbreak,
+    /// The given choice of flags.
+    Custom(ModeFlags),
+}
+
+/**
+  The choices that make up a [TermMode]. A flag that is `true` is left the way the original
+  settings had it, and one that is `false` is turned off.
+  */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ModeFlags {
+    /// `ISIG`: the interrupt, quit and suspend characters generate signals.
+    pub signals: bool,
+    /// `IXON`: Ctrl-S and Ctrl-Q stop and start output.
+    pub flow_control: bool,
+    /// `ICRNL`: carriage return is translated to newline.
+    pub translate_cr: bool,
+    /// `IEXTEN`: implementation-defined processing, such as Ctrl-V quoting the next character.
+    pub extended: bool,
+    /**
+      `VMIN`: the number of bytes a read waits for. When this is zero, a read that finds nothing
+      before `timeout` returns empty and the handlers simply read again, so a zero `timeout` as
+      well makes them spin.
+      */
+    pub min_bytes: u8,
+    /// `VTIME`: how long a read waits for more input, in tenths of a second.
+    pub timeout: u8,
+}
+
+impl TermMode {
+    /// Returns the flags this mode is made up of.
+    pub fn flags(&self) -> ModeFlags {
+        match self {
+            TermMode::Raw => ModeFlags {
+                signals: false,
+                flow_control: false,
+                translate_cr: false,
+                extended: false,
+                min_bytes: 1,
+                timeout: 0,
+            },
+            TermMode::Cbreak => ModeFlags {
+                signals: true,
+                flow_control: true,
+                translate_cr: true,
+                extended: false,
+                min_bytes: 1,
+                timeout: 0,
+            },
+            TermMode::Custom(flags) => *flags,
+        }
+    }
+
+    /// Returns a copy of the given terminal settings modified for this mode.
+    #[allow(clippy::identity_op)]
+    pub fn apply(&self, termios: &Termios) -> Termios {
+        let flags = self.flags();
+        let mut new_termios = *termios;  // make a mutable copy of termios that we will modify
+        new_termios.c_lflag &= !(
+            termios::ICANON |       // Disable canonical mode
+            termios::ECHO |         // Disable echo
+            0);
+        if !flags.translate_cr {
+            new_termios.c_iflag &= !termios::ICRNL;     // Don't interpret carriage return as linefeed
+        }
+        if !flags.flow_control {
+            new_termios.c_iflag &= !termios::IXON;      // Ignore XON/XOFF flow control (^Q & ^S)
+        }
+        if !flags.signals {
+            new_termios.c_lflag &= !termios::ISIG;      // Disable signal mode
+        }
+        if !flags.extended {
+            new_termios.c_lflag &= !termios::IEXTEN;    // Disable extended processing mode
+        }
+        new_termios.c_cc[termios::VMIN] = flags.min_bytes;
+        new_termios.c_cc[termios::VTIME] = flags.timeout;
+        new_termios
+    }
+}
+
+/**
+  Keeps a terminal in raw mode, or another [TermMode], for as long as it is alive, restoring the
+  original settings when it is dropped, including when a panic unwinds past it or the function
+  holding it returns early.
+
+  The first guard to be created also records the original settings globally, so that they are still
+  restored if the process never gets as far as dropping the guard. A panic hook puts the terminal
+  back before the panic message is printed (which covers `panic = "abort"` as well), and an `atexit`
+  handler covers [std::process::exit]. The panic hook wraps whatever hook was installed before it;
+  if the application replaces the hook afterwards, it can call [restore_terminal] from its own.
+  */
+pub struct RawModeGuard {
+    fd: RawFd,
+    mode: TermMode,
+    original: Termios,
+    registered: bool,
+}
+
+impl RawModeGuard {
+    /// Puts the terminal open on `fd` into raw mode.
+    pub fn new(fd: RawFd) -> io::Result<Self> {
+        Self::with_mode(fd, TermMode::Raw)
+    }
+
+    /// Puts the terminal open on `fd` into the given mode.
+    pub fn with_mode(fd: RawFd, mode: TermMode) -> io::Result<Self> {
+        let original = Termios::from_fd(fd)?;
+        let applied = mode.apply(&original);
+        let registered = SAVED_TERMINAL.save(fd, &original, &applied);
+        if registered {
+            install_restore_hooks();
+        }
+        let guard = RawModeGuard {
+            fd,
+            mode,
+            original,
+            registered,
+        };
+        tcsetattr(fd, TCSANOW, &applied)?;
+        Ok(guard)
+    }
+
+    /// Returns the mode the guard put the terminal in.
+    pub fn mode(&self) -> &TermMode {
+        &self.mode
+    }
+
+    /// Returns the settings the terminal had before the guard was created.
+    pub fn original(&self) -> &Termios {
+        &self.original
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        let _ = tcsetattr(self.fd, TCSANOW, &self.original);  // reset the terminal to original termios data
+        if self.registered {
+            SAVED_TERMINAL.forget();
+        }
+    }
+}
+
+/**
+  Restores the terminal settings saved by the outermost live [RawModeGuard], if there is one, along
+  with the file status flags of a terminal an input stream made non-blocking, and disables any
+  [Protocol] that is still enabled. This only makes async-signal-safe calls, so it may
+  be used from signal handlers as well as from panic hooks and exit handlers. The guard itself is
+  left alone and will restore the settings again when dropped.
+  */
+pub fn restore_terminal() {
+    disable_protocols();
+    SAVED_TERMINAL.restore();
+}
+
+/**
+  Optional terminal reporting modes that change what the terminal sends. Whichever of these are
+  enabled through [enable_protocol] are tracked, so that [restore_terminal] and the signal handlers
+  installed by [restore_on_signals] can switch them off again before the application goes away.
+  */
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Protocol {
+    /// Mouse button reporting, using the SGR encoding.
+    Mouse,
+    /// Pasted text is surrounded by `ESC [ 200 ~` and `ESC [ 201 ~`.
+    BracketedPaste,
+    /// The kitty keyboard protocol, with escape codes disambiguated.
+    KittyKeyboard,
+}
+
+static ENABLED_PROTOCOLS: AtomicU32 = AtomicU32::new(0);
+
+impl Protocol {
+    const ALL: [Protocol; 3] = [Protocol::Mouse, Protocol::BracketedPaste, Protocol::KittyKeyboard];
+
+    fn flag(self) -> u32 {
+        1 << self as u32
+    }
+
+    fn enable_sequence(self) -> &'static [u8] {
+        match self {
+            Protocol::Mouse => b"\x1b[?1000h\x1b[?1006h",
+            Protocol::BracketedPaste => b"\x1b[?2004h",
+            Protocol::KittyKeyboard => b"\x1b[>1u",
+        }
+    }
+
+    fn disable_sequence(self) -> &'static [u8] {
+        match self {
+            Protocol::Mouse => b"\x1b[?1006l\x1b[?1000l",
+            Protocol::BracketedPaste => b"\x1b[?2004l",
+            Protocol::KittyKeyboard => b"\x1b[<u",
+        }
+    }
+}
+
+/// Asks the terminal on stdout to start using `protocol`.
+pub fn enable_protocol(protocol: Protocol) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    stdout.write_all(protocol.enable_sequence())?;
+    stdout.flush()?;
+    ENABLED_PROTOCOLS.fetch_or(protocol.flag(), Ordering::SeqCst);
+    Ok(())
+}
+
+/// Asks the terminal on stdout to stop using `protocol`.
+pub fn disable_protocol(protocol: Protocol) -> io::Result<()> {
+    ENABLED_PROTOCOLS.fetch_and(!protocol.flag(), Ordering::SeqCst);
+    let mut stdout = io::stdout();
+    stdout.write_all(protocol.disable_sequence())?;
+    stdout.flush()
+}
+
+/// Returns true if `protocol` has been enabled and not disabled again.
+pub fn protocol_enabled(protocol: Protocol) -> bool {
+    ENABLED_PROTOCOLS.load(Ordering::SeqCst) & protocol.flag() != 0
+}
+
+/**
+  Disables every enabled protocol using only async-signal-safe calls, returning the flags of the
+  ones that were enabled.
+  */
+fn disable_protocols() -> u32 {
+    let enabled = ENABLED_PROTOCOLS.swap(0, Ordering::SeqCst);
+    for protocol in Protocol::ALL.iter().filter(|p| enabled & p.flag() != 0) {
+        write_stdout(protocol.disable_sequence());
+    }
+    enabled
+}
+
+/// Enables the protocols whose flags are set in `protocols` using only async-signal-safe calls.
+fn enable_protocols(protocols: u32) {
+    for protocol in Protocol::ALL.iter().filter(|p| protocols & p.flag() != 0) {
+        write_stdout(protocol.enable_sequence());
+    }
+    ENABLED_PROTOCOLS.fetch_or(protocols, Ordering::SeqCst);
+}
+
+fn write_stdout(bytes: &[u8]) {
+    unsafe { libc::write(libc::STDOUT_FILENO, bytes.as_ptr() as *const libc::c_void, bytes.len()); }
+}
+
+/**
+  Installs handlers for SIGTERM, SIGHUP, SIGINT and SIGQUIT that put the terminal back the way it
+  was, as [restore_terminal] does, and then re-raise the signal with its default disposition so the
+  process still ends the way it would have. Raw mode turns off the keyboard signals, but these can
+  still arrive from `kill` or from the connection to the terminal closing.
+
+  This replaces any handlers already installed for those signals.
+  */
+pub fn restore_on_signals() -> io::Result<()> {
+    for signal in &[libc::SIGTERM, libc::SIGHUP, libc::SIGINT, libc::SIGQUIT] {
+        install_handler(*signal, restore_and_reraise, libc::SA_RESETHAND)?;
+    }
+    Ok(())
+}
+
+extern "C" fn restore_and_reraise(signal: libc::c_int) {
+    restore_terminal();
+    // SA_RESETHAND has already put back the default disposition.
+    unsafe { libc::raise(signal); }
+}
+
+fn install_handler(signal: libc::c_int, handler: extern "C" fn(libc::c_int), flags: libc::c_int) -> io::Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = flags;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(signal, &action, ptr::null_mut()) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Set when the process has been continued and the application hasn't been told yet.
+static RESUMED: AtomicBool = AtomicBool::new(false);
+
+/// The protocols that were switched off when the process was stopped, to be enabled again.
+static SUSPENDED_PROTOCOLS: AtomicU32 = AtomicU32::new(0);
+
+/**
+  Stops the process the way Ctrl-Z does when the terminal generates signals. The terminal is put
+  back the way it was, as [restore_terminal] does, and SIGTSTP is raised with its default
+  disposition. Once the process is continued, the settings applied by the outermost [RawModeGuard]
+  and every [Protocol] that was switched off are restored before this returns.
+  */
+pub fn suspend() {
+    stop_process();
+    // The caller knows it has been resumed.
+    RESUMED.store(false, Ordering::SeqCst);
+}
+
+/**
+  Installs handlers so that the terminal is handed back and taken again properly when the process
+  is stopped and continued by something other than [suspend], such as `kill -TSTP` or `kill -STOP`
+  from another shell. SIGTSTP puts the terminal back before stopping, and SIGCONT re-applies the
+  settings of the outermost [RawModeGuard] and re-enables any [Protocol] that was switched off.
+  Sessions with [job_control](InputOptions::job_control) install these themselves and report
+  `Resumed` with the next input.
+
+  This replaces any handlers already installed for those signals.
+  */
+pub fn handle_job_control_signals() -> io::Result<()> {
+    install_handler(libc::SIGTSTP, stop_for_job_control, libc::SA_RESTART)?;
+    install_handler(libc::SIGCONT, resume_after_stop, libc::SA_RESTART)
+}
+
+extern "C" fn stop_for_job_control(_signal: libc::c_int) {
+    stop_process();
+}
+
+extern "C" fn resume_after_stop(_signal: libc::c_int) {
+    resume_terminal();
+    RESUMED.store(true, Ordering::SeqCst);
+}
+
+/**
+  Restores the terminal and stops the process with the default action of SIGTSTP, returning once
+  it has been continued. This only makes async-signal-safe calls, since it also runs as the SIGTSTP
+  handler, in which case SIGTSTP is blocked and has to be let through for the stop to happen.
+  */
+fn stop_process() {
+    SUSPENDED_PROTOCOLS.fetch_or(disable_protocols(), Ordering::SeqCst);
+    SAVED_TERMINAL.restore();
+    unsafe {
+        let mut default: libc::sigaction = mem::zeroed();
+        default.sa_sigaction = libc::SIG_DFL;
+        let mut previous: libc::sigaction = mem::zeroed();
+        libc::sigaction(libc::SIGTSTP, &default, &mut previous);
+        let mut unblock: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut unblock);
+        libc::sigaddset(&mut unblock, libc::SIGTSTP);
+        let mut mask: libc::sigset_t = mem::zeroed();
+        libc::pthread_sigmask(libc::SIG_UNBLOCK, &unblock, &mut mask);
+        libc::raise(libc::SIGTSTP);
+        // Stopped here until the process is continued.
+        libc::pthread_sigmask(libc::SIG_SETMASK, &mask, ptr::null_mut());
+        libc::sigaction(libc::SIGTSTP, &previous, ptr::null_mut());
+    }
+    resume_terminal();
+}
+
+/**
+  Re-applies the settings of the outermost [RawModeGuard] and re-enables the protocols that
+  [stop_process] switched off. This is safe to repeat, which happens when the SIGCONT handler runs
+  before [stop_process] gets to it.
+  */
+fn resume_terminal() {
+    SAVED_TERMINAL.reapply();
+    enable_protocols(SUSPENDED_PROTOCOLS.swap(0, Ordering::SeqCst));
+}
+
+/**
+  The settings registered by the outermost [RawModeGuard]: the descriptor, the original settings,
+  and the ones the guard applied. They are written into a free slot, and `current` is only set to
+  that slot once it is complete, so readers never see a partial copy. A reader counts itself into
+  the slot it is about to use and checks that it is still current before touching it, and a slot
+  is only written again once it is neither current nor being read.
+
+  Alongside them are the file status flags a terminal had before an input stream made it
+  non-blocking, packed with the descriptor into `file_flags` as `fd << 32 | flags`. The flags belong
+  to the open file, which stdin shares with the shell, so they are put back wherever the settings
+  are. Everything here is lock-free, so it can be used from signal handlers.
+  */
+struct SavedTerminal {
+    current: AtomicUsize,
+    readers: [AtomicUsize; SAVED_SLOTS],
+    slots: [UnsafeCell<MaybeUninit<SavedSettings>>; SAVED_SLOTS],
+    file_flags: AtomicU64,
+}
+
+#[derive(Clone, Copy)]
+struct SavedSettings {
+    fd: RawFd,
+    original: Termios,
+    applied: Termios,
+}
+
+// A slot is only written while no reader can be in it, as described above.
+unsafe impl Sync for SavedTerminal {}
+
+const SAVED_SLOTS: usize = 4;
+const NO_TERMINAL: usize = usize::MAX;
+const SAVING: usize = usize::MAX - 1;
+const NO_FILE_FLAGS: u64 = u64::MAX;
+
+static SAVED_TERMINAL: SavedTerminal = SavedTerminal {
+    current: AtomicUsize::new(NO_TERMINAL),
+    readers: [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+    slots: [
+        UnsafeCell::new(MaybeUninit::uninit()),
+        UnsafeCell::new(MaybeUninit::uninit()),
+        UnsafeCell::new(MaybeUninit::uninit()),
+        UnsafeCell::new(MaybeUninit::uninit()),
+    ],
+    file_flags: AtomicU64::new(NO_FILE_FLAGS),
+};
+
+impl SavedTerminal {
+    /// Saves the settings for `fd` if nothing is saved yet, returning true if they were saved.
+    fn save(&self, fd: RawFd, original: &Termios, applied: &Termios) -> bool {
+        if self.current.compare_exchange(NO_TERMINAL, SAVING, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return false;
+        }
+        // A reader that counts itself in after this sees SAVING or a newer slot, and backs off.
+        let free = (0..SAVED_SLOTS).find(|&slot| self.readers[slot].load(Ordering::SeqCst) == 0);
+        let slot = match free {
+            Some(slot) => slot,
+            None => {
+                self.current.store(NO_TERMINAL, Ordering::SeqCst);
+                return false;
+            },
+        };
+        unsafe {
+            (*self.slots[slot].get()).write(SavedSettings { fd, original: *original, applied: *applied });
+        }
+        self.current.store(slot, Ordering::SeqCst);
+        true
+    }
+
+    fn forget(&self) {
+        self.current.store(NO_TERMINAL, Ordering::SeqCst);
+    }
+
+    fn restore(&self) {
+        self.with_current(|saved| { let _ = tcsetattr(saved.fd, TCSANOW, &saved.original); });
+        if let Some((fd, flags)) = self.file_flags() {
+            unsafe { libc::fcntl(fd, libc::F_SETFL, flags); }
+        }
+    }
+
+    fn reapply(&self) {
+        self.with_current(|saved| { let _ = tcsetattr(saved.fd, TCSANOW, &saved.applied); });
+        if let Some((fd, flags)) = self.file_flags() {
+            unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK); }
+        }
+    }
+
+    #[cfg(any(test, feature = "stream"))]
+    /// Saves the file status flags `fd` had before it was made non-blocking, if none are saved yet.
+    fn save_file_flags(&self, fd: RawFd, flags: libc::c_int) -> bool {
+        let packed = (fd as u32 as u64) << 32 | flags as u32 as u64;
+        self.file_flags.compare_exchange(NO_FILE_FLAGS, packed, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    }
+
+    #[cfg(any(test, feature = "stream"))]
+    fn forget_file_flags(&self) {
+        self.file_flags.store(NO_FILE_FLAGS, Ordering::SeqCst);
+    }
+
+    fn file_flags(&self) -> Option<(RawFd, libc::c_int)> {
+        match self.file_flags.load(Ordering::SeqCst) {
+            NO_FILE_FLAGS => None,
+            packed => Some(((packed >> 32) as u32 as RawFd, packed as u32 as libc::c_int)),
+        }
+    }
+
+    /// Calls `f` with the saved settings, if there are any and they aren't being written.
+    fn with_current<F: FnOnce(&SavedSettings)>(&self, f: F) {
+        let slot = self.current.load(Ordering::SeqCst);
+        if slot >= SAVED_SLOTS { return; }
+        self.readers[slot].fetch_add(1, Ordering::SeqCst);
+        if self.current.load(Ordering::SeqCst) == slot {
+            f(unsafe { (*self.slots[slot].get()).assume_init_ref() });
+        }
+        self.readers[slot].fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/**
+  Serializes the tests that put a terminal into a mode with a [RawModeGuard], since only one set
+  of settings can be saved at a time and some of them check which one is.
+  */
+#[cfg(test)]
+pub(crate) fn lock_saved_terminal() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/**
+  Registers the file status flags `fd` had before it was made non-blocking, so that they are put
+  back along with the terminal settings. Returns false if another descriptor's flags are registered.
+  */
+#[cfg(any(test, feature = "stream"))]
+pub(crate) fn save_file_flags(fd: RawFd, flags: libc::c_int) -> bool {
+    let registered = SAVED_TERMINAL.save_file_flags(fd, flags);
+    if registered {
+        install_restore_hooks();
+    }
+    registered
+}
+
+#[cfg(any(test, feature = "stream"))]
+pub(crate) fn forget_file_flags() {
+    SAVED_TERMINAL.forget_file_flags();
+}
+
+/// A pseudo-terminal for tests, closed when dropped even if the test fails.
+#[cfg(test)]
+pub(crate) struct Pty {
+    pub(crate) master: RawFd,
+    pub(crate) slave: RawFd,
+}
+
+#[cfg(test)]
+impl Pty {
+    pub(crate) fn open() -> Self {
+        let (mut master, mut slave) = (0, 0);
+        let result = unsafe {
+            libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), ptr::null())
+        };
+        assert_eq!(result, 0);
+        Pty { master, slave }
+    }
+
+    /// Writes `keys` to the master side, as if they were typed.
+    pub(crate) fn type_keys(&self, keys: &[u8]) {
+        assert_eq!(unsafe { libc::write(self.master, keys.as_ptr() as *const libc::c_void, keys.len()) }, keys.len() as isize);
+    }
+}
+
+#[cfg(test)]
+impl Drop for Pty {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.slave);
+            libc::close(self.master);
+        }
+    }
+}
+
+/// Installs the panic hook and exit handler that restore the saved terminal settings, once.
+fn install_restore_hooks() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            restore_terminal();
+            previous(info);
+        }));
+        unsafe { libc::atexit(restore_at_exit); }
+    });
+}
+
+extern "C" fn restore_at_exit() {
+    restore_terminal();
+}
+
+/**
+  A byte read from the terminal by [chunked_stdin_handler], tagged with whether it was the last byte
+  returned by its read.
+  */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputByte {
+    pub byte: u8,
+    pub end_of_chunk: bool,
+}
+
+impl From<InputByte> for u8 {
+    fn from(input: InputByte) -> u8 {
+        input.byte
+    }
+}
+
+/// An [InputByte] along with when the read that returned it finished, as sessions parse them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct TimedByte {
+    pub(crate) input: InputByte,
+    pub(crate) arrived: Instant,
 }
 
 /**
   This is a raw stdin handler that returns a stream of u8 items along a mpsc channel. For input, it
   takes a [mpsc::Sender<u8>] as the channel where it sends all of the input characters, and a
   sequence of bytes that will tell 
+
+  Input is read as it becomes available rather than a byte at a time; use [chunked_stdin_handler]
+  to also learn where each read ended.
   */
 pub fn stdin_handler<T: From<u8>>(tx: mpsc::Sender<T>, quit_sequence: &[u8]) {
+    stdin_handler_with(tx, quit_sequence, TermMode::Raw, None);
+}
+
+/**
+  Like [stdin_handler], but reads with the terminal in `mode`. If `stop` is given, reading also
+  ends as soon as that descriptor becomes readable, so that a session which ended some other way
+  isn't left waiting for one more key press.
+  */
+pub fn stdin_handler_with<T: From<u8>>(tx: mpsc::Sender<T>, quit_sequence: &[u8], mode: TermMode, stop: Option<RawFd>) {
+    read_terminal(|timed| tx.send(timed.input.byte.into()).is_ok(), quit_sequence, mode, stop);
+}
+
+/**
+  Like [stdin_handler_with], but sends each byte as an [InputByte] marking whether it was the last
+  byte of its read, so that a parser can tell a sequence that arrived whole from one split across
+  reads.
+  */
+pub fn chunked_stdin_handler<T: From<InputByte>>(tx: mpsc::Sender<T>, quit_sequence: &[u8], mode: TermMode, stop: Option<RawFd>) {
+    read_terminal(|timed| tx.send(timed.input.into()).is_ok(), quit_sequence, mode, stop);
+}
+
+/// Reads the terminal for [stdin_handler_with], passing each byte to `send` until it returns false.
+pub(crate) fn read_terminal<F: FnMut(TimedByte) -> bool>(mut send: F, quit_sequence: &[u8], mode: TermMode, stop: Option<RawFd>) {
     let stdin = 0; // couldn't get std::os::unix::io::FromRawFd to work on /dev/stdin or /dev/tty
-    let termios = Termios::from_fd(stdin).unwrap();
-    let mut new_termios = termios.clone();  // make a mutable copy of termios that we will modify
-    //show_debug_termios_data(&new_termios);
-    new_termios.c_iflag &= !(
-        termios::ICRNL |        // Don't interpret carriage return as linefeed
-        termios::IXON |         // Ignore XON/XOFF flow control (^Q & ^S)
-        0);
-    new_termios.c_lflag &= !(
-        termios::ISIG |         // Disable signal mode
-        termios::ICANON |       // Disable canonical mode
-        termios::ECHO |         // Disable echo
-        termios::IEXTEN |       // Disable extended processing mode (probably not needed since I
-                                // think it is only active in canonical mode, but just to be safe.)
-        0);
-    tcsetattr(stdin, TCSANOW, &mut new_termios).unwrap();
-    let mut reader = io::stdin();
-    let mut buffer = [0;1];  // read exactly one byte
+    let guard = RawModeGuard::with_mode(stdin, mode).unwrap();  // resets stdin to the original termios data when dropped
+    let mut buffer = [0; 64];
     let mut quit_buf = Vec::new();
-    loop {
-        if let Err(_) = reader.read_exact(&mut buffer) {
-            break;
-        }
-        if let Err(_) = tx.send(buffer[0].into()) {
-            break;
+    'reading: loop {
+        if let Some(stop) = stop {
+            let mut fds = [
+                libc::pollfd { fd: stop, events: libc::POLLIN, revents: 0 },
+                libc::pollfd { fd: stdin, events: libc::POLLIN, revents: 0 },
+            ];
+            match unsafe { libc::poll(fds.as_mut_ptr(), 2, poll_timeout(None)) } {
+                -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+                -1 => break,
+                _ if fds[0].revents != 0 => break,
+                _ => {},
+            }
         }
-        if check_quit_buffer(buffer[0], &mut quit_buf, &quit_sequence) {
-            break;
+        let count = match read_input(stdin, &mut buffer) {
+            Ok(0) if !input_ended(stdin, guard.mode()) => continue,
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        let arrived = Instant::now();
+        for (i, b) in buffer[..count].iter().enumerate() {
+            let input = InputByte { byte: *b, end_of_chunk: i + 1 == count };
+            if !send(TimedByte { input, arrived }) {
+                break 'reading;
+            }
+            if check_quit_buffer(*b, &mut quit_buf, quit_sequence) {
+                break 'reading;
+            }
         }
     }
-    tcsetattr(stdin, TCSANOW, & termios).unwrap();  // reset the stdin to original termios data
+}
+
+/**
+  Reads whatever input is available from `fd` in a single `read(2)`, bypassing the buffering done
+  by [io::Stdin] so that the boundaries between reads are preserved.
+  */
+pub(crate) fn read_input(fd: RawFd, buffer: &mut [u8]) -> io::Result<usize> {
+    match unsafe { libc::read(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) } {
+        -1 => Err(io::Error::last_os_error()),
+        count => Ok(count as usize),
+    }
+}
+
+/**
+  Returns true if an empty read from `fd` in the given mode means that the input has ended. With a
+  `min_bytes` of zero it only means the read timed out, unless the terminal has also hung up.
+  */
+pub(crate) fn input_ended(fd: RawFd, mode: &TermMode) -> bool {
+    if mode.flags().min_bytes > 0 { return true; }
+    let mut poll_fd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    match unsafe { libc::poll(&mut poll_fd, 1, 0) } {
+        0 => false,
+        -1 => true,
+        _ => poll_fd.revents & (libc::POLLHUP | libc::POLLERR | libc::POLLNVAL) != 0,
+    }
 }
 
 /**
@@ -216,39 +1472,33 @@ pub fn stdin_handler<T: From<u8>>(tx: mpsc::Sender<T>, quit_sequence: &[u8]) {
   sequence of bytes that will tell 
   */
 pub fn raw_handler(tx: mpsc::Sender<Option<u8>>, quit_sequence: &[u8]) {
+    raw_handler_with(tx, quit_sequence, TermMode::Raw);
+}
+
+/// Like [raw_handler], but reads with the terminal in `mode`.
+pub fn raw_handler_with(tx: mpsc::Sender<Option<u8>>, quit_sequence: &[u8], mode: TermMode) {
     let stdin = 0; // couldn't get std::os::unix::io::FromRawFd to work on /dev/stdin or /dev/tty
-    let termios = Termios::from_fd(stdin).unwrap();
-    let mut new_termios = termios.clone();  // make a mutable copy of termios that we will modify
-    //show_debug_termios_data(&new_termios);
-    new_termios.c_iflag &= !(
-        termios::ICRNL |        // Don't interpret carriage return as linefeed
-        termios::IXON |         // Ignore XON/XOFF flow control (^Q & ^S)
-        0);
-    new_termios.c_lflag &= !(
-        termios::ISIG |         // Disable signal mode
-        termios::ICANON |       // Disable canonical mode
-        termios::ECHO |         // Disable echo
-        termios::IEXTEN |       // Disable extended processing mode (probably not needed since I
-                                // think it is only active in canonical mode, but just to be safe.)
-        0);
-    tcsetattr(stdin, TCSANOW, &mut new_termios).unwrap();
-    let mut reader = io::stdin();
+    let guard = RawModeGuard::with_mode(stdin, mode).unwrap();  // resets stdin to the original termios data when dropped
     let mut buffer = [0;1];  // read exactly one byte
     let mut quit_buf = Vec::new();
     loop {
-        if let Err(_) = reader.read_exact(&mut buffer) {
-            let _ = tx.send(None);  // Don't care if this succeeds.
-            break;
+        match read_input(stdin, &mut buffer) {
+            Ok(0) if !input_ended(stdin, guard.mode()) => continue,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Ok(1) => {},
+            _ => {
+                let _ = tx.send(None);  // Don't care if this succeeds.
+                break;
+            },
         }
-        if let Err(_) = tx.send(Some(buffer[0])) {
+        if tx.send(Some(buffer[0])).is_err() {
             break;
         }
-        if check_quit_buffer(buffer[0], &mut quit_buf, &quit_sequence) {
+        if check_quit_buffer(buffer[0], &mut quit_buf, quit_sequence) {
             let _ = tx.send(None);  // Don't care if this succeeds.
             break;
         }
     }
-    tcsetattr(stdin, TCSANOW, & termios).unwrap();  // reset the stdin to original termios data
 }
 
 #[allow(dead_code)]
@@ -265,6 +1515,7 @@ fn show_debug_termios_data(termios: &Termios) {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use crate::user::*;
 
@@ -362,4 +1613,409 @@ mod tests {
             assert_eq!(&test_buf, &[]);
         }
     }
+
+    #[test]
+    fn test_stdin_iterator_nonblocking() {
+        let (sender, receiver) = mpsc::channel();
+        let mut input = StdinIterator {
+            join_handle: None,
+            receiver,
+            stop: None,
+            done: false,
+        };
+        // Nothing available yet.
+        assert_eq!(input.try_next(), None);
+        assert_eq!(input.next_timeout(Duration::from_millis(1)), None);
+        assert_eq!(input.next_deadline(Instant::now()), None);
+        assert_eq!(input.is_done(), false);
+        sender.send(Some(b'a')).unwrap();
+        sender.send(Some(b'b')).unwrap();
+        sender.send(Some(b'c')).unwrap();
+        assert_eq!(input.try_next(), Some(b'a'));
+        assert_eq!(input.next_timeout(Duration::from_millis(1)), Some(b'b'));
+        assert_eq!(input.next_deadline(Instant::now() + Duration::from_millis(1)), Some(b'c'));
+        // End of input.
+        sender.send(None).unwrap();
+        assert_eq!(input.try_next(), None);
+        assert_eq!(input.is_done(), true);
+        assert_eq!(input.next(), None);
+    }
+
+    #[test]
+    fn test_escape_timer() {
+        let ms = Duration::from_millis;
+        // A fixed timeout never changes.
+        let mut timer = EscapeTimer::new(EscapeTimeout::Fixed(ms(30)));
+        let start = Instant::now();
+        timer.input(start, false);
+        timer.input(start + ms(200), true);
+        timer.sequence_end(true);
+        assert_eq!(timer.timeout(), ms(30));
+        // An adaptive timeout starts at the initial value.
+        let mode = EscapeTimeout::Adaptive { initial: ms(50), min: ms(10), max: ms(500) };
+        let mut timer = EscapeTimer::new(mode);
+        assert_eq!(timer.timeout(), ms(50));
+        // Delays within sequences that time out are ignored.
+        timer.input(start, false);
+        timer.input(start + ms(300), true);
+        timer.sequence_end(false);
+        assert_eq!(timer.timeout(), ms(50));
+        // Steady delays within recognized sequences move the timeout to a margin above them.
+        let mut now = start;
+        for _ in 0..50 {
+            now += ms(1000);
+            timer.input(now, false);
+            timer.input(now + ms(40), true);
+            timer.sequence_end(true);
+        }
+        assert!(timer.timeout() > ms(40));
+        assert!(timer.timeout() <= ms(100));
+        // Sequences that arrive in one piece bring it down, but never below the minimum.
+        for _ in 0..200 {
+            now += ms(1000);
+            timer.input(now, false);
+            timer.input(now, true);
+            timer.sequence_end(true);
+        }
+        assert_eq!(timer.timeout(), ms(10));
+        // Very slow links are capped at the maximum.
+        for _ in 0..50 {
+            now += ms(1000);
+            timer.input(now, false);
+            timer.input(now + ms(900), true);
+            timer.sequence_end(true);
+        }
+        assert_eq!(timer.timeout(), ms(500));
+    }
+
+    #[test]
+    fn test_escape_timeout_recovers() {
+        use crate::parse::{ExtendedChar::*, SpecialChar::*};
+
+        let ms = Duration::from_millis;
+        let escape_timeout = EscapeTimeout::Adaptive { initial: ms(5), min: ms(1), max: ms(500) };
+        let options = InputOptions { escape_timeout, ..InputOptions::default() };
+        let mut parser = InputParser::new(b"", &options, ControlChars::default());
+        let mut now = Instant::now();
+        // Every arrow key is split by a link slower than the timeout.
+        for _ in 0..20 {
+            let mut out = Vec::new();
+            now += ms(1000);
+            parser.push(0x1b, Some(now), &mut out);
+            parser.flush(&mut out);
+            parser.push(b'[', Some(now + ms(40)), &mut out);
+            parser.push(b'A', None, &mut out);
+            assert_eq!(out, vec![Byte(0x1b), Byte(b'['), Byte(b'A')]);
+        }
+        // The missed sequences raise the timeout until they fit, so the next one isn't split.
+        assert!(parser.timeout() > ms(40));
+        let mut out = Vec::new();
+        now += ms(1000);
+        parser.push(0x1b, Some(now), &mut out);
+        parser.push(b'[', Some(now + ms(40)), &mut out);
+        parser.push(b'A', None, &mut out);
+        assert_eq!(out, vec![Normal(UpArrow)]);
+        // A key typed after a lone Escape isn't taken as a missed sequence.
+        let mut parser = InputParser::new(b"", &options, ControlChars::default());
+        let mut out = Vec::new();
+        parser.push(0x1b, Some(now), &mut out);
+        parser.flush(&mut out);
+        parser.push(b'[', Some(now + ms(300)), &mut out);
+        parser.push(b'x', Some(now + ms(400)), &mut out);
+        assert_eq!(parser.timeout(), ms(5));
+    }
+
+    #[test]
+    fn test_parse_handler_chunks() {
+        use crate::parse::{ExtendedChar::*, SpecialChar::*};
+
+        let options = InputOptions {
+            escape_timeout: EscapeTimeout::Fixed(Duration::from_millis(20)),
+            ..InputOptions::default()
+        };
+        let (to_user, from_stdin) = mpsc::channel();
+        let (to_main, from_user) = mpsc::channel::<Option<ExtendedChar>>();
+        let handler = thread::spawn(move || {
+            parse_handler(from_stdin, &to_main, b"q", &options, ControlChars::default());
+        });
+        let send = |bytes: &[u8], end_of_chunk: bool| {
+            for (i, b) in bytes.iter().enumerate() {
+                let end_of_chunk = end_of_chunk && i + 1 == bytes.len();
+                let input = InputByte { byte: *b, end_of_chunk };
+                to_user.send(TimedByte { input, arrived: Instant::now() }).unwrap();
+            }
+        };
+        // An escape in the middle of a read is waited on however long the rest takes.
+        send(b"\x1b", false);
+        thread::sleep(Duration::from_millis(60));
+        send(b"[A", true);
+        assert_eq!(from_user.recv().unwrap(), Some(Normal(UpArrow)));
+        // An escape at the end of a read is given up on after the timeout.
+        send(b"\x1b", true);
+        thread::sleep(Duration::from_millis(60));
+        send(b"[A", true);
+        assert_eq!(from_user.recv().unwrap(), Some(Byte(0x1b)));
+        assert_eq!(from_user.recv().unwrap(), Some(Byte(b'[')));
+        assert_eq!(from_user.recv().unwrap(), Some(Byte(b'A')));
+        send(b"q", true);
+        assert_eq!(from_user.recv().unwrap(), Some(Byte(b'q')));
+        handler.join().unwrap();
+        assert!(from_user.recv().is_err());
+    }
+
+    #[test]
+    fn test_job_control_parsing() {
+        use crate::parse::ExtendedChar::*;
+
+        let control = ControlChars { suspend: Some(26), ..ControlChars::default() };
+        let options = InputOptions { job_control: true, ..InputOptions::default() };
+        let mut parser = InputParser::new(b"q", &options, control);
+        let mut out = Vec::new();
+        // The suspend character finishes anything that was held.
+        assert_eq!(parser.push(0x1b, None, &mut out), Status::Continue);
+        assert_eq!(parser.push(26, None, &mut out), Status::Suspend);
+        assert_eq!(out, vec![Byte(0x1b), Suspended]);
+        assert_eq!(parser.is_pending(), false);
+        assert_eq!(parser.push(b'q', None, &mut out), Status::Quit);
+        // Without job control it is ordinary input.
+        let mut parser = InputParser::new(b"q", &InputOptions::default(), control);
+        let mut out = Vec::new();
+        assert_eq!(parser.push(26, None, &mut out), Status::Continue);
+        assert_eq!(out, vec![Byte(26)]);
+    }
+
+    #[test]
+    fn test_signal_events() {
+        use crate::parse::ExtendedChar::*;
+
+        let control = ControlChars { interrupt: Some(3), quit: Some(28), ..ControlChars::default() };
+        let options = InputOptions { signal_events: true, ..InputOptions::default() };
+        let mut parser = InputParser::new(b"", &options, control);
+        let mut out = Vec::new();
+        for b in b"a\x03\x1b\x1c\x1a" {
+            assert_eq!(parser.push(*b, None, &mut out), Status::Continue);
+        }
+        // A disabled character is passed through, and a held escape is given up on.
+        assert_eq!(out, vec![Byte(b'a'), Interrupt, Byte(0x1b), Quit, Byte(26)]);
+        // Suspending takes priority over reporting.
+        let control = ControlChars { suspend: Some(26), ..control };
+        let options = InputOptions { job_control: true, ..options };
+        let mut parser = InputParser::new(b"", &options, control);
+        let mut out = Vec::new();
+        assert_eq!(parser.push(26, None, &mut out), Status::Suspend);
+        assert_eq!(parser.push(3, None, &mut out), Status::Continue);
+        assert_eq!(out, vec![Suspended, Interrupt]);
+    }
+
+    #[test]
+    fn test_editing_events() {
+        use crate::parse::{ExtendedChar::*, SpecialChar::*};
+
+        let control = ControlChars {
+            erase: Some(8),
+            kill: Some(21),
+            word_erase: Some(23),
+            eof: Some(4),
+            literal_next: Some(22),
+            ..ControlChars::default()
+        };
+        let options = InputOptions { editing_events: true, ..InputOptions::default() };
+        let mut parser = InputParser::new(b"", &options, control);
+        let mut out = Vec::new();
+        // The configured erase character is Backspace, and DEL is left alone.
+        for b in b"\x08\x7f\x15\x17\x04" {
+            parser.push(*b, None, &mut out);
+        }
+        assert_eq!(out, vec![Normal(Backspace), Byte(0x7f), KillLine, DeleteWord, EndOfFile]);
+        // The literal next character quotes escapes and itself.
+        let mut out = Vec::new();
+        for b in b"\x16\x1b[A\x16\x16\x16\x08" {
+            parser.push(*b, None, &mut out);
+        }
+        assert_eq!(out, vec![Byte(0x1b), Byte(b'['), Byte(b'A'), Byte(22), Byte(8)]);
+        assert_eq!(parser.is_pending(), false);
+        // Pasted control characters are left as they are.
+        let mut out = Vec::new();
+        for b in b"\x1b[200~\x08\x16\x15\x1b[201~\x08" {
+            parser.push(*b, None, &mut out);
+        }
+        assert_eq!(out, vec![PasteStart, Byte(8), Byte(22), Byte(21), PasteEnd, Normal(Backspace)]);
+    }
+
+    #[test]
+    fn test_quit_trigger() {
+        use crate::parse::{ExtendedChar::*, SpecialChar::*};
+
+        // A sequence of parsed keys.
+        let options = InputOptions {
+            quit: Some(QuitTrigger::Keys(vec![Byte(24), Normal(F10)])),
+            ..InputOptions::default()
+        };
+        let mut parser = InputParser::new(b"", &options, ControlChars::default());
+        let mut out = Vec::new();
+        let statuses: Vec<_> = b"\x18\x1b[21~".iter().map(|b| parser.push(*b, None, &mut out)).collect();
+        assert_eq!(statuses[..5], [Status::Continue; 5]);
+        assert_eq!(statuses[5], Status::Quit);
+        assert_eq!(out, vec![Byte(24), Normal(F10)]);
+        // Characters completed along with the trigger but after it are dropped.
+        let options = InputOptions {
+            signal_events: true,
+            quit: Some(QuitTrigger::Predicate(|e| *e == AltByte(b'['))),
+            ..InputOptions::default()
+        };
+        let control = ControlChars { interrupt: Some(3), ..ControlChars::default() };
+        let mut parser = InputParser::new(b"", &options, control);
+        let mut out = Vec::new();
+        for b in b"\x1b[1" {
+            assert_eq!(parser.push(*b, None, &mut out), Status::Continue);
+        }
+        assert_eq!(parser.push(3, None, &mut out), Status::Quit);
+        assert_eq!(out, vec![AltByte(b'[')]);
+        // A lone Escape only completes when it is flushed.
+        let options = InputOptions {
+            quit: Some(QuitTrigger::Predicate(|e| *e == Byte(0x1b))),
+            ..InputOptions::default()
+        };
+        let mut parser = InputParser::new(b"", &options, ControlChars::default());
+        let mut out = Vec::new();
+        assert_eq!(parser.push(0x1b, None, &mut out), Status::Continue);
+        assert_eq!(parser.flush(&mut out), Status::Quit);
+        assert_eq!(out, vec![Byte(0x1b)]);
+    }
+
+    #[test]
+    fn test_raw_mode_guard() {
+        let _lock = lock_saved_terminal();
+        let pty = Pty::open();
+        let slave = pty.slave;
+        let original = Termios::from_fd(slave).unwrap();
+        assert_ne!(original.c_lflag & termios::ICANON, 0);
+        {
+            let guard = RawModeGuard::new(slave).unwrap();
+            assert_eq!(guard.original(), &original);
+            let raw = Termios::from_fd(slave).unwrap();
+            assert_eq!(raw.c_lflag & (termios::ICANON | termios::ECHO | termios::ISIG), 0);
+            // A nested guard restores the raw settings and leaves the outer one registered.
+            drop(RawModeGuard::new(slave).unwrap());
+            assert_eq!(Termios::from_fd(slave).unwrap(), raw);
+            // The registered settings can be restored without the guard.
+            restore_terminal();
+            assert_eq!(Termios::from_fd(slave).unwrap(), original);
+            tcsetattr(slave, TCSANOW, &raw).unwrap();
+        }
+        assert_eq!(Termios::from_fd(slave).unwrap(), original);
+        // Once dropped, the settings are no longer registered.
+        tcsetattr(slave, TCSANOW, &TermMode::Raw.apply(&original)).unwrap();
+        restore_terminal();
+        assert_eq!(Termios::from_fd(slave).unwrap().c_lflag & termios::ICANON, 0);
+    }
+
+    #[test]
+    fn test_term_mode() {
+        let _lock = lock_saved_terminal();
+        let pty = Pty::open();
+        let slave = pty.slave;
+        let original = Termios::from_fd(slave).unwrap();
+        let kept = termios::ISIG | termios::IEXTEN;
+        assert_eq!(original.c_lflag & kept, kept);
+        assert_ne!(original.c_iflag & termios::ICRNL, 0);
+        // Raw mode turns everything off.
+        let raw = TermMode::Raw.apply(&original);
+        assert_eq!(raw.c_lflag & (termios::ICANON | termios::ECHO | termios::ISIG | termios::IEXTEN), 0);
+        assert_eq!(raw.c_iflag & (termios::ICRNL | termios::IXON), 0);
+        assert_eq!((raw.c_cc[termios::VMIN], raw.c_cc[termios::VTIME]), (1, 0));
+        // Cbreak mode leaves signals and carriage return translation alone.
+        let cbreak = TermMode::Cbreak.apply(&original);
+        assert_eq!(cbreak.c_lflag & (termios::ICANON | termios::ECHO | termios::IEXTEN), 0);
+        assert_eq!(cbreak.c_lflag & termios::ISIG, termios::ISIG);
+        assert_eq!(cbreak.c_iflag & termios::ICRNL, termios::ICRNL);
+        assert_eq!(cbreak.c_iflag & termios::IXON, original.c_iflag & termios::IXON);
+        // A custom mode, applied and restored by a guard.
+        let flags = ModeFlags { signals: true, min_bytes: 0, timeout: 5, ..TermMode::Raw.flags() };
+        {
+            let guard = RawModeGuard::with_mode(slave, TermMode::Custom(flags)).unwrap();
+            assert_eq!(guard.mode(), &TermMode::Custom(flags));
+            let custom = Termios::from_fd(slave).unwrap();
+            assert_eq!(custom.c_lflag & kept, termios::ISIG);
+            assert_eq!((custom.c_cc[termios::VMIN], custom.c_cc[termios::VTIME]), (0, 5));
+            // Nothing has been typed, so an empty read isn't the end of the input.
+            assert_eq!(input_ended(slave, guard.mode()), false);
+            assert_eq!(input_ended(slave, &TermMode::Raw), true);
+            pty.type_keys(b"x");
+            let mut buffer = [0u8; 4];
+            assert_eq!(read_input(slave, &mut buffer).unwrap(), 1);
+        }
+        assert_eq!(Termios::from_fd(slave).unwrap(), original);
+    }
+
+    #[test]
+    fn test_restore_on_signals() {
+        use std::{os::unix::process::ExitStatusExt, process::Command};
+
+        // The child half of the test: enter raw mode on the inherited pty and get terminated.
+        if let Ok(fd) = std::env::var("TERMINAL_INPUT_SIGNAL_TEST_FD") {
+            let fd = fd.parse().unwrap();
+            let _guard = RawModeGuard::new(fd).unwrap();
+            let _nonblocking = crate::pipe::NonBlocking::set(fd).unwrap();
+            enable_protocol(Protocol::BracketedPaste).unwrap();
+            restore_on_signals().unwrap();
+            unsafe { libc::raise(libc::SIGTERM); }
+            thread::sleep(Duration::from_secs(5));
+            return;
+        }
+        let pty = Pty::open();
+        let slave = pty.slave;
+        let original = Termios::from_fd(slave).unwrap();
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "user::tests::test_restore_on_signals", "--nocapture"])
+            .env("TERMINAL_INPUT_SIGNAL_TEST_FD", slave.to_string())
+            .output()
+            .unwrap();
+        assert_eq!(output.status.signal(), Some(libc::SIGTERM));
+        assert_eq!(Termios::from_fd(slave).unwrap(), original);
+        // The pty is shared with the child, which made it non-blocking and was killed holding it.
+        assert_eq!(unsafe { libc::fcntl(slave, libc::F_GETFL) } & libc::O_NONBLOCK, 0);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("\x1b[?2004h\x1b[?2004l"));
+    }
+
+    #[test]
+    fn test_suspend() {
+        use std::{io::Read, process::{Command, Stdio}};
+
+        // The child half of the test: enter raw mode on the inherited pty and suspend.
+        if let Ok(fd) = std::env::var("TERMINAL_INPUT_SUSPEND_TEST_FD") {
+            let fd = fd.parse().unwrap();
+            let _guard = RawModeGuard::new(fd).unwrap();
+            let raw = Termios::from_fd(fd).unwrap();
+            enable_protocol(Protocol::BracketedPaste).unwrap();
+            handle_job_control_signals().unwrap();
+            suspend();
+            // Both the settings and the protocol are back once the process is continued.
+            assert_eq!(Termios::from_fd(fd).unwrap(), raw);
+            assert!(protocol_enabled(Protocol::BracketedPaste));
+            disable_protocol(Protocol::BracketedPaste).unwrap();
+            return;
+        }
+        let pty = Pty::open();
+        let slave = pty.slave;
+        let original = Termios::from_fd(slave).unwrap();
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "user::tests::test_suspend", "--nocapture"])
+            .env("TERMINAL_INPUT_SUSPEND_TEST_FD", slave.to_string())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let pid = child.id() as libc::pid_t;
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, libc::WUNTRACED) }, pid);
+        assert!(libc::WIFSTOPPED(status));
+        // While stopped, the terminal has its original settings.
+        assert_eq!(Termios::from_fd(slave).unwrap(), original);
+        unsafe { libc::kill(pid, libc::SIGCONT); }
+        let mut stdout = String::new();
+        child.stdout.take().unwrap().read_to_string(&mut stdout).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert!(stdout.contains("\x1b[?2004h\x1b[?2004l\x1b[?2004h\x1b[?2004l"));
+    }
 }
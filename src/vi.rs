@@ -0,0 +1,811 @@
+use std::{
+    io::{self, Stdout, Write},
+    time::Duration,
+};
+use crate::{
+    line::{Edit, LineBuffer, LineEditor, printable},
+    parse::{
+        ExtendedChar::{self, *},
+        SpecialChar::*,
+    },
+};
+
+/**
+  A short escape timeout to read input with for a [ViEditor], so that Escape leaves insert mode
+  straight away rather than waiting to see if it starts a sequence. Set it as the
+  [escape_timeout](crate::user::InputOptions::escape_timeout) with
+  `EscapeTimeout::Fixed(vi::ESCAPE_TIMEOUT)`.
+  */
+pub const ESCAPE_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// The escape sequences that set the shape of the cursor to show the mode.
+const INSERT_CURSOR: &str = "\x1b[6 q";
+const NORMAL_CURSOR: &str = "\x1b[2 q";
+const DEFAULT_CURSOR: &str = "\x1b[0 q";
+
+/// The largest count a command can be given, so that a mistyped one can't keep the editor busy.
+const MAX_COUNT: usize = 9999;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ViMode {
+    /// Keys are handled by the [LineEditor], so typing inserts text.
+    Insert,
+    /// Keys are commands, moving the cursor and changing the text.
+    Normal,
+    /// Motions select text from where the mode was entered, for an operator to act on.
+    Visual,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operator {
+    Delete,
+    Change,
+    Yank,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Motion {
+    Left,
+    Right,
+    WordForward,
+    WordBackward,
+    WordEnd,
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    Find { c: char, forward: bool, till: bool },
+    /// The whole line, when an operator is doubled as in `dd`.
+    Line,
+    /// The text selected in visual mode.
+    Selection,
+    /// A number of characters from the cursor, across lines, which is what a selection becomes once acted on.
+    Span(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Command {
+    Move(Motion),
+    Operate(Operator, Motion),
+    /// Enters insert mode, after moving to the position given by the motion for `a`, `I` and `A`.
+    Insert(Option<Motion>),
+    Put { before: bool },
+    Replace(char),
+    /// Moves to another line, or through the history from the first and last lines.
+    Vertical { down: bool },
+    Visual,
+    Repeat,
+}
+
+impl Command {
+    fn is_change(self) -> bool {
+        match self {
+            Command::Operate(op, _) => op != Operator::Yank,
+            Command::Insert(_) | Command::Put { .. } | Command::Replace(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// The result of parsing the keys typed so far in normal or visual mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Parse {
+    Incomplete,
+    Invalid,
+    /// A command, with its count if one was given.
+    Done(Option<usize>, Command),
+}
+
+/// A change that `.` repeats: the command, its count and the keys typed in the insert mode it started.
+#[derive(Clone, Debug)]
+struct Change {
+    count: Option<usize>,
+    command: Command,
+    inserted: Vec<ExtendedChar>,
+}
+
+/**
+  A vi-style layer over a [LineEditor]. It starts each line in insert mode, where keys go to the
+  editor as usual, and Escape switches to normal mode, where they are commands:
+
+  - the motions `h`, `l`, `w`, `b`, `e`, `0`, `^`, `$` and `f`, `t`, `F`, `T` followed by a character;
+  - the operators `d`, `c` and `y` followed by a motion, or doubled to act on the whole line, and
+    `x`, `X`, `D`, `C`, `s` and `Y` as the usual shorthands for them;
+  - `i`, `a`, `I` and `A` to go back to insert mode, `p` and `P` to put the last text deleted or
+    yanked, `r` to replace characters and `j` and `k` to move between lines and through the history;
+  - `v` for visual mode, where the motions select text for `d`, `c` and `y` to act on, although
+    the selection isn't highlighted;
+  - `.` to repeat the last change, along with any text it inserted.
+
+  Counts may be given before commands and before motions, as in `2d3w`. Deleted and yanked text
+  goes to the editor's [KillRing](crate::line::KillRing), so Ctrl-Y in insert mode yanks it too.
+  Keys other than these, such as Enter and the arrows, go to the editor in any mode.
+
+  The mode is shown with the shape of the cursor: a bar in insert mode and a block otherwise. The
+  input should be read with a short escape timeout such as [ESCAPE_TIMEOUT], and a key that
+  arrives with Alt held is taken as Escape followed by the key, as it is when typed too quickly.
+  */
+pub struct ViEditor<W: Write = Stdout> {
+    editor: LineEditor<W>,
+    mode: ViMode,
+    keys: Vec<char>,
+    anchor: usize,
+    last_change: Option<Change>,
+    recording: Option<Change>,
+    shape: &'static str,
+}
+
+impl ViEditor<Stdout> {
+    /// Creates an editor that draws on stdout.
+    pub fn new(prompt: &str) -> Self {
+        Self::with_editor(LineEditor::new(prompt))
+    }
+}
+
+impl<W: Write> ViEditor<W> {
+    pub fn with_editor(editor: LineEditor<W>) -> Self {
+        ViEditor {
+            editor,
+            mode: ViMode::Insert,
+            keys: Vec::new(),
+            anchor: 0,
+            last_change: None,
+            recording: None,
+            shape: DEFAULT_CURSOR,
+        }
+    }
+
+    pub fn editor(&self) -> &LineEditor<W> {
+        &self.editor
+    }
+
+    pub fn editor_mut(&mut self) -> &mut LineEditor<W> {
+        &mut self.editor
+    }
+
+    pub fn into_editor(self) -> LineEditor<W> {
+        self.editor
+    }
+
+    pub fn mode(&self) -> ViMode {
+        self.mode
+    }
+
+    /// Reads a line from `input` in the same way as [LineEditor::read_line].
+    pub fn read_line<I: Iterator<Item = ExtendedChar>>(&mut self, input: I) -> io::Result<Option<String>> {
+        self.start()?;
+        for key in input {
+            match self.handle(key)? {
+                Edit::Continue => {},
+                Edit::Accept(line) => { return Ok(Some(line)); },
+                Edit::Cancel => { return Err(io::ErrorKind::Interrupted.into()); },
+                Edit::EndOfFile => { return Ok(None); },
+            }
+        }
+        self.set_cursor_shape(DEFAULT_CURSOR)?;
+        Ok(None)
+    }
+
+    /// Starts a new, empty line in insert mode and draws the prompt.
+    pub fn start(&mut self) -> io::Result<()> {
+        self.mode = ViMode::Insert;
+        self.keys.clear();
+        self.recording = None;
+        self.editor.start()?;
+        self.set_cursor_shape(INSERT_CURSOR)
+    }
+
+    /// Edits the line with a key, redrawing whatever it changed.
+    pub fn handle(&mut self, key: ExtendedChar) -> io::Result<Edit> {
+        let edit = match (self.mode, key) {
+            (_, AltByte(b)) => {
+                self.escape()?;
+                self.handle(Byte(b))?
+            },
+            (ViMode::Insert, Byte(0x1b)) => {
+                self.escape()?;
+                Edit::Continue
+            },
+            (ViMode::Insert, _) => {
+                if let Some(change) = &mut self.recording {
+                    change.inserted.push(key);
+                }
+                self.editor.handle(key)?
+            },
+            (_, Byte(0x1b)) => {
+                self.keys.clear();
+                if self.mode == ViMode::Visual {
+                    self.mode = ViMode::Normal;
+                }
+                Edit::Continue
+            },
+            (_, Normal(LeftArrow)) => { self.command_key('h')? },
+            (_, Normal(RightArrow)) => { self.command_key('l')? },
+            (_, _) => match printable(key) {
+                Some(c) => self.command_key(c)?,
+                None => {
+                    self.keys.clear();
+                    let edit = self.editor.handle(key)?;
+                    if edit == Edit::Continue {
+                        self.settle()?;
+                    }
+                    edit
+                },
+            },
+        };
+        if edit != Edit::Continue {
+            self.set_cursor_shape(DEFAULT_CURSOR)?;
+        }
+        Ok(edit)
+    }
+
+    /// Leaves insert or visual mode for normal mode.
+    fn escape(&mut self) -> io::Result<()> {
+        self.keys.clear();
+        if self.mode == ViMode::Insert {
+            if let Some(change) = self.recording.take() {
+                self.last_change = Some(change);
+            }
+            // As in vi, the cursor goes back onto the last character inserted.
+            let buffer = self.editor.buffer_mut();
+            if buffer.cursor() > buffer.line_start() {
+                buffer.move_left();
+            }
+            self.editor.refresh()?;
+            self.set_cursor_shape(NORMAL_CURSOR)?;
+        }
+        self.mode = ViMode::Normal;
+        Ok(())
+    }
+
+    /// Adds a key to the command being typed in normal or visual mode, running it once it is complete.
+    fn command_key(&mut self, c: char) -> io::Result<Edit> {
+        self.keys.push(c);
+        match parse(&self.keys, self.mode == ViMode::Visual) {
+            Parse::Incomplete => {},
+            Parse::Invalid => { self.keys.clear(); },
+            Parse::Done(count, command) => {
+                self.keys.clear();
+                return self.run(count, command);
+            },
+        }
+        Ok(Edit::Continue)
+    }
+
+    fn run(&mut self, count: Option<usize>, command: Command) -> io::Result<Edit> {
+        if command == Command::Repeat {
+            return self.repeat(count);
+        }
+        let mut command = command;
+        let mut count = count;
+        if let Command::Operate(op, Motion::Selection) = command {
+            // Repeating a change made in visual mode acts on as many characters again.
+            let (start, end) = self.selection();
+            command = Command::Operate(op, Motion::Span(end - start));
+            count = None;
+            self.editor.buffer_mut().set_cursor(start);
+        }
+        if command.is_change() {
+            let change = Change { count, command, inserted: Vec::new() };
+            if self.execute(count.unwrap_or(1), command) {
+                self.recording = Some(change);
+            }
+            else {
+                self.last_change = Some(change);
+            }
+        }
+        else if let Command::Vertical { down } = command {
+            let key = if down { Normal(DownArrow) } else { Normal(UpArrow) };
+            for _ in 0..count.unwrap_or(1) {
+                self.editor.handle(key)?;
+            }
+        }
+        else {
+            self.execute(count.unwrap_or(1), command);
+        }
+        self.settle()?;
+        Ok(Edit::Continue)
+    }
+
+    /// Repeats the last change, with `count` in place of its own if there is one.
+    fn repeat(&mut self, count: Option<usize>) -> io::Result<Edit> {
+        let change = match self.last_change.clone() {
+            Some(change) => change,
+            None => return Ok(Edit::Continue),
+        };
+        let count = count.or(change.count);
+        if self.execute(count.unwrap_or(1), change.command) {
+            self.mode = ViMode::Insert;
+            for &key in &change.inserted {
+                self.editor.handle(key)?;
+            }
+            self.escape()?;
+            self.last_change = Some(Change { count, ..change });
+        }
+        self.settle()?;
+        Ok(Edit::Continue)
+    }
+
+    /**
+      Carries out a command on the text, returning true if it enters insert mode. Nothing is
+      drawn until [settle](ViEditor::settle) is called.
+      */
+    fn execute(&mut self, count: usize, command: Command) -> bool {
+        let visual = self.mode == ViMode::Visual;
+        let editor = &mut self.editor;
+        let cursor = editor.buffer().cursor();
+        match command {
+            Command::Move(motion) => {
+                if let Some((to, _)) = target(editor.buffer(), motion, count) {
+                    editor.buffer_mut().set_cursor(to);
+                }
+            },
+            Command::Operate(op, motion) => {
+                let (start, end) = match operator_range(editor.buffer(), op, motion, count) {
+                    Some(range) => range,
+                    None => return false,
+                };
+                let buffer = editor.buffer_mut();
+                let text = if op == Operator::Yank {
+                    buffer.set_cursor(start.min(cursor));
+                    buffer.chars()[start..end].iter().collect()
+                }
+                else {
+                    buffer.remove(start, end)
+                };
+                if !text.is_empty() {
+                    editor.kill_ring().push(text);
+                }
+                self.mode = ViMode::Normal;
+                if op == Operator::Change {
+                    self.mode = ViMode::Insert;
+                    return true;
+                }
+            },
+            Command::Insert(motion) => {
+                if let Some((to, _)) = motion.and_then(|motion| target(editor.buffer(), motion, 1)) {
+                    editor.buffer_mut().set_cursor(to);
+                }
+                self.mode = ViMode::Insert;
+                return true;
+            },
+            Command::Put { before } => {
+                let text = match editor.kill_ring().yank() {
+                    Some(text) => text.to_string(),
+                    None => return false,
+                };
+                let buffer = editor.buffer_mut();
+                if !before && cursor < buffer.line_end() {
+                    buffer.move_right();
+                }
+                for _ in 0..count {
+                    buffer.insert_str(&text);
+                }
+                buffer.move_left();
+            },
+            Command::Replace(c) => {
+                let buffer = editor.buffer_mut();
+                if cursor.saturating_add(count) <= buffer.line_end() {
+                    buffer.remove(cursor, cursor + count);
+                    for _ in 0..count {
+                        buffer.insert(c);
+                    }
+                    buffer.move_left();
+                }
+            },
+            Command::Visual => {
+                if visual {
+                    self.mode = ViMode::Normal;
+                }
+                else {
+                    self.mode = ViMode::Visual;
+                    self.anchor = cursor;
+                }
+            },
+            Command::Vertical { .. } | Command::Repeat => {},
+        }
+        false
+    }
+
+    /// Keeps the cursor on a character outside insert mode, and brings the screen up to date.
+    fn settle(&mut self) -> io::Result<()> {
+        if self.mode != ViMode::Insert {
+            let buffer = self.editor.buffer_mut();
+            if buffer.cursor() == buffer.line_end() && buffer.cursor() > buffer.line_start() {
+                buffer.move_left();
+            }
+        }
+        self.editor.refresh()?;
+        let shape = if self.mode == ViMode::Insert { INSERT_CURSOR } else { NORMAL_CURSOR };
+        self.set_cursor_shape(shape)
+    }
+
+    /// Returns the range of the text selected in visual mode, which includes the character under the cursor.
+    fn selection(&self) -> (usize, usize) {
+        let buffer = self.editor.buffer();
+        let cursor = buffer.cursor();
+        let (start, end) = if self.anchor < cursor { (self.anchor, cursor) } else { (cursor, self.anchor) };
+        (start.min(buffer.len()), (end + 1).min(buffer.len()))
+    }
+
+    fn set_cursor_shape(&mut self, shape: &'static str) -> io::Result<()> {
+        if shape == self.shape { return Ok(()); }
+        self.shape = shape;
+        let out = self.editor.output();
+        out.write_all(shape.as_bytes())?;
+        out.flush()
+    }
+}
+
+/// Parses the keys typed so far in normal mode, or in visual mode if `visual` is set.
+fn parse(keys: &[char], visual: bool) -> Parse {
+    let (count, rest) = parse_count(keys);
+    let (&c, rest) = match rest.split_first() {
+        Some(split) => split,
+        None => return Parse::Incomplete,
+    };
+    let operator = match c {
+        'd' => Some(Operator::Delete),
+        'c' => Some(Operator::Change),
+        'y' => Some(Operator::Yank),
+        _ => None,
+    };
+    let command = match (c, operator) {
+        (_, Some(op)) if visual => Command::Operate(op, Motion::Selection),
+        ('x', _) | ('X', _) if visual => Command::Operate(Operator::Delete, Motion::Selection),
+        ('s', _) if visual => Command::Operate(Operator::Change, Motion::Selection),
+        (_, Some(op)) => {
+            let (inner, rest) = parse_count(rest);
+            let motion = match rest.first() {
+                Some(&m) if m == c => Ok(Motion::Line),
+                _ => parse_motion(rest),
+            };
+            return match motion {
+                Ok(motion) => Parse::Done(multiply(count, inner), Command::Operate(op, motion)),
+                Err(parse) => parse,
+            };
+        },
+        ('x', _) => Command::Operate(Operator::Delete, Motion::Right),
+        ('X', _) => Command::Operate(Operator::Delete, Motion::Left),
+        ('D', _) => Command::Operate(Operator::Delete, Motion::LineEnd),
+        ('C', _) => Command::Operate(Operator::Change, Motion::LineEnd),
+        ('s', _) => Command::Operate(Operator::Change, Motion::Right),
+        ('Y', _) => Command::Operate(Operator::Yank, Motion::Line),
+        ('i', _) => Command::Insert(None),
+        ('a', _) => Command::Insert(Some(Motion::Right)),
+        ('I', _) => Command::Insert(Some(Motion::FirstNonBlank)),
+        ('A', _) => Command::Insert(Some(Motion::LineEnd)),
+        ('p', _) => Command::Put { before: false },
+        ('P', _) => Command::Put { before: true },
+        ('r', _) => match rest.first() {
+            Some(&r) => Command::Replace(r),
+            None => return Parse::Incomplete,
+        },
+        ('j', _) => Command::Vertical { down: true },
+        ('k', _) => Command::Vertical { down: false },
+        ('v', _) => Command::Visual,
+        ('.', _) => Command::Repeat,
+        _ => match parse_motion(&keys[keys.len() - rest.len() - 1..]) {
+            Ok(motion) => Command::Move(motion),
+            Err(parse) => return parse,
+        },
+    };
+    Parse::Done(count, command)
+}
+
+/// Splits the count off the start of `keys`. A leading zero is the `0` motion rather than a count.
+fn parse_count(keys: &[char]) -> (Option<usize>, &[char]) {
+    let digits = match keys.first() {
+        Some('0') => 0,
+        _ => keys.iter().take_while(|c| c.is_ascii_digit()).count(),
+    };
+    let count = keys[..digits].iter().fold(None, |count: Option<usize>, c| {
+        let digit = c.to_digit(10).unwrap_or(0) as usize;
+        Some(count.unwrap_or(0).saturating_mul(10).saturating_add(digit).min(MAX_COUNT))
+    });
+    (count, &keys[digits..])
+}
+
+fn parse_motion(keys: &[char]) -> Result<Motion, Parse> {
+    let motion = match keys.first() {
+        None => return Err(Parse::Incomplete),
+        Some('h') => Motion::Left,
+        Some('l') | Some(' ') => Motion::Right,
+        Some('w') => Motion::WordForward,
+        Some('b') => Motion::WordBackward,
+        Some('e') => Motion::WordEnd,
+        Some('0') => Motion::LineStart,
+        Some('^') => Motion::FirstNonBlank,
+        Some('$') => Motion::LineEnd,
+        Some(&f) if "ftFT".contains(f) => match keys.get(1) {
+            Some(&c) => Motion::Find { c, forward: f.is_lowercase(), till: f.eq_ignore_ascii_case(&'t') },
+            None => return Err(Parse::Incomplete),
+        },
+        Some(_) => return Err(Parse::Invalid),
+    };
+    Ok(motion)
+}
+
+fn multiply(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.saturating_mul(b).min(MAX_COUNT)),
+        (a, b) => a.or(b),
+    }
+}
+
+/**
+  Returns the range of text that an operator acts on when given `motion`, or `None` if the
+  motion fails, for instance because the character to find isn't there.
+  */
+fn operator_range(buffer: &LineBuffer, op: Operator, motion: Motion, count: usize) -> Option<(usize, usize)> {
+    let text = buffer.chars();
+    let cursor = buffer.cursor();
+    if motion == Motion::Line {
+        let start = buffer.line_start();
+        let mut end = buffer.line_end();
+        for _ in 1..count {
+            if end == text.len() { break; }
+            end = text[end + 1..].iter().position(|&c| c == '\n').map_or(text.len(), |i| end + 1 + i);
+        }
+        // Deleting whole lines takes a newline with them.
+        if op == Operator::Delete && end < text.len() {
+            return Some((start, end + 1));
+        }
+        if op == Operator::Delete && start > 0 {
+            return Some((start - 1, end));
+        }
+        return Some((start, end));
+    }
+    // As in vi, `cw` on a word changes only up to the end of it.
+    let motion = match motion {
+        Motion::WordForward if op == Operator::Change && text.get(cursor).is_some_and(|&c| class(c) != 0) => Motion::WordEnd,
+        motion => motion,
+    };
+    let (to, inclusive) = target(buffer, motion, count)?;
+    let (start, end) = if to < cursor { (to, cursor) } else { (cursor, to) };
+    let end = if inclusive { end + 1 } else { end };
+    Some((start, end.min(text.len())))
+}
+
+/**
+  Returns where `motion` repeated `count` times takes the cursor, and whether an operator given
+  the motion includes the character there.
+  */
+fn target(buffer: &LineBuffer, motion: Motion, count: usize) -> Option<(usize, bool)> {
+    let text = buffer.chars();
+    let cursor = buffer.cursor();
+    let mut pos = cursor;
+    let target = match motion {
+        Motion::Left => (cursor.saturating_sub(count).max(buffer.line_start()), false),
+        Motion::Right => (cursor.saturating_add(count).min(buffer.line_end()), false),
+        Motion::WordForward => {
+            for _ in 0..count {
+                pos = next_word(text, pos);
+            }
+            (pos, false)
+        },
+        Motion::WordBackward => {
+            for _ in 0..count {
+                pos = previous_word(text, pos);
+            }
+            (pos, false)
+        },
+        Motion::WordEnd => {
+            for _ in 0..count {
+                pos = word_end(text, pos);
+            }
+            (pos, true)
+        },
+        Motion::LineStart => (buffer.line_start(), false),
+        Motion::FirstNonBlank => {
+            let start = buffer.line_start();
+            let blank = text[start..buffer.line_end()].iter().take_while(|c| c.is_whitespace()).count();
+            (start + blank, false)
+        },
+        Motion::LineEnd => (buffer.line_end(), false),
+        Motion::Find { c, forward, till } => {
+            let (start, end) = (buffer.line_start(), buffer.line_end());
+            let found = if forward {
+                let from = (cursor + 1).min(end);
+                text[from..end].iter().enumerate()
+                    .filter(|&(_, &t)| t == c).nth(count - 1)
+                    .map(|(i, _)| from + i)
+            }
+            else {
+                text[start..cursor.max(start)].iter().enumerate().rev()
+                    .filter(|&(_, &t)| t == c).nth(count - 1)
+                    .map(|(i, _)| start + i)
+            }?;
+            // `t` and `T` stop next to the character, which may leave the cursor where it is.
+            let to = match (till, forward) {
+                (false, _) => found,
+                (true, true) => found - 1,
+                (true, false) => found + 1,
+            };
+            (to, forward && to != cursor)
+        },
+        Motion::Span(len) => (cursor.saturating_add(len).min(text.len()), false),
+        Motion::Line | Motion::Selection => (cursor, false),
+    };
+    Some(target)
+}
+
+/// Sorts characters into whitespace, word characters and punctuation, which words are made of runs of.
+fn class(c: char) -> u8 {
+    if c.is_whitespace() { 0 }
+    else if c.is_alphanumeric() || c == '_' { 1 }
+    else { 2 }
+}
+
+/// Returns the start of the next word after `pos`, for `w`.
+fn next_word(text: &[char], mut pos: usize) -> usize {
+    if let Some(&c) = text.get(pos) {
+        let kind = class(c);
+        if kind != 0 {
+            while pos < text.len() && class(text[pos]) == kind {
+                pos += 1;
+            }
+        }
+    }
+    while pos < text.len() && class(text[pos]) == 0 {
+        pos += 1;
+    }
+    pos
+}
+
+/// Returns the start of the word before `pos`, for `b`.
+fn previous_word(text: &[char], mut pos: usize) -> usize {
+    while pos > 0 && class(text[pos - 1]) == 0 {
+        pos -= 1;
+    }
+    if pos > 0 {
+        let kind = class(text[pos - 1]);
+        while pos > 0 && class(text[pos - 1]) == kind {
+            pos -= 1;
+        }
+    }
+    pos
+}
+
+/// Returns the last character of the word after `pos`, for `e`.
+fn word_end(text: &[char], pos: usize) -> usize {
+    let mut pos = pos + 1;
+    while pos < text.len() && class(text[pos]) == 0 {
+        pos += 1;
+    }
+    if pos >= text.len() {
+        return text.len().saturating_sub(1);
+    }
+    let kind = class(text[pos]);
+    while pos + 1 < text.len() && class(text[pos + 1]) == kind {
+        pos += 1;
+    }
+    pos
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vi::*;
+
+    fn type_keys(vi: &mut ViEditor<Vec<u8>>, keys: &str) {
+        for c in keys.chars() {
+            let key = match c {
+                '\x1b' => Byte(0x1b),
+                c if c.is_ascii() => Byte(c as u8),
+                c => Char(c),
+            };
+            assert_eq!(vi.handle(key).unwrap(), Edit::Continue);
+        }
+    }
+
+    fn state(vi: &ViEditor<Vec<u8>>) -> (String, usize) {
+        (vi.editor().buffer().text(), vi.editor().buffer().cursor())
+    }
+
+    #[test]
+    fn test_vi_parse() {
+        let keys = |s: &str| s.chars().collect::<Vec<_>>();
+        assert_eq!(parse(&keys("2d3w"), false), Parse::Done(Some(6), Command::Operate(Operator::Delete, Motion::WordForward)));
+        assert_eq!(parse(&keys("dd"), false), Parse::Done(None, Command::Operate(Operator::Delete, Motion::Line)));
+        assert_eq!(parse(&keys("0"), false), Parse::Done(None, Command::Move(Motion::LineStart)));
+        assert_eq!(parse(&keys("10l"), false), Parse::Done(Some(10), Command::Move(Motion::Right)));
+        assert_eq!(parse(&keys("ct"), false), Parse::Incomplete);
+        assert_eq!(parse(&keys("ct)"), false), Parse::Done(None, Command::Operate(Operator::Change, Motion::Find { c: ')', forward: true, till: true })));
+        assert_eq!(parse(&keys("dq"), false), Parse::Invalid);
+        // Counts are capped rather than overflowing.
+        assert_eq!(parse(&keys("18446744073709551616l"), false), Parse::Done(Some(MAX_COUNT), Command::Move(Motion::Right)));
+        assert_eq!(parse(&keys("500d500w"), false), Parse::Done(Some(MAX_COUNT), Command::Operate(Operator::Delete, Motion::WordForward)));
+        assert_eq!(parse(&keys("y"), true), Parse::Done(None, Command::Operate(Operator::Yank, Motion::Selection)));
+    }
+
+    #[test]
+    fn test_vi_editor() {
+        let mut vi = ViEditor::with_editor(LineEditor::with_output(Vec::new(), "> "));
+        vi.start().unwrap();
+        assert!(String::from_utf8_lossy(vi.editor_mut().output()).ends_with(INSERT_CURSOR));
+        type_keys(&mut vi, "one two.three four\x1b");
+        assert_eq!(vi.mode(), ViMode::Normal);
+        assert_eq!(state(&vi), ("one two.three four".to_string(), 17));
+        assert!(String::from_utf8_lossy(vi.editor_mut().output()).ends_with(NORMAL_CURSOR));
+        // Motions, with counts.
+        type_keys(&mut vi, "0w");
+        assert_eq!(vi.editor().buffer().cursor(), 4);
+        type_keys(&mut vi, "2w");
+        assert_eq!(vi.editor().buffer().cursor(), 8);
+        type_keys(&mut vi, "e");
+        assert_eq!(vi.editor().buffer().cursor(), 12);
+        type_keys(&mut vi, "2b");
+        assert_eq!(vi.editor().buffer().cursor(), 7);
+        type_keys(&mut vi, "$");
+        assert_eq!(vi.editor().buffer().cursor(), 17);
+        type_keys(&mut vi, "0fo");
+        assert_eq!(vi.editor().buffer().cursor(), 6);
+        type_keys(&mut vi, "02fo");
+        assert_eq!(vi.editor().buffer().cursor(), 15);
+        type_keys(&mut vi, "Tt");
+        assert_eq!(vi.editor().buffer().cursor(), 9);
+        // Operators, and putting back what they took.
+        type_keys(&mut vi, "0dw");
+        assert_eq!(state(&vi), ("two.three four".to_string(), 0));
+        type_keys(&mut vi, "2dw");
+        assert_eq!(state(&vi), ("three four".to_string(), 0));
+        type_keys(&mut vi, "$p");
+        assert_eq!(state(&vi), ("three fourtwo.".to_string(), 13));
+        type_keys(&mut vi, "0cwfive\x1b");
+        assert_eq!(state(&vi), ("five fourtwo.".to_string(), 3));
+        type_keys(&mut vi, "wy$P");
+        assert_eq!(state(&vi), ("five fourtwo.fourtwo.".to_string(), 12));
+        type_keys(&mut vi, "0d2e");
+        assert_eq!(state(&vi), (".fourtwo.".to_string(), 0));
+        type_keys(&mut vi, "dtwx");
+        assert_eq!(state(&vi), ("o.".to_string(), 0));
+        // `.` repeats the last change, along with the text it inserted.
+        type_keys(&mut vi, "A!\x1b0.");
+        assert_eq!(state(&vi), ("o.!!".to_string(), 3));
+        type_keys(&mut vi, "0x2.");
+        assert_eq!(state(&vi), ("!".to_string(), 0));
+        type_keys(&mut vi, "ccfourtwo\x1b03rx");
+        assert_eq!(state(&vi), ("xxxrtwo".to_string(), 2));
+        type_keys(&mut vi, "18446744073709551615l18446744073709551615rz");
+        assert_eq!(state(&vi), ("xxxrtwo".to_string(), 6));
+        // Visual mode selects text for an operator.
+        type_keys(&mut vi, "0vlld");
+        assert_eq!(vi.mode(), ViMode::Normal);
+        assert_eq!(state(&vi), ("rtwo".to_string(), 0));
+        type_keys(&mut vi, ".");
+        assert_eq!(state(&vi), ("o".to_string(), 0));
+        type_keys(&mut vi, "vecX\x1b");
+        assert_eq!(state(&vi), ("X".to_string(), 0));
+        type_keys(&mut vi, "D");
+        assert_eq!(state(&vi), ("".to_string(), 0));
+        // A selection can span lines, and `.` acts on as many characters again.
+        vi.editor_mut().buffer_mut().insert_str("ab\ncd\nef\ngh");
+        vi.editor_mut().buffer_mut().set_cursor(0);
+        type_keys(&mut vi, "vjd");
+        assert_eq!(state(&vi), ("d\nef\ngh".to_string(), 0));
+        type_keys(&mut vi, ".");
+        assert_eq!(state(&vi), ("\ngh".to_string(), 0));
+        type_keys(&mut vi, "vjd");
+        assert_eq!(state(&vi), ("h".to_string(), 0));
+        type_keys(&mut vi, "D");
+        // `t` and `T` with their character next to the cursor stay put, and so take nothing.
+        vi.editor_mut().buffer_mut().insert_str("a)b)");
+        vi.editor_mut().buffer_mut().set_cursor(0);
+        type_keys(&mut vi, "t)");
+        assert_eq!(state(&vi), ("a)b)".to_string(), 0));
+        type_keys(&mut vi, "dt)");
+        assert_eq!(state(&vi), ("a)b)".to_string(), 0));
+        type_keys(&mut vi, "2t)");
+        assert_eq!(state(&vi), ("a)b)".to_string(), 2));
+        type_keys(&mut vi, "dT)");
+        assert_eq!(state(&vi), ("a)b)".to_string(), 2));
+        type_keys(&mut vi, "0D");
+        assert_eq!(state(&vi), ("".to_string(), 0));
+        // Alt with a key is Escape followed by the key, and Enter accepts in any mode.
+        type_keys(&mut vi, "iab");
+        assert_eq!(vi.handle(AltByte(b'x')).unwrap(), Edit::Continue);
+        assert_eq!(state(&vi), ("a".to_string(), 0));
+        vi.editor_mut().output().clear();
+        assert_eq!(vi.handle(Byte(b'\r')).unwrap(), Edit::Accept("a".to_string()));
+        assert!(String::from_utf8_lossy(vi.editor_mut().output()).ends_with(DEFAULT_CURSOR));
+    }
+}